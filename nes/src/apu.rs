@@ -1,5 +1,5 @@
 mod timer {
-    #[derive(serde::Serialize, serde::Deserialize)]
+    #[derive(Clone, serde::Serialize, serde::Deserialize)]
     pub struct State {
         divider: u16
     }
@@ -31,10 +31,41 @@ mod timer {
     impl<T: Context> Interface for T {}
 }
 
+use crate::region::Region;
 use serde::{Deserialize, Serialize};
 
 type ChannelRegister = [u8; 4];
 
+// channels that can be panned in stereo mode, dmc always stays centered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioChannel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+}
+
+// live channel state for debuggers and music rippers. volume is the envelope (or constant)
+// volume, the triangle has none and reports 0 and the dmc reports its 7 bit output level.
+// The dmc's length_counter is the sample bytes it has left to read
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ApuChannelDebug {
+    pub enabled: bool,
+    pub length_counter: u8,
+    pub volume: u8,
+    // timer period in apu cycles, cpu cycles for the triangle
+    pub period: u16,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ApuDebug {
+    pub pulse1: ApuChannelDebug,
+    pub pulse2: ApuChannelDebug,
+    pub triangle: ApuChannelDebug,
+    pub noise: ApuChannelDebug,
+    pub dmc: ApuChannelDebug,
+}
+
 const LENGTH_TABLE: [u8; 32] = [
     0x0A, 0xFE, 0x14, 0x02, 0x28, 0x04, 0x50, 0x06, 
     0xA0, 0x08, 0x3C, 0x0A, 0x0E, 0x0C, 0x1A, 0x0E,
@@ -62,7 +93,20 @@ const NOISE_CHANNEL_NTSC_PERIOD_TABLE: [u16; 16] = [
     4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
 ];
 
-#[derive(Serialize, Deserialize)]
+const RATE_PAL: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+const NOISE_CHANNEL_PAL_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+// cpu cycles of the frame counter's quarter frame steps; the 4th step ends the 4-step
+// sequence and the 5th ends the 5-step one
+const FRAME_COUNTER_STEPS_NTSC: [usize; 5] = [7457, 14913, 22371, 29829, 37281];
+const FRAME_COUNTER_STEPS_PAL: [usize; 5] = [8313, 16627, 24939, 33253, 41565];
+
+#[derive(Clone, Serialize, Deserialize)]
 struct Envelope {
     decay: u8,
     divider: u8,
@@ -110,7 +154,7 @@ impl Envelope {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct LengthCounter {
     divider: u8,
     enable: bool,
@@ -152,7 +196,7 @@ impl LengthCounter {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PulseChannel {
     register: ChannelRegister,
     envelope: Envelope,
@@ -271,6 +315,15 @@ impl PulseChannel {
         self.length_counter.output() != 0
     }
 
+    pub fn debug_state(&self) -> ApuChannelDebug {
+        ApuChannelDebug {
+            enabled: self.is_enabled(),
+            length_counter: self.length_counter.output(),
+            volume: self.envelope.output(),
+            period: timer::Context::period(self),
+        }
+    }
+
     pub fn on_quarter_frame_clock(&mut self) {
         self.envelope.tick();
     }
@@ -338,7 +391,7 @@ impl PulseChannel {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TriangleChannel {
     register: ChannelRegister,
     timer: timer::State,
@@ -358,7 +411,10 @@ impl timer::Context for TriangleChannel {
     }
 
     fn on_timer_clock(&mut self) {
-        if self.length_counter.output() > 0 && self.linear_counter_divider > 0 {
+        // periods below 2 step the sequencer at ultrasonic rates. The hardware keeps going, but
+        // the result is only heard as a pop, so the step is held where it was instead
+        let ultrasonic = self.reg_timer() < 2;
+        if self.length_counter.output() > 0 && self.linear_counter_divider > 0 && !ultrasonic {
             self.sequence_index += 1;
             if self.sequence_index >= 32 {
                 self.sequence_index = 0;
@@ -430,18 +486,25 @@ impl TriangleChannel {
         self.length_counter.tick();
     }
 
+    // a stopped sequencer (length or linear counter at 0, or an ultrasonic period)
+    // keeps outputting its current step
     pub fn output(&self) -> u8 {
-        if self.reg_timer() < 2 {
-            7
-        } else {
-            self.sequence_output()
-        }
+        self.sequence_output()
     }
 
     pub fn is_enabled(&self) -> bool {
         self.length_counter.output() > 0
     }
 
+    pub fn debug_state(&self) -> ApuChannelDebug {
+        ApuChannelDebug {
+            enabled: self.is_enabled(),
+            length_counter: self.length_counter.output(),
+            volume: 0,
+            period: timer::Context::period(self),
+        }
+    }
+
     pub fn tick(&mut self) {
         timer::Interface::tick(self);
     }
@@ -462,13 +525,14 @@ impl TriangleChannel {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct NoiseChannel {
     register: ChannelRegister,
     timer: timer::State,
     envelope: Envelope,
     length_counter: LengthCounter,
     feedback_register: u16,
+    region: Region,
 }
 
 impl timer::Context for NoiseChannel {
@@ -492,7 +556,11 @@ impl timer::Context for NoiseChannel {
     }
 
     fn period(&self) -> u16 {
-        NOISE_CHANNEL_NTSC_PERIOD_TABLE[self.reg_noise_period_index() as usize]
+        let table = match self.region {
+            Region::Pal => &NOISE_CHANNEL_PAL_PERIOD_TABLE,
+            Region::Ntsc | Region::Dendy => &NOISE_CHANNEL_NTSC_PERIOD_TABLE,
+        };
+        table[self.reg_noise_period_index() as usize]
     }
 }
 
@@ -504,6 +572,7 @@ impl NoiseChannel {
             envelope: Envelope::new(),
             length_counter: LengthCounter::new(),
             feedback_register: 0b0000_0001,
+            region: Region::default(),
         }
     }
 
@@ -562,6 +631,15 @@ impl NoiseChannel {
         self.length_counter.output() > 0
     }
 
+    pub fn debug_state(&self) -> ApuChannelDebug {
+        ApuChannelDebug {
+            enabled: self.is_enabled(),
+            length_counter: self.length_counter.output(),
+            volume: self.envelope.output(),
+            period: timer::Context::period(self),
+        }
+    }
+
     pub fn on_quarter_frame_clock(&mut self) {
         self.envelope.tick();
     }
@@ -583,7 +661,7 @@ impl NoiseChannel {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct DeltaModulationChannel {
     register: ChannelRegister,
     enable: bool,
@@ -596,6 +674,7 @@ pub struct DeltaModulationChannel {
     output: u8,
     silence_flag: bool,
     interrupt_flag: bool,
+    region: Region,
 }
 
 impl timer::Context for DeltaModulationChannel {
@@ -629,7 +708,11 @@ impl timer::Context for DeltaModulationChannel {
     }
 
     fn period(&self) -> u16 {
-        RATE_NTSC[self.reg_rate_index()] >> 1 - 1
+        let rate = match self.region {
+            Region::Pal => &RATE_PAL,
+            Region::Ntsc | Region::Dendy => &RATE_NTSC,
+        };
+        rate[self.reg_rate_index()] >> 1 - 1
     }
 }
 
@@ -647,6 +730,7 @@ impl DeltaModulationChannel {
             output: 0,
             silence_flag: false,
             interrupt_flag: false,
+            region: Region::default(),
         }
     }
 
@@ -704,6 +788,15 @@ impl DeltaModulationChannel {
         self.sample_remaining_bytes != 0
     }
 
+    pub fn debug_state(&self) -> ApuChannelDebug {
+        ApuChannelDebug {
+            enabled: self.is_enabled(),
+            length_counter: self.sample_remaining_bytes,
+            volume: self.output,
+            period: timer::Context::period(self),
+        }
+    }
+
     pub fn output(&self) -> u8 {
         if self.enable {
             self.output
@@ -748,7 +841,7 @@ impl DeltaModulationChannel {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct FrameRegister(u8);
 impl FrameRegister {
     pub fn new() -> Self {
@@ -768,7 +861,7 @@ impl FrameRegister {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct State {
     pub pulse1: PulseChannel,
     pub pulse2: PulseChannel,
@@ -808,6 +901,11 @@ pub trait Context: Sized {
     fn activate_dma(&mut self, addr: u16);
     fn on_sample(&mut self, sample: f32);
     fn is_on_odd_cpu_cycle(&mut self) -> bool;
+    fn region(&self) -> Region;
+    // pan per AudioChannel from -1.0 (left) to 1.0 (right), None for mono output
+    fn stereo_pan(&self) -> Option<[f32; 4]>;
+    // cartridge audio (FDS etc.), already scaled to the mixer output
+    fn expansion_audio(&self) -> f32;
 }
 
 pub trait Interface: Sized + Context {
@@ -815,6 +913,25 @@ pub trait Interface: Sized + Context {
         Private::on_cpu_tick(self);
     }
 
+    // the reset button: the channels are silenced as if $4015 = 0 was written, $4017 is written
+    // again with its last value, which restarts the frame counter, the triangle goes back to step 0
+    // and the dmc output level keeps only its low bit. Power on is State::new
+    fn reset(&mut self) {
+        Interface::write_state_register(self, 0);
+        let frame = self.state().frame.0;
+        Interface::set_frame(self, frame);
+        self.state_mut().triangle.sequence_index = 0;
+        self.state_mut().dmc.output &= 1;
+    }
+
+    fn get_frame_interrupt_flag(&self) -> bool {
+        self.state().frame_interrupt_flag
+    }
+
+    fn get_dmc_interrupt_flag(&self) -> bool {
+        self.state().dmc.interrupt_flag
+    }
+
     fn set_pulse1(&mut self, addr: u16, value: u8) {
         self.state_mut().pulse1.set_register(addr, value);
     }
@@ -903,6 +1020,16 @@ pub trait Interface: Sized + Context {
     fn mixer_output(&self) -> f32 {
         Private::mixer_output(self)
     }
+
+    fn debug_state(&self) -> ApuDebug {
+        ApuDebug {
+            pulse1: self.state().pulse1.debug_state(),
+            pulse2: self.state().pulse2.debug_state(),
+            triangle: self.state().triangle.debug_state(),
+            noise: self.state().noise.debug_state(),
+            dmc: self.state().dmc.debug_state(),
+        }
+    }
 }
 
 impl<T: Context> Interface for T {}
@@ -910,6 +1037,11 @@ impl<T: Context> Private for T {}
 
 trait Private: Sized + Context {
     fn on_cpu_tick(&mut self) {
+        // Dendy keeps the NTSC tables, only PAL consoles have their own
+        let region = self.region();
+        self.state_mut().noise.region = region;
+        self.state_mut().dmc.region = region;
+
         self.state_mut().triangle.tick();
         if !Context::is_on_odd_cpu_cycle(self) {
             self.state_mut().pulse1.tick();
@@ -932,43 +1064,47 @@ trait Private: Sized + Context {
             }
         }
 
-        // TODO: add PAL support
+        // Dendy uses the same steps as NTSC at its own cpu clock
+        let steps = match region {
+            Region::Pal => FRAME_COUNTER_STEPS_PAL,
+            Region::Ntsc | Region::Dendy => FRAME_COUNTER_STEPS_NTSC,
+        };
         match self.state().frame_counter_timer {
-            7457 => {
+            t if t == steps[0] => {
                 Private::quarter_frame_clock(self);
             }
-            14913 => {
+            t if t == steps[1] => {
                 Private::quarter_frame_clock(self);
                 Private::half_frame_clock(self);
             }
-            22371 => {
+            t if t == steps[2] => {
                 Private::quarter_frame_clock(self);
             }
-            29828 => {
+            t if t == steps[3] - 1 => {
                 if !self.state().frame.is_5_step() {
                     Private::set_frame_interrupt(self, true);
                 }
             }
-            29829 => {
+            t if t == steps[3] => {
                 if !self.state().frame.is_5_step() {
                     Private::quarter_frame_clock(self);
                     Private::half_frame_clock(self);
                     Private::set_frame_interrupt(self, true);
                 }
             }
-            29830 => {
+            t if t == steps[3] + 1 => {
                 if !self.state().frame.is_5_step() {
                     self.state_mut().frame_counter_timer = 0;
                     Private::set_frame_interrupt(self, true);
                 }
             }
-            37281 => {
+            t if t == steps[4] => {
                 if self.state().frame.is_5_step() {
                     Private::quarter_frame_clock(self);
                     Private::half_frame_clock(self);
                 }
             }
-            37282 => {
+            t if t == steps[4] + 1 => {
                 if self.state().frame.is_5_step() {
                     self.state_mut().frame_counter_timer = 0;
                 }
@@ -1015,35 +1151,152 @@ trait Private: Sized + Context {
         let noise_sample = self.state().noise.output() as f32;
         let dmc_sample = self.state().dmc.output() as f32;
 
-        let pulse_out = if pulse1_sample > 0.0 || pulse2_sample > 0.0 {
-            95.88 / (8128.0 / (pulse1_sample + pulse2_sample) + 100.0)
-        } else {
-            0.0
-        };
+        mix(pulse1_sample, pulse2_sample, triangle_sample, noise_sample, dmc_sample)
+    }
 
-        let tnd_out = if triangle_sample > 0.0 || noise_sample > 0.0 || dmc_sample > 0.0 {
-            159.79
-                / ((1.0
-                    / (triangle_sample / 8227.0 + noise_sample / 12241.0 + dmc_sample / 22638.0))
-                    + 100.0)
-        } else {
-            0.0
-        };
+    fn stereo_mixer_output(&self, pan: [f32; 4]) -> (f32, f32) {
+        let samples = [
+            self.state().pulse1.output() as f32,
+            self.state().pulse2.output() as f32,
+            self.state().triangle.output() as f32,
+            self.state().noise.output() as f32,
+        ];
+        let dmc_sample = self.state().dmc.output() as f32;
+
+        // a centered channel plays at full volume on both sides, so a fully
+        // centered stereo mix matches the mono one
+        let mut left = [0.0f32; 4];
+        let mut right = [0.0f32; 4];
+        for (i, (sample, pan)) in samples.iter().zip(pan.iter()).enumerate() {
+            let pan = pan.clamp(-1.0, 1.0);
+            left[i] = sample * (1.0 - pan).min(1.0);
+            right[i] = sample * (1.0 + pan).min(1.0);
+        }
 
-        pulse_out + tnd_out
+        (
+            mix(left[0], left[1], left[2], left[3], dmc_sample),
+            mix(right[0], right[1], right[2], right[3], dmc_sample),
+        )
     }
 
     fn output_clock(&mut self) {
         let sample_rate = 44.1;
-        let cpu_frequence = 21477.272 / 12.0;
+        let cpu_frequence = match self.region() {
+            Region::Ntsc => 21477.272 / 12.0,
+            Region::Pal => 26601.712 / 16.0,
+            Region::Dendy => 26601.712 / 15.0,
+        };
         let adjust = 1.9;  // experienced parameter
         let sample_every = cpu_frequence / sample_rate - adjust;
         if self.state().sample_counter > sample_every {
             self.state_mut().sample_counter -= sample_every;
-            let sample = self.mixer_output();
-            self.on_sample(sample);
+            match self.stereo_pan() {
+                Some(pan) => {
+                    let (left, right) = self.stereo_mixer_output(pan);
+                    let expansion = self.expansion_audio();
+                    self.on_sample(left + expansion);
+                    self.on_sample(right + expansion);
+                }
+                None => {
+                    let sample = self.mixer_output() + self.expansion_audio();
+                    self.on_sample(sample);
+                }
+            }
         } else {
             self.state_mut().sample_counter += 1.0;
         }
     }
 }
+
+fn mix(pulse1_sample: f32, pulse2_sample: f32, triangle_sample: f32, noise_sample: f32, dmc_sample: f32) -> f32 {
+    let pulse_out = if pulse1_sample > 0.0 || pulse2_sample > 0.0 {
+        95.88 / (8128.0 / (pulse1_sample + pulse2_sample) + 100.0)
+    } else {
+        0.0
+    };
+
+    let tnd_out = if triangle_sample > 0.0 || noise_sample > 0.0 || dmc_sample > 0.0 {
+        159.79
+            / ((1.0
+                / (triangle_sample / 8227.0 + noise_sample / 12241.0 + dmc_sample / 22638.0))
+                + 100.0)
+    } else {
+        0.0
+    };
+
+    pulse_out + tnd_out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // clocks until the shift register is back where it started
+    fn noise_period(mode_flag: u8) -> usize {
+        let mut noise = NoiseChannel::new();
+        noise.set_register(0x400E, mode_flag);
+        let start = noise.feedback_register;
+        let mut clocks = 0;
+        loop {
+            timer::Context::on_timer_clock(&mut noise);
+            clocks += 1;
+            if noise.feedback_register == start {
+                return clocks;
+            }
+            assert!(clocks <= 0x8000);
+        }
+    }
+
+    #[test]
+    fn noise_shift_register_periods() {
+        assert_eq!(noise_period(0x00), 32767);
+        // the short mode taps bit 6, starting from the power on value of 1
+        assert_eq!(noise_period(0x80), 93);
+    }
+
+    // $4008 takes the control flag and reload value, a $400B write with length index 1
+    fn triangle(linear_control: u8) -> TriangleChannel {
+        let mut triangle = TriangleChannel::new();
+        triangle.set_enabled(true);
+        triangle.set_register(0x4008, linear_control);
+        triangle.set_register(0x400A, 0x40);
+        triangle.set_register(0x400B, 0x08);
+        triangle
+    }
+
+    #[test]
+    fn triangle_linear_counter_counts_quarter_frames() {
+        let mut triangle = triangle(0x03);
+        let counts: Vec<u8> = (0..5).map(|_| {
+            triangle.on_quarter_frame_clock();
+            triangle.linear_counter_divider
+        }).collect();
+        assert_eq!(counts, [3, 2, 1, 0, 0]);
+
+        // the sequencer holds its step once the counter is out
+        let step = triangle.sequence_index;
+        timer::Context::on_timer_clock(&mut triangle);
+        assert_eq!(triangle.sequence_index, step);
+
+        // a $400B write reloads it on the next quarter frame
+        triangle.set_register(0x400B, 0x08);
+        triangle.on_quarter_frame_clock();
+        assert_eq!(triangle.linear_counter_divider, 3);
+        timer::Context::on_timer_clock(&mut triangle);
+        assert_eq!(triangle.sequence_index, step + 1);
+    }
+
+    #[test]
+    fn triangle_control_flag_keeps_reloading_the_linear_counter() {
+        let mut triangle = triangle(0x83);
+        for _ in 0..5 {
+            triangle.on_quarter_frame_clock();
+            assert_eq!(triangle.linear_counter_divider, 3);
+        }
+        // clearing control lets the next quarter frame drop the reload flag
+        triangle.set_register(0x4008, 0x03);
+        triangle.on_quarter_frame_clock();
+        triangle.on_quarter_frame_clock();
+        assert_eq!(triangle.linear_counter_divider, 2);
+    }
+}
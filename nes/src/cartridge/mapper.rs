@@ -1,3 +1,4 @@
+use crate::cartridge::MirrorMode;
 use serde::{Deserialize, Serialize};
 
 const CPU_ADDRESS_SPACE_MAPPED_BEGIN: u16 = 0x6000;
@@ -57,7 +58,7 @@ pub enum BankWindow {
     Size1k = 0x0400,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct BaseMapper {
     cpu_map_table: [MapTableItem; CPU_MAP_TABLE_SIZE],
     ppu_map_table: [MapTableItem; PPU_MAP_TABLE_SIZE],
@@ -121,6 +122,14 @@ impl BaseMapper {
         }
     }
 
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    pub fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
     // pub fn initialize_prg_rom(&mut self, prg_rom: &Vec<u8>) {
     //     self.prg_rom = prg_rom.clone()
     // }
@@ -182,7 +191,8 @@ impl BaseMapper {
         bank_window: BankWindow,
         mem_attr: MemAttr,
     ) {
-        let bank_selector = bank_selector % ((self.prg_rom.len() / (bank_window as usize)) as u8);
+        let bank_num = self.bank_num(bank_type, bank_window).max(1);
+        let bank_selector = (bank_selector as usize % bank_num) as u8;
         let addr = addr & (bank_window as u16 - 1).reverse_bits();
         let bank_window = bank_window as usize;
         let offset = bank_window * bank_selector as usize;
@@ -260,24 +270,29 @@ impl BaseMapper {
         }
     }
 
-    pub fn peek_cpu_memory(&self, addr: u16) -> u8 {
+    pub fn is_mapped(&self, addr: u16) -> bool {
+        self.cpu_map_table[Self::cpu_map_table_idx(addr)].bank_type.is_some()
+    }
+
+    pub fn try_peek_cpu_memory(&self, addr: u16) -> Option<u8> {
         let item = self.cpu_map_table[Self::cpu_map_table_idx(addr)];
         let offset = (addr as usize & (CPU_MINIMUM_MAP_SIZE - 1)) + item.offset;
-        if let Some(bank_type) = item.bank_type {
-            self.internal_peek(bank_type, item.attribute, offset)
-        } else {
-            panic!("Peek unmapped cpu memory: 0x{:x}", addr)
-        }
+        item.bank_type.map(|bank_type| self.internal_peek(bank_type, item.attribute, offset))
     }
 
+    // boards without prg ram leave $6000-$7FFF unmapped, reads there give 0 as open bus
+    pub fn peek_cpu_memory(&self, addr: u16) -> u8 {
+        self.try_peek_cpu_memory(addr).unwrap_or(0)
+    }
+
+    // and writes there go nowhere
     pub fn poke_cpu_memory(&mut self, addr: u16, value: u8) {
+        if !self.is_mapped(addr) {
+            return;
+        }
         let item = self.cpu_map_table[Self::cpu_map_table_idx(addr)];
         let offset = (addr as usize & (CPU_MINIMUM_MAP_SIZE - 1)) + item.offset;
-        if let Some(bank_type) = item.bank_type {
-            self.internal_poke(bank_type, item.attribute, offset, value);
-        } else {
-            panic!("Poke unmapped cpu memory: 0x{:x}", addr)
-        }
+        self.internal_poke(item.bank_type.unwrap(), item.attribute, offset, value);
     }
 
     pub fn peek_ppu_memory(&self, addr: u16) -> u8 {
@@ -340,6 +355,30 @@ impl BaseMapper {
         self.map_ppu_address(0x2C00, BankType::NAMETABLE, 1, BankWindow::Size1k);
     }
 
+    pub fn initialize_and_map_nametable(&mut self, mirroring: MirrorMode) {
+        match mirroring {
+            MirrorMode::Horizontal => self.initialize_and_map_nametable_horizontal(),
+            MirrorMode::Vertical => self.initialize_and_map_nametable_vertical(),
+            MirrorMode::OneScreenLow => self.initialize_and_map_nametable_onescreen_lower_bank(),
+            MirrorMode::OneScreenHigh => self.initialize_and_map_nametable_onescreen_upper_bank(),
+            MirrorMode::FourScreen => self.initialize_and_map_nametable_fourscreen(),
+        }
+    }
+
+    pub fn current_mirroring(&self) -> MirrorMode {
+        let bank = |addr: u16| self.ppu_map_table[Self::ppu_map_table_idx(addr)].offset / PPU_MINIMUM_MAP_SIZE;
+        let (a, b, c, d) = (bank(0x2000), bank(0x2400), bank(0x2800), bank(0x2C00));
+        if a == b && b == c && c == d {
+            if a == 0 { MirrorMode::OneScreenLow } else { MirrorMode::OneScreenHigh }
+        } else if a == b && c == d {
+            MirrorMode::Horizontal
+        } else if a == c && b == d {
+            MirrorMode::Vertical
+        } else {
+            MirrorMode::FourScreen
+        }
+    }
+
     pub fn bank_num(&self, bank_type: BankType, bank_window: BankWindow) -> usize {
         match bank_type {
             BankType::PRG_ROM => self.prg_rom.len() / bank_window as usize,
@@ -373,9 +412,9 @@ impl BaseMapper {
 }
 
 pub trait Mapper {
-    fn peek_expansion_rom(&mut self, addr: u16) -> u8 {
-        println!("PEEK EXPANSION ROM: 0x{:x}", addr);
-        0
+    // None when nothing on the cartridge answers, the cpu then reads open bus
+    fn peek_expansion_rom(&mut self, _addr: u16) -> Option<u8> {
+        None
     }
     fn poke_expansion_rom(&mut self, addr: u16, val: u8) {
         println!("POKE EXPANSION ROM: 0x{:x}, VALUE: 0x{:x}", addr, val);
@@ -387,9 +426,26 @@ pub trait Mapper {
     fn vpeek(&mut self, addr: u16) -> u8;
     fn vpoke(&mut self, addr: u16, val: u8);
 
-    fn irq(&mut self) -> bool { false }
+    fn reset_mapper_to_defaults(&mut self) {}
+
+    // clocked once per cpu cycle, returns true to raise an irq
+    fn on_cpu_cycle(&mut self) -> bool { false }
+    // every address the ppu puts on its bus, used to watch A12
+    fn on_ppu_address(&mut self, _addr: u16) {}
     fn irq_acknowledge(&mut self) -> bool { false }
+    // expansion audio mixed in by the apu
+    fn audio_output(&self) -> f32 { 0.0 }
+
+    fn current_mirroring(&self) -> MirrorMode;
+    // remaps the nametables, the mapper may switch it again on its next register write
+    fn set_mirroring(&mut self, mirroring: MirrorMode);
+
+    // the battery backed prg ram, for .sav files. None when the board has none
+    fn sram(&self) -> Option<&[u8]> { None }
+    fn sram_mut(&mut self) -> Option<&mut [u8]> { None }
 
     fn load_state(&mut self, state: Vec<u8>);
     fn save_state(&self) -> Vec<u8>;
+
+    fn clone_box(&self) -> Box<dyn Mapper>;
 }
@@ -1,8 +1,8 @@
 use crate::cartridge::{BankType, BankWindow, BaseMapper, Mapper, MemAttr};
-use crate::cartridge::{ChrRom, NesHeader, PrgRom};
+use crate::cartridge::{ChrRom, MirrorMode, NesHeader, PrgRom};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct State {
     inner: BaseMapper,
 }
@@ -10,7 +10,7 @@ pub struct State {
 impl State {
     pub fn new(header: &NesHeader, prg_rom: &PrgRom, chr_rom: &ChrRom) -> Self {
         let mut inner = BaseMapper::new();
-        inner.initialize(prg_rom, chr_rom, 0x2000, 0x2000);
+        inner.initialize(prg_rom, chr_rom, 0x2000, header.chr_ram_size);
 
         inner.map_cpu_address(0x6000, BankType::PRG_RAM, 0, BankWindow::Size8k);
         inner.map_ppu_address(0x0000, BankType::CHR_MEM, 0, BankWindow::Size8k);
@@ -19,14 +19,7 @@ impl State {
         inner.map_cpu_address(0x8000, BankType::PRG_ROM, 0, BankWindow::Size16k);
         inner.map_cpu_address(0xC000, BankType::PRG_ROM, last_bank as u8, BankWindow::Size16k);
 
-        match header.mirroring {
-            super::MirrorMode::Vertical => {
-                inner.initialize_and_map_nametable_vertical()
-            }
-            super::MirrorMode::Horizontal => {
-                inner.initialize_and_map_nametable_horizontal()
-            }
-        };
+        inner.initialize_and_map_nametable(header.mirroring);
         State { inner }
     }
 }
@@ -48,6 +41,14 @@ impl Mapper for State {
         self.inner.poke_ppu_memory(addr, value)
     }
 
+    fn current_mirroring(&self) -> MirrorMode {
+        self.inner.current_mirroring()
+    }
+
+    fn set_mirroring(&mut self, mirroring: MirrorMode) {
+        self.inner.initialize_and_map_nametable(mirroring)
+    }
+
     fn load_state(&mut self, state: Vec<u8>) {
         let state: Self = bincode::deserialize(&state[..]).unwrap();
         *self = state;
@@ -56,4 +57,8 @@ impl Mapper for State {
     fn save_state(&self) -> Vec<u8> {
         bincode::serialize(&self).unwrap()
     }
+
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
 }
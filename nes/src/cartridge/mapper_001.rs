@@ -1,21 +1,21 @@
 use crate::cartridge::{BankType, BankWindow, BaseMapper, Mapper, MemAttr};
-use crate::cartridge::{ChrRom, NesHeader, PrgRom};
+use crate::cartridge::{ChrRom, MirrorMode, NesHeader, PrgRom};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 enum PrgRomBankSwitchMode {
     Switch32k,
     FixFirstBank,
     FixLastBank,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 enum ChrRomBankSwitchMode {
     Switch8k,
     Switch4k,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct State {
     inner: BaseMapper,
     shifter: u8,
@@ -24,12 +24,16 @@ pub struct State {
     prg_rom_16k_selector: u8,
     chr_4k_lower_selector: u8,
     chr_4k_upper_selector: u8,
+    cpu_cycle: usize,
+    last_write_cycle: Option<usize>,
+    // bit 4 of $E000 on MMC1B and later
+    prg_ram_disable: bool,
 }
 
 impl State {
     pub fn new(header: &NesHeader, prg_rom: &PrgRom, chr_rom: &ChrRom) -> Self {
         let mut inner = BaseMapper::new();
-        inner.initialize(prg_rom, chr_rom, 0x8000, 0x20000);
+        inner.initialize(prg_rom, chr_rom, 0x8000, header.chr_ram_size);
 
         inner.map_cpu_address(0x6000, BankType::PRG_RAM, 0, BankWindow::Size8k);
         inner.map_ppu_address(0x0000, BankType::CHR_MEM, 0, BankWindow::Size8k);
@@ -38,14 +42,7 @@ impl State {
         inner.map_cpu_address(0x8000, BankType::PRG_ROM, 0, BankWindow::Size16k);
         inner.map_cpu_address(0xC000, BankType::PRG_ROM, last_bank as u8, BankWindow::Size16k);
 
-        match header.mirroring {
-            super::MirrorMode::Vertical => {
-                inner.initialize_and_map_nametable_vertical();
-            }
-            super::MirrorMode::Horizontal => {
-                inner.initialize_and_map_nametable_horizontal();
-            }
-        };
+        inner.initialize_and_map_nametable(header.mirroring);
 
         let shifter =  0b0001_0000u8;
         let prg_rom_bank_mode = PrgRomBankSwitchMode::FixLastBank;
@@ -59,6 +56,18 @@ impl State {
             prg_rom_16k_selector: 0, 
             chr_4k_lower_selector: 0, 
             chr_4k_upper_selector: 1, 
+            cpu_cycle: 0,
+            last_write_cycle: None,
+            prg_ram_disable: false,
+        }
+    }
+
+    fn update_prg_ram(&mut self) {
+        if self.prg_ram_disable {
+            self.inner.unmap_cpu_address(0x6000, BankWindow::Size8k);
+        }
+        else {
+            self.inner.map_cpu_address(0x6000, BankType::PRG_RAM, 0, BankWindow::Size8k);
         }
     }
 
@@ -101,6 +110,14 @@ impl Mapper for State {
         match addr {
             0x6000..=0x7FFF => self.inner.poke_cpu_memory(addr, value),
             0x8000..=0xFFFF => {
+                // the serial port ignores a write on the cycle right after another one,
+                // which is what the dummy write of an RMW instruction does
+                let consecutive = self.last_write_cycle == Some(self.cpu_cycle.wrapping_sub(1));
+                self.last_write_cycle = Some(self.cpu_cycle);
+                if consecutive {
+                    return;
+                }
+
                 let shifter_full = self.shifter & 1 != 0;
                 let reset = value & 0b1000_0000 != 0;
 
@@ -154,6 +171,8 @@ impl Mapper for State {
                                     self.prg_rom_16k_selector = value & 0b1111;
                                 },
                             }
+                            self.prg_ram_disable = value & 0b1_0000 != 0;
+                            self.update_prg_ram();
                             
                         }
                         _ => unreachable!("CPU ADDRESS: 0x{:X}", addr)
@@ -169,6 +188,23 @@ impl Mapper for State {
         }
     }
 
+    fn reset_mapper_to_defaults(&mut self) {
+        self.shifter = 0b0001_0000;
+        self.prg_rom_bank_mode = PrgRomBankSwitchMode::FixLastBank;
+        self.chr_rom_bank_mode = ChrRomBankSwitchMode::Switch4k;
+        self.prg_rom_16k_selector = 0;
+        self.chr_4k_lower_selector = 0;
+        self.chr_4k_upper_selector = 1;
+        self.prg_ram_disable = false;
+        self.update_map_state();
+        self.update_prg_ram();
+    }
+
+    fn on_cpu_cycle(&mut self) -> bool {
+        self.cpu_cycle = self.cpu_cycle.wrapping_add(1);
+        false
+    }
+
     fn vpeek(&mut self, addr: u16) -> u8 {
         self.inner.peek_ppu_memory(addr)
     }
@@ -177,6 +213,22 @@ impl Mapper for State {
         self.inner.poke_ppu_memory(addr, value)
     }
 
+    fn current_mirroring(&self) -> MirrorMode {
+        self.inner.current_mirroring()
+    }
+
+    fn set_mirroring(&mut self, mirroring: MirrorMode) {
+        self.inner.initialize_and_map_nametable(mirroring)
+    }
+
+    fn sram(&self) -> Option<&[u8]> {
+        Some(self.inner.prg_ram())
+    }
+
+    fn sram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(self.inner.prg_ram_mut())
+    }
+
     fn load_state(&mut self, state: Vec<u8>) {
         let state: Self = bincode::deserialize(&state[..]).unwrap();
         *self = state;
@@ -185,4 +237,64 @@ impl Mapper for State {
     fn save_state(&self) -> Vec<u8> {
         bincode::serialize(&self).unwrap()
     }
+
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::NesVersion;
+
+    // 8 PRG banks, each filled with its own number
+    fn mmc1() -> State {
+        let header = NesHeader {
+            prg_banks: 8,
+            chr_banks: 0,
+            mirroring: MirrorMode::Horizontal,
+            four_screen_mode: false,
+            has_battery: false,
+            nes_version: NesVersion::V1,
+            mapper_id: 1,
+            submapper: 0,
+            region: None,
+            chr_ram_size: 0x2000,
+        };
+        let prg_rom: Vec<u8> = (0..8u8).flat_map(|bank| vec![bank; 0x4000]).collect();
+        State::new(&header, &prg_rom, &Vec::new())
+    }
+
+    // a write with a few idle cycles in front, like a plain STA
+    fn spaced_write(mapper: &mut State, addr: u16, value: u8) {
+        for _ in 0..3 {
+            mapper.on_cpu_cycle();
+        }
+        mapper.poke(addr, value);
+    }
+
+    #[test]
+    fn second_of_two_back_to_back_writes_is_ignored() {
+        let mut mapper = mmc1();
+        // an RMW instruction writes the old value, then the new one on the next cycle
+        spaced_write(&mut mapper, 0xE000, 1);
+        mapper.on_cpu_cycle();
+        mapper.poke(0xE000, 0);
+        for bit in [1, 0, 0, 0] {
+            spaced_write(&mut mapper, 0xE000, bit);
+        }
+        // 0b00011, where taking the second write would have given 0b00101
+        assert_eq!(mapper.peek(0x8000), 3);
+        assert_eq!(mapper.peek(0xC000), 7);
+    }
+
+    #[test]
+    fn five_spaced_writes_load_a_register() {
+        let mut mapper = mmc1();
+        for bit in [1, 0, 1, 0, 0] {
+            spaced_write(&mut mapper, 0xE000, bit);
+        }
+        assert_eq!(mapper.peek(0x8000), 5);
+    }
 }
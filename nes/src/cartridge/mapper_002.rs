@@ -1,17 +1,22 @@
 use crate::cartridge::{BankType, BankWindow, BaseMapper, Mapper, MemAttr};
-use crate::cartridge::{ChrRom, NesHeader, PrgRom};
+use crate::cartridge::{ChrRom, MirrorMode, NesHeader, PrgRom};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+// NES 2.0 submapper 2 marks boards where the rom drives the bus during bank writes
+const SUBMAPPER_BUS_CONFLICTS: u8 = 2;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct State {
     inner: BaseMapper,
+    // the written value is ANDed with the rom byte at the same address
+    bus_conflicts: bool,
 }
 
 impl State {
     pub fn new(header: &NesHeader, prg_rom: &PrgRom, chr_rom: &ChrRom) -> Self {
         let mut inner = BaseMapper::new();
          
-        inner.initialize(prg_rom, chr_rom, 0, 0x2000);
+        inner.initialize(prg_rom, chr_rom, 0, header.chr_ram_size);
 
         inner.map_ppu_address(0x0000, BankType::CHR_MEM, 0, BankWindow::Size8k);
         
@@ -19,15 +24,11 @@ impl State {
         inner.map_cpu_address(0x8000, BankType::PRG_ROM, 0, BankWindow::Size16k);
         inner.map_cpu_address(0xC000, BankType::PRG_ROM, last_bank as u8, BankWindow::Size16k);
 
-        match header.mirroring {
-            super::MirrorMode::Vertical => {
-                inner.initialize_and_map_nametable_vertical();
-            }
-            super::MirrorMode::Horizontal => {
-                inner.initialize_and_map_nametable_horizontal();
-            }
-        };
-        State { inner }
+        inner.initialize_and_map_nametable(header.mirroring);
+        State {
+            inner,
+            bus_conflicts: header.submapper == SUBMAPPER_BUS_CONFLICTS,
+        }
     }
 }
 
@@ -42,6 +43,7 @@ impl Mapper for State {
                 self.inner.poke_cpu_memory(addr, value)
             }
             0x8000..=0xFFFF => {
+                let value = if self.bus_conflicts { value & self.inner.peek_cpu_memory(addr) } else { value };
                 let selector = value & 0b0000_0111;
                 self.inner.map_cpu_address(0x8000, BankType::PRG_ROM, selector, BankWindow::Size16k);
             }
@@ -49,6 +51,10 @@ impl Mapper for State {
         }
     }
 
+    fn reset_mapper_to_defaults(&mut self) {
+        self.inner.map_cpu_address(0x8000, BankType::PRG_ROM, 0, BankWindow::Size16k);
+    }
+
     fn vpeek(&mut self, addr: u16) -> u8 {
         self.inner.peek_ppu_memory(addr)
     }
@@ -57,6 +63,14 @@ impl Mapper for State {
         self.inner.poke_ppu_memory(addr, value)
     }
 
+    fn current_mirroring(&self) -> MirrorMode {
+        self.inner.current_mirroring()
+    }
+
+    fn set_mirroring(&mut self, mirroring: MirrorMode) {
+        self.inner.initialize_and_map_nametable(mirroring)
+    }
+
     fn load_state(&mut self, state: Vec<u8>) {
         let state: Self = bincode::deserialize(&state[..]).unwrap();
         *self = state;
@@ -65,4 +79,8 @@ impl Mapper for State {
     fn save_state(&self) -> Vec<u8> {
         bincode::serialize(&self).unwrap()
     }
+
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
 }
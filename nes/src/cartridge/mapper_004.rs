@@ -1,8 +1,8 @@
-use crate::cartridge::{BankType, BankWindow, BaseMapper, Mapper};
-use crate::cartridge::{ChrRom, NesHeader, PrgRom};
+use crate::cartridge::{BankType, BankWindow, BaseMapper, Mapper, MemAttr};
+use crate::cartridge::{ChrRom, MirrorMode, NesHeader, NesVersion, PrgRom};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct State {
     inner: BaseMapper,
     bank_register: u8,
@@ -12,15 +12,50 @@ pub struct State {
     irq_enable: bool,
     irq_counter: u8,
     irq_latch: u8,
+    irq_pending: bool,
     four_screen: bool,
-    irq_acknowledge_flag: bool
+    irq_acknowledge_flag: bool,
+    a12: bool,
+    a12_low_cycles: usize,
+    // $A001, enabled and writable at power on so games that never write it can still save
+    prg_ram_enable: bool,
+    prg_ram_write_protect: bool,
+    // only NES 2.0 submapper 0 is surely an MMC3. iNES 1.0 can't tell it from the MMC6,
+    // whose games (StarTropics) write their own 1K ram protect bits to $A001
+    has_prg_ram_protect: bool,
+}
+
+// A12 has to stay low for a few M2 cycles before a rising edge clocks the counter,
+// which filters out the toggling during background fetches
+const A12_LOW_CYCLES_FILTER: usize = 3;
+
+impl State {
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 {
+            self.irq_counter = self.irq_latch;
+        }
+        else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enable {
+            self.irq_pending = true;
+        }
+    }
+
+    fn update_prg_ram(&mut self) {
+        match (self.prg_ram_enable, self.prg_ram_write_protect) {
+            (false, _) => self.inner.unmap_cpu_address(0x6000, BankWindow::Size8k),
+            (true, true) => self.inner.map_cpu_address_with_attr(0x6000, BankType::PRG_RAM, 0, BankWindow::Size8k, MemAttr::ReadOnly),
+            (true, false) => self.inner.map_cpu_address(0x6000, BankType::PRG_RAM, 0, BankWindow::Size8k),
+        }
+    }
 }
 
 impl State {
     pub fn new(header: &NesHeader, prg_rom: &PrgRom, chr_rom: &ChrRom) -> Self {
         let mut inner = BaseMapper::new();
          
-        inner.initialize(prg_rom, chr_rom, 0x2000, 0x2000);
+        inner.initialize(prg_rom, chr_rom, 0x2000, header.chr_ram_size);
         
         inner.map_cpu_address(0x6000, BankType::PRG_RAM, 0, BankWindow::Size8k);
 
@@ -41,11 +76,8 @@ impl State {
             (_, true) => {
                 inner.initialize_and_map_nametable_fourscreen();
             }
-            (super::MirrorMode::Vertical, false) => {
-                inner.initialize_and_map_nametable_vertical();
-            }
-            (super::MirrorMode::Horizontal, false) => {
-                inner.initialize_and_map_nametable_horizontal();
+            (mirroring, false) => {
+                inner.initialize_and_map_nametable(mirroring);
             }
         };
         State { 
@@ -57,8 +89,14 @@ impl State {
             irq_enable: false,
             irq_counter: 0,
             irq_latch: 0, 
+            irq_pending: false,
             four_screen: header.four_screen_mode,
             irq_acknowledge_flag: false,
+            a12: false,
+            a12_low_cycles: 0,
+            prg_ram_enable: true,
+            prg_ram_write_protect: false,
+            has_prg_ram_protect: header.nes_version == NesVersion::V2 && header.submapper == 0,
         }
     }
 }
@@ -146,8 +184,10 @@ impl Mapper for State {
                         }
                     };
                 }
-                else {
-                    // not to implement `PRG RAM protect`
+                else if self.has_prg_ram_protect {
+                    self.prg_ram_enable = value & 0b1000_0000 != 0;
+                    self.prg_ram_write_protect = value & 0b0100_0000 != 0;
+                    self.update_prg_ram();
                 }
             }
             0xC000..=0xDFFF => {
@@ -161,6 +201,7 @@ impl Mapper for State {
             0xE000..=0xFFFF => {
                 if addr & 1 == 0 {
                     self.irq_enable = false;
+                    self.irq_pending = false;
                     self.irq_acknowledge_flag = true;
                 }
                 else {
@@ -171,6 +212,29 @@ impl Mapper for State {
         }
     }
 
+    fn reset_mapper_to_defaults(&mut self) {
+        self.bank_register = 0;
+        self.prg_rom_bank_mode = false;
+        self.chr_a12_inversion = false;
+        self.irq_enable = false;
+        self.irq_counter = 0;
+        self.irq_latch = 0;
+        self.irq_pending = false;
+        self.irq_acknowledge_flag = false;
+        self.a12 = false;
+        self.a12_low_cycles = 0;
+        self.prg_ram_enable = true;
+        self.prg_ram_write_protect = false;
+        self.update_prg_ram();
+
+        let last_bank = self.inner.bank_num(BankType::PRG_ROM, BankWindow::Size8k) - 1;
+        self.inner.map_ppu_address(0x0000, BankType::CHR_MEM, 0, BankWindow::Size8k);
+        self.inner.map_cpu_address(0x8000, BankType::PRG_ROM, 0, BankWindow::Size8k);
+        self.inner.map_cpu_address(0xA000, BankType::PRG_ROM, 0, BankWindow::Size8k);
+        self.inner.map_cpu_address(0xC000, BankType::PRG_ROM, self.second_last_prg_rom_bank as u8, BankWindow::Size8k);
+        self.inner.map_cpu_address(0xE000, BankType::PRG_ROM, last_bank as u8, BankWindow::Size8k);
+    }
+
     fn vpeek(&mut self, addr: u16) -> u8 {
         self.inner.peek_ppu_memory(addr)
     }
@@ -179,6 +243,22 @@ impl Mapper for State {
         self.inner.poke_ppu_memory(addr, value)
     }
 
+    fn current_mirroring(&self) -> MirrorMode {
+        self.inner.current_mirroring()
+    }
+
+    fn set_mirroring(&mut self, mirroring: MirrorMode) {
+        self.inner.initialize_and_map_nametable(mirroring)
+    }
+
+    fn sram(&self) -> Option<&[u8]> {
+        Some(self.inner.prg_ram())
+    }
+
+    fn sram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(self.inner.prg_ram_mut())
+    }
+
     fn load_state(&mut self, state: Vec<u8>) {
         let state: Self = bincode::deserialize(&state[..]).unwrap();
         *self = state;
@@ -188,20 +268,26 @@ impl Mapper for State {
         bincode::serialize(&self).unwrap()
     }
 
-    fn irq(&mut self) -> bool { 
-        if self.irq_counter == 0 {
-            self.irq_counter = self.irq_latch;
-            false
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+
+    fn on_cpu_cycle(&mut self) -> bool {
+        if !self.a12 {
+            self.a12_low_cycles += 1;
         }
-        else {
-            self.irq_counter -= 1;
-            if self.irq_counter == 0 {
-                true
-            }
-            else {
-                false
+        self.irq_pending
+    }
+
+    fn on_ppu_address(&mut self, addr: u16) {
+        let a12 = addr & 0x1000 != 0;
+        if a12 {
+            if !self.a12 && self.a12_low_cycles >= A12_LOW_CYCLES_FILTER {
+                self.clock_irq_counter();
             }
+            self.a12_low_cycles = 0;
         }
+        self.a12 = a12;
     }
 
     fn irq_acknowledge(&mut self) -> bool { 
@@ -210,3 +296,210 @@ impl Mapper for State {
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu;
+    use crate::region::Region;
+
+    fn mmc3() -> State {
+        mmc3_with(NesVersion::V1)
+    }
+
+    fn mmc3_with(nes_version: NesVersion) -> State {
+        let header = NesHeader {
+            prg_banks: 2,
+            chr_banks: 0,
+            mirroring: MirrorMode::Horizontal,
+            four_screen_mode: false,
+            has_battery: false,
+            nes_version,
+            mapper_id: 4,
+            submapper: 0,
+            region: None,
+            chr_ram_size: 0x2000,
+        };
+        State::new(&header, &vec![0; 0x8000], &Vec::new())
+    }
+
+    // A12 goes low for `low_cycles` M2 cycles, then rises
+    fn rise_a12(mapper: &mut State, low_cycles: usize) {
+        mapper.on_ppu_address(0x0000);
+        for _ in 0..low_cycles {
+            mapper.on_cpu_cycle();
+        }
+        mapper.on_ppu_address(0x1000);
+    }
+
+    // the real ppu fetching through the mapper, one M2 cycle every 3 dots
+    struct Board {
+        ppu: ppu::State,
+        mapper: State,
+        palette: ppu::Palette,
+        dots: usize,
+    }
+
+    impl ppu::Context for Board {
+        fn peek_vram(&mut self, addr: u16) -> u8 {
+            self.mapper.on_ppu_address(addr);
+            self.mapper.vpeek(if addr > 0x2FFF { addr & 0x2FFF } else { addr })
+        }
+
+        fn poke_vram(&mut self, addr: u16, val: u8) {
+            self.mapper.on_ppu_address(addr);
+            self.mapper.vpoke(addr, val)
+        }
+
+        fn state(&self) -> &ppu::State {
+            &self.ppu
+        }
+
+        fn state_mut(&mut self) -> &mut ppu::State {
+            &mut self.ppu
+        }
+
+        fn trigger_nmi(&mut self) {}
+
+        fn generate_frame(&mut self) {}
+
+        fn on_scanline_start(&mut self, _scanline: usize) {}
+
+        fn region(&self) -> Region {
+            Region::Ntsc
+        }
+
+        fn is_indexed_output_enabled(&self) -> bool {
+            false
+        }
+
+        fn is_oam_corruption_enabled(&self) -> bool {
+            false
+        }
+
+        fn palette(&self) -> &ppu::Palette {
+            &self.palette
+        }
+    }
+
+    impl Board {
+        fn tick_to(&mut self, scanline: usize, dot: usize) {
+            while ppu::Interface::get_beam_position(self) != (scanline, dot) {
+                ppu::Interface::tick(self);
+                self.dots += 1;
+                if self.dots % 3 == 0 {
+                    self.mapper.on_cpu_cycle();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn counter_is_clocked_once_per_scanline_with_sprites_at_1000() {
+        let mut board = Board { ppu: ppu::State::new(), mapper: mmc3(), palette: ppu::Palette::default_palette(), dots: 0 };
+        ppu::Interface::end_warmup(&mut board);
+        // background at $0000, 8x8 sprites at $1000
+        ppu::Interface::write_ppuctrl(&mut board, 0x08);
+        ppu::Interface::write_ppumask(&mut board, 0x18);
+        board.tick_to(0, 0);
+        board.mapper.irq_counter = 0;
+        board.mapper.irq_latch = 255;
+
+        for scanline in 1..240 {
+            board.tick_to(scanline, 0);
+            assert_eq!(board.mapper.irq_counter as usize, 256 - scanline, "scanline {}", scanline);
+        }
+    }
+
+    #[test]
+    fn short_a12_lows_are_filtered() {
+        let mut mapper = mmc3();
+        mapper.irq_latch = 10;
+        rise_a12(&mut mapper, A12_LOW_CYCLES_FILTER);
+        assert_eq!(mapper.irq_counter, 10);
+
+        rise_a12(&mut mapper, A12_LOW_CYCLES_FILTER - 1);
+        rise_a12(&mut mapper, 0);
+        assert_eq!(mapper.irq_counter, 10);
+
+        rise_a12(&mut mapper, A12_LOW_CYCLES_FILTER);
+        assert_eq!(mapper.irq_counter, 9);
+    }
+
+    #[test]
+    fn counter_reloads_at_0() {
+        let mut mapper = mmc3();
+        mapper.poke(0xC000, 2);
+        mapper.poke(0xE001, 0);
+
+        rise_a12(&mut mapper, A12_LOW_CYCLES_FILTER);
+        assert_eq!(mapper.irq_counter, 2);
+        assert!(!mapper.on_cpu_cycle());
+        rise_a12(&mut mapper, A12_LOW_CYCLES_FILTER);
+        rise_a12(&mut mapper, A12_LOW_CYCLES_FILTER);
+        assert_eq!(mapper.irq_counter, 0);
+        assert!(mapper.on_cpu_cycle());
+
+        // $C001 clears the counter, so the next clock reloads the new latch
+        mapper.poke(0xC000, 5);
+        mapper.poke(0xC001, 0);
+        rise_a12(&mut mapper, A12_LOW_CYCLES_FILTER);
+        assert_eq!(mapper.irq_counter, 5);
+    }
+
+    #[test]
+    fn latch_0_raises_irq_on_every_clock() {
+        let mut mapper = mmc3();
+        mapper.poke(0xC000, 0);
+        mapper.poke(0xE001, 0);
+        for _ in 0..3 {
+            mapper.poke(0xE000, 0);
+            mapper.poke(0xE001, 0);
+            rise_a12(&mut mapper, A12_LOW_CYCLES_FILTER);
+            assert!(mapper.irq_pending);
+        }
+    }
+
+    #[test]
+    fn e000_acknowledges_and_disables_irq() {
+        let mut mapper = mmc3();
+        mapper.poke(0xC000, 1);
+        mapper.poke(0xE001, 0);
+        rise_a12(&mut mapper, A12_LOW_CYCLES_FILTER);
+        rise_a12(&mut mapper, A12_LOW_CYCLES_FILTER);
+        assert!(mapper.on_cpu_cycle());
+        assert!(!mapper.irq_acknowledge());
+
+        mapper.poke(0xE000, 0);
+        assert!(!mapper.on_cpu_cycle());
+        assert!(mapper.irq_acknowledge());
+        assert!(!mapper.irq_acknowledge());
+
+        // disabled, the counter still runs but raises nothing
+        rise_a12(&mut mapper, A12_LOW_CYCLES_FILTER);
+        rise_a12(&mut mapper, A12_LOW_CYCLES_FILTER);
+        assert_eq!(mapper.irq_counter, 0);
+        assert!(!mapper.on_cpu_cycle());
+
+        // enabling again doesn't bring back the acknowledged irq
+        mapper.poke(0xE001, 0);
+        assert!(!mapper.on_cpu_cycle());
+    }
+
+    #[test]
+    fn a001_protects_prg_ram_only_on_nes_2_0_mmc3() {
+        let mut mapper = mmc3_with(NesVersion::V2);
+        mapper.poke(0x6000, 0x42);
+        mapper.poke(0xA001, 0b1100_0000);
+        mapper.poke(0x6000, 0x99);
+        assert_eq!(mapper.peek(0x6000), 0x42);
+
+        // an iNES 1.0 header might be an MMC6, which means something else by it
+        let mut mapper = mmc3_with(NesVersion::V1);
+        mapper.poke(0xA001, 0b1100_0000);
+        mapper.poke(0x6000, 0x99);
+        assert_eq!(mapper.peek(0x6000), 0x99);
+        mapper.poke(0xA001, 0b0000_0000);
+        assert_eq!(mapper.peek(0x6000), 0x99);
+    }
+}
@@ -0,0 +1,410 @@
+use crate::cartridge::{BankType, BankWindow, BaseMapper, Mapper};
+use crate::cartridge::{ChrRom, MirrorMode, NesHeader, PrgRom};
+use serde::{Deserialize, Serialize};
+
+// Namco 163
+
+const SOUND_RAM_SIZE: usize = 0x80;
+// channel registers take the top of sound ram, 8 bytes each from $40 (channel 0) to $78 (channel 7)
+const CHANNEL_REGISTERS_BASE: usize = 0x40;
+// cpu cycles spent on each channel update
+const CHANNEL_UPDATE_CYCLES: usize = 15;
+// a channel at full volume swings about as far as both pulse channels together
+const AUDIO_OUTPUT_SCALE: f32 = 0.3 / 225.0;
+// chr and nametable registers at or above this select a ciram page instead of chr rom
+const CIRAM_BANK_SELECT: u8 = 0xE0;
+const IRQ_COUNTER_MAX: u16 = 0x7FFF;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Audio {
+    ram: Vec<u8>,
+    address: u8,
+    auto_increment: bool,
+    disabled: bool,
+    cycles: usize,
+    current_channel: usize,
+    channel_outputs: [i16; 8],
+    output: f32,
+}
+
+impl Audio {
+    fn new() -> Self {
+        Audio {
+            ram: vec![0; SOUND_RAM_SIZE],
+            address: 0,
+            auto_increment: false,
+            disabled: false,
+            cycles: 0,
+            current_channel: 7,
+            channel_outputs: [0; 8],
+            output: 0.0,
+        }
+    }
+
+    fn set_address(&mut self, value: u8) {
+        self.address = value & 0x7F;
+        self.auto_increment = value & 0b1000_0000 != 0;
+    }
+
+    fn read_data(&mut self) -> u8 {
+        let value = self.ram[self.address as usize];
+        self.advance_address();
+        value
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.ram[self.address as usize] = value;
+        self.advance_address();
+    }
+
+    fn advance_address(&mut self) {
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7F;
+        }
+    }
+
+    // bits 4-6 of $7F hold the number of enabled channels minus 1, counted down from channel 7
+    fn enabled_channels(&self) -> usize {
+        ((self.ram[0x7F] >> 4) & 0b111) as usize + 1
+    }
+
+    fn tick(&mut self) {
+        if self.disabled {
+            return;
+        }
+        self.cycles += 1;
+        if self.cycles < CHANNEL_UPDATE_CYCLES {
+            return;
+        }
+        self.cycles = 0;
+
+        let enabled_channels = self.enabled_channels();
+        let channel = self.current_channel;
+        self.channel_outputs[channel] = self.update_channel(channel);
+        self.current_channel = if channel <= 8 - enabled_channels { 7 } else { channel - 1 };
+
+        // the chip outputs one channel at a time, so each is heard for 1/n of the time
+        let lowest_channel = 8 - enabled_channels;
+        let sum: i16 = self.channel_outputs[lowest_channel..].iter().sum();
+        self.output = sum as f32 / enabled_channels as f32 * AUDIO_OUTPUT_SCALE;
+    }
+
+    // steps the channel's phase by its frequency and returns its current sample times volume
+    fn update_channel(&mut self, channel: usize) -> i16 {
+        let base = CHANNEL_REGISTERS_BASE + channel * 8;
+        let register = |offset: usize| self.ram[base + offset] as u32;
+        let frequency = register(0) | register(2) << 8 | (register(4) & 0b11) << 16;
+        let phase = register(1) | register(3) << 8 | register(5) << 16;
+        let length = 256 - (register(4) & 0xFC);
+        let wave_address = register(6);
+        let volume = (register(7) & 0x0F) as i16;
+
+        let phase = (phase + frequency) % (length << 16);
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+
+        // 4 bit samples, two to a byte with the low nibble first
+        let sample_address = (((phase >> 16) + wave_address) & 0xFF) as usize;
+        let sample = (self.ram[sample_address >> 1] >> ((sample_address & 1) * 4)) & 0x0F;
+        (sample as i16 - 8) * volume
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct State {
+    inner: BaseMapper,
+    chr_registers: [u8; 8],
+    nametable_registers: [u8; 4],
+    // ciram can't be selected for $0000-$0FFF and $1000-$1FFF respectively while set
+    chr_low_ciram_disabled: bool,
+    chr_high_ciram_disabled: bool,
+    irq_counter: u16,
+    irq_enable: bool,
+    irq_pending: bool,
+    audio: Audio,
+}
+
+impl State {
+    pub fn new(header: &NesHeader, prg_rom: &PrgRom, chr_rom: &ChrRom) -> Self {
+        let mut inner = BaseMapper::new();
+
+        inner.initialize(prg_rom, chr_rom, 0x2000, header.chr_ram_size);
+
+        inner.map_cpu_address(0x6000, BankType::PRG_RAM, 0, BankWindow::Size8k);
+
+        // sets up ciram, the nametable registers decide the layout from there
+        inner.initialize_and_map_nametable(header.mirroring);
+
+        let mut state = State {
+            inner,
+            chr_registers: [0; 8],
+            nametable_registers: [0; 4],
+            chr_low_ciram_disabled: false,
+            chr_high_ciram_disabled: false,
+            irq_counter: 0,
+            irq_enable: false,
+            irq_pending: false,
+            audio: Audio::new(),
+        };
+        state.map_prg_banks_to_defaults();
+        state.update_chr_banks();
+        state
+    }
+
+    fn map_prg_banks_to_defaults(&mut self) {
+        let last_bank = self.inner.bank_num(BankType::PRG_ROM, BankWindow::Size8k) - 1;
+        self.inner.map_cpu_address(0x8000, BankType::PRG_ROM, 0, BankWindow::Size8k);
+        self.inner.map_cpu_address(0xA000, BankType::PRG_ROM, 0, BankWindow::Size8k);
+        self.inner.map_cpu_address(0xC000, BankType::PRG_ROM, 0, BankWindow::Size8k);
+        self.inner.map_cpu_address(0xE000, BankType::PRG_ROM, last_bank as u8, BankWindow::Size8k);
+    }
+
+    fn update_chr_banks(&mut self) {
+        for (i, &bank) in self.chr_registers.iter().enumerate() {
+            let ciram_disabled = if i < 4 { self.chr_low_ciram_disabled } else { self.chr_high_ciram_disabled };
+            let addr = i as u16 * 0x400;
+            if bank >= CIRAM_BANK_SELECT && !ciram_disabled {
+                self.inner.map_ppu_address(addr, BankType::NAMETABLE, bank & 1, BankWindow::Size1k);
+            }
+            else {
+                self.inner.map_ppu_address(addr, BankType::CHR_MEM, bank, BankWindow::Size1k);
+            }
+        }
+        for (i, &bank) in self.nametable_registers.iter().enumerate() {
+            let addr = 0x2000 + i as u16 * 0x400;
+            if bank >= CIRAM_BANK_SELECT {
+                self.inner.map_ppu_address(addr, BankType::NAMETABLE, bank & 1, BankWindow::Size1k);
+            }
+            else {
+                self.inner.map_ppu_address(addr, BankType::CHR_MEM, bank, BankWindow::Size1k);
+            }
+        }
+    }
+}
+
+impl Mapper for State {
+    fn peek_expansion_rom(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4FFF => Some(self.audio.read_data()),
+            0x5000..=0x57FF => Some(self.irq_counter as u8),
+            0x5800..=0x5FFF => Some((self.irq_counter >> 8) as u8 | if self.irq_enable { 0b1000_0000 } else { 0 }),
+            _ => None,
+        }
+    }
+
+    fn poke_expansion_rom(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4800..=0x4FFF => self.audio.write_data(value),
+            // writing either half of the counter acknowledges the irq
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | value as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | ((value as u16 & 0x7F) << 8);
+                self.irq_enable = value & 0b1000_0000 != 0;
+                self.irq_pending = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn peek(&mut self, addr: u16) -> u8 {
+        self.inner.peek_cpu_memory(addr)
+    }
+
+    fn poke(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x6000..=0x7FFF => {
+                self.inner.poke_cpu_memory(addr, value)
+            }
+            0x8000..=0xBFFF => {
+                self.chr_registers[((addr - 0x8000) / 0x800) as usize] = value;
+                self.update_chr_banks();
+            }
+            0xC000..=0xDFFF => {
+                self.nametable_registers[((addr - 0xC000) / 0x800) as usize] = value;
+                self.update_chr_banks();
+            }
+            0xE000..=0xE7FF => {
+                self.audio.disabled = value & 0b0100_0000 != 0;
+                self.inner.map_cpu_address(0x8000, BankType::PRG_ROM, value & 0b0011_1111, BankWindow::Size8k);
+            }
+            0xE800..=0xEFFF => {
+                self.chr_low_ciram_disabled = value & 0b0100_0000 != 0;
+                self.chr_high_ciram_disabled = value & 0b1000_0000 != 0;
+                self.inner.map_cpu_address(0xA000, BankType::PRG_ROM, value & 0b0011_1111, BankWindow::Size8k);
+                self.update_chr_banks();
+            }
+            0xF000..=0xF7FF => {
+                self.inner.map_cpu_address(0xC000, BankType::PRG_ROM, value & 0b0011_1111, BankWindow::Size8k);
+            }
+            0xF800..=0xFFFF => {
+                // not to implement `PRG RAM write protect`, only the sound ram address
+                self.audio.set_address(value);
+            }
+            _ => unreachable!("CPU ADDRESS: 0x{:X}", addr)
+        }
+    }
+
+    fn reset_mapper_to_defaults(&mut self) {
+        self.chr_registers = [0; 8];
+        self.nametable_registers = [0; 4];
+        self.chr_low_ciram_disabled = false;
+        self.chr_high_ciram_disabled = false;
+        self.irq_counter = 0;
+        self.irq_enable = false;
+        self.irq_pending = false;
+        self.audio = Audio::new();
+        self.map_prg_banks_to_defaults();
+        self.update_chr_banks();
+    }
+
+    fn vpeek(&mut self, addr: u16) -> u8 {
+        self.inner.peek_ppu_memory(addr)
+    }
+
+    fn vpoke(&mut self, addr: u16, value: u8) {
+        self.inner.poke_ppu_memory(addr, value)
+    }
+
+    fn on_cpu_cycle(&mut self) -> bool {
+        // counts up to $7FFF and stays there
+        if self.irq_enable && self.irq_counter < IRQ_COUNTER_MAX {
+            self.irq_counter += 1;
+            if self.irq_counter == IRQ_COUNTER_MAX {
+                self.irq_pending = true;
+            }
+        }
+        self.audio.tick();
+        self.irq_pending
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output
+    }
+
+    fn current_mirroring(&self) -> MirrorMode {
+        self.inner.current_mirroring()
+    }
+
+    fn set_mirroring(&mut self, mirroring: MirrorMode) {
+        self.inner.initialize_and_map_nametable(mirroring)
+    }
+
+    fn sram(&self) -> Option<&[u8]> {
+        Some(self.inner.prg_ram())
+    }
+
+    fn sram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(self.inner.prg_ram_mut())
+    }
+
+    fn load_state(&mut self, state: Vec<u8>) {
+        let state: Self = bincode::deserialize(&state[..]).unwrap();
+        *self = state;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(&self).unwrap()
+    }
+
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::NesVersion;
+
+    // 128K PRG and 64K CHR, each 1K CHR bank filled with its own number
+    fn n163() -> State {
+        let header = NesHeader {
+            prg_banks: 8,
+            chr_banks: 8,
+            mirroring: MirrorMode::Vertical,
+            four_screen_mode: false,
+            has_battery: false,
+            nes_version: NesVersion::V1,
+            mapper_id: 19,
+            submapper: 0,
+            region: None,
+            chr_ram_size: 0,
+        };
+        let chr_rom: Vec<u8> = (0..64u8).flat_map(|bank| vec![bank; 0x400]).collect();
+        State::new(&header, &vec![0; 0x20000], &chr_rom)
+    }
+
+    #[test]
+    fn sound_ram_address_increments_only_when_asked() {
+        let mut mapper = n163();
+        mapper.poke(0xF800, 0x80 | 0x7E);
+        for value in [0x11, 0x22, 0x33] {
+            mapper.poke_expansion_rom(0x4800, value);
+        }
+        // and wraps within the 128 bytes
+        mapper.poke(0xF800, 0x00);
+        assert_eq!(mapper.peek_expansion_rom(0x4800), Some(0x33));
+        assert_eq!(mapper.peek_expansion_rom(0x4800), Some(0x33));
+        mapper.poke(0xF800, 0x80 | 0x7E);
+        let reads: Vec<_> = (0..3).map(|_| mapper.peek_expansion_rom(0x4800)).collect();
+        assert_eq!(reads, [Some(0x11), Some(0x22), Some(0x33)]);
+    }
+
+    #[test]
+    fn channels_update_in_turn_from_channel_7_down() {
+        let mut audio = Audio::new();
+        let next_channels = |audio: &mut Audio| -> Vec<usize> {
+            (0..4).map(|_| {
+                (0..CHANNEL_UPDATE_CYCLES).for_each(|_| audio.tick());
+                audio.current_channel
+            }).collect()
+        };
+        assert_eq!(audio.enabled_channels(), 1);
+        assert_eq!(next_channels(&mut audio), [7, 7, 7, 7]);
+
+        audio.ram[0x7F] = 0x20;
+        assert_eq!(audio.enabled_channels(), 3);
+        assert_eq!(next_channels(&mut audio), [6, 5, 7, 6]);
+
+        audio.ram[0x7F] = 0x70;
+        assert_eq!(audio.enabled_channels(), 8);
+    }
+
+    #[test]
+    fn irq_counter_stops_at_7fff_until_acknowledged() {
+        let mut mapper = n163();
+        mapper.poke_expansion_rom(0x5000, 0xFD);
+        mapper.poke_expansion_rom(0x5800, 0x80 | 0x7F);
+        assert!(!mapper.on_cpu_cycle());
+        assert!(mapper.on_cpu_cycle());
+        assert!(mapper.on_cpu_cycle());
+        assert_eq!(mapper.peek_expansion_rom(0x5000), Some(0xFF));
+        assert_eq!(mapper.peek_expansion_rom(0x5800), Some(0xFF));
+
+        mapper.poke_expansion_rom(0x5000, 0xFF);
+        assert!(!mapper.on_cpu_cycle());
+        assert_eq!(mapper.peek_expansion_rom(0x5000), Some(0xFF));
+    }
+
+    #[test]
+    fn chr_registers_from_e0_select_ciram() {
+        let mut mapper = n163();
+        mapper.poke(0xC000, 0xE1);
+        mapper.vpoke(0x2000, 0x42);
+        mapper.poke(0x8000, 0xE1);
+        assert_eq!(mapper.vpeek(0x0000), 0x42);
+        mapper.poke(0x8000, 0xE0);
+        assert_ne!(mapper.vpeek(0x0000), 0x42);
+
+        // unless ciram is disabled for that half of the pattern tables
+        mapper.poke(0x8000, 0xE1);
+        mapper.poke(0xE800, 0b0100_0000);
+        assert_eq!(mapper.vpeek(0x0000), 0xE1 % 64);
+        mapper.poke(0xA000, 0xE1);
+        assert_eq!(mapper.vpeek(0x1000), 0x42);
+    }
+}
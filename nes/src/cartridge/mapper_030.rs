@@ -0,0 +1,264 @@
+use crate::cartridge::{BankType, BankWindow, BaseMapper, Mapper, MemAttr};
+use crate::cartridge::{ChrRom, MirrorMode, NesHeader, PrgRom};
+use serde::{Deserialize, Serialize};
+
+// UNROM 512: the board always has 32K of CHR-RAM
+const CHR_RAM_SIZE: usize = 0x8000;
+const FLASH_SECTOR_SIZE: usize = 0x1000;
+
+// SST39SF040 command sequences, driven by writes to $8000-$BFFF
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum FlashCommand {
+    Idle,
+    Unlocked1,
+    Unlocked2,
+    Program,
+    EraseUnlocked,
+    EraseUnlocked1,
+    EraseUnlocked2,
+    SoftwareId,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct State {
+    inner: BaseMapper,
+    // the flashable variant only decodes the bank register at $C000-$FFFF
+    flashable: bool,
+    // header "four screen" bit with horizontal mirroring means the register picks a single screen
+    one_screen: bool,
+    prg_bank: u8,
+    flash_command: FlashCommand,
+}
+
+impl State {
+    pub fn new(header: &NesHeader, prg_rom: &PrgRom, chr_rom: &ChrRom) -> Self {
+        let mut inner = BaseMapper::new();
+
+        inner.initialize(prg_rom, chr_rom, 0, header.chr_ram_size.max(CHR_RAM_SIZE));
+
+        inner.map_ppu_address(0x0000, BankType::CHR_MEM, 0, BankWindow::Size8k);
+
+        let flashable = header.has_battery;
+        let last_bank = inner.bank_num(BankType::PRG_ROM, BankWindow::Size16k) - 1;
+        let prg_attr = if flashable { MemAttr::ReadWrite } else { MemAttr::ReadOnly };
+        inner.map_cpu_address_with_attr(0x8000, BankType::PRG_ROM, 0, BankWindow::Size16k, prg_attr);
+        inner.map_cpu_address(0xC000, BankType::PRG_ROM, last_bank as u8, BankWindow::Size16k);
+
+        let one_screen = match (header.four_screen_mode, header.mirroring) {
+            (true, MirrorMode::Vertical) => {
+                inner.initialize_and_map_nametable_fourscreen();
+                false
+            }
+            (true, _) => {
+                inner.initialize_and_map_nametable_onescreen_lower_bank();
+                true
+            }
+            (false, mirroring) => {
+                inner.initialize_and_map_nametable(mirroring);
+                false
+            }
+        };
+        State {
+            inner,
+            flashable,
+            one_screen,
+            prg_bank: 0,
+            flash_command: FlashCommand::Idle,
+        }
+    }
+
+    fn write_bank_register(&mut self, value: u8) {
+        self.prg_bank = value & 0b0001_1111;
+        let prg_attr = if self.flashable { MemAttr::ReadWrite } else { MemAttr::ReadOnly };
+        self.inner.map_cpu_address_with_attr(0x8000, BankType::PRG_ROM, self.prg_bank, BankWindow::Size16k, prg_attr);
+        self.inner.map_ppu_address(0x0000, BankType::CHR_MEM, (value & 0b0110_0000) >> 5, BankWindow::Size8k);
+        if self.one_screen {
+            if value & 0b1000_0000 != 0 {
+                self.inner.initialize_and_map_nametable_onescreen_upper_bank();
+            }
+            else {
+                self.inner.initialize_and_map_nametable_onescreen_lower_bank();
+            }
+        }
+    }
+
+    // games flash their save data into PRG-ROM, the unlock addresses are $5555 and $2AAA of the chip
+    fn write_flash(&mut self, addr: u16, value: u8) {
+        let flash_addr = ((self.prg_bank as usize) << 14) | (addr as usize & 0x3FFF);
+        self.flash_command = match (self.flash_command, flash_addr, value) {
+            (FlashCommand::Program, _, _) => {
+                // programming can only clear bits
+                let old = self.inner.peek_cpu_memory(addr);
+                self.inner.poke_cpu_memory(addr, old & value);
+                FlashCommand::Idle
+            }
+            (_, _, 0xF0) => FlashCommand::Idle,
+            (FlashCommand::Idle, 0x5555, 0xAA) => FlashCommand::Unlocked1,
+            (FlashCommand::SoftwareId, 0x5555, 0xAA) => FlashCommand::Unlocked1,
+            (FlashCommand::Unlocked1, 0x2AAA, 0x55) => FlashCommand::Unlocked2,
+            (FlashCommand::Unlocked2, 0x5555, 0xA0) => FlashCommand::Program,
+            (FlashCommand::Unlocked2, 0x5555, 0x80) => FlashCommand::EraseUnlocked,
+            (FlashCommand::Unlocked2, 0x5555, 0x90) => FlashCommand::SoftwareId,
+            (FlashCommand::EraseUnlocked, 0x5555, 0xAA) => FlashCommand::EraseUnlocked1,
+            (FlashCommand::EraseUnlocked1, 0x2AAA, 0x55) => FlashCommand::EraseUnlocked2,
+            (FlashCommand::EraseUnlocked2, _, 0x30) => {
+                let sector = 0x8000 | (addr as usize & 0x3FFF & !(FLASH_SECTOR_SIZE - 1));
+                for i in 0..FLASH_SECTOR_SIZE {
+                    self.inner.poke_cpu_memory((sector + i) as u16, 0xFF);
+                }
+                FlashCommand::Idle
+            }
+            (FlashCommand::SoftwareId, _, _) => FlashCommand::SoftwareId,
+            _ => FlashCommand::Idle,
+        };
+    }
+}
+
+impl Mapper for State {
+    fn peek(&mut self, addr: u16) -> u8 {
+        match addr {
+            // no PRG-RAM on this board
+            0x6000..=0x7FFF => 0,
+            // manufacturer and device id of the SST39SF040
+            0x8000..=0xBFFF if self.flash_command == FlashCommand::SoftwareId => {
+                if addr & 1 == 0 { 0xBF } else { 0xB7 }
+            }
+            _ => self.inner.peek_cpu_memory(addr)
+        }
+    }
+
+    fn poke(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x6000..=0x7FFF => {}
+            0x8000..=0xBFFF if self.flashable => {
+                self.write_flash(addr, value);
+            }
+            0x8000..=0xFFFF => {
+                self.write_bank_register(value);
+            }
+            _ => unreachable!("CPU ADDRESS: 0x{:X}", addr)
+        }
+    }
+
+    fn reset_mapper_to_defaults(&mut self) {
+        self.flash_command = FlashCommand::Idle;
+        self.write_bank_register(0);
+    }
+
+    fn vpeek(&mut self, addr: u16) -> u8 {
+        self.inner.peek_ppu_memory(addr)
+    }
+
+    fn vpoke(&mut self, addr: u16, value: u8) {
+        self.inner.poke_ppu_memory(addr, value)
+    }
+
+    fn current_mirroring(&self) -> MirrorMode {
+        self.inner.current_mirroring()
+    }
+
+    fn set_mirroring(&mut self, mirroring: MirrorMode) {
+        self.inner.initialize_and_map_nametable(mirroring)
+    }
+
+    fn load_state(&mut self, state: Vec<u8>) {
+        let state: Self = bincode::deserialize(&state[..]).unwrap();
+        *self = state;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(&self).unwrap()
+    }
+
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::NesVersion;
+
+    // 8 PRG banks, each filled with its own number
+    fn unrom512(flashable: bool, four_screen_mode: bool) -> State {
+        let header = NesHeader {
+            prg_banks: 8,
+            chr_banks: 0,
+            mirroring: MirrorMode::Horizontal,
+            four_screen_mode,
+            has_battery: flashable,
+            nes_version: NesVersion::V1,
+            mapper_id: 30,
+            submapper: 0,
+            region: None,
+            chr_ram_size: 0,
+        };
+        let prg_rom: Vec<u8> = (0..8u8).flat_map(|bank| vec![bank; 0x4000]).collect();
+        State::new(&header, &prg_rom, &Vec::new())
+    }
+
+    // the chip's own addresses $5555 and $2AAA, reached through bank 1 and bank 0
+    fn flash_command(mapper: &mut State, command: u8) {
+        for (bank, addr, value) in [(1, 0x9555, 0xAA), (0, 0xAAAA, 0x55), (1, 0x9555, command)] {
+            mapper.poke(0xC000, bank);
+            mapper.poke(addr, value);
+        }
+    }
+
+    #[test]
+    fn register_switches_prg_and_chr_banks() {
+        let mut mapper = unrom512(false, false);
+        assert_eq!((mapper.peek(0x8000), mapper.peek(0xC000)), (0, 7));
+        mapper.vpoke(0x0000, 0x11);
+        mapper.poke(0x8000, 0b0100_0101);
+        assert_eq!((mapper.peek(0x8000), mapper.peek(0xFFFF)), (5, 7));
+        assert_eq!(mapper.vpeek(0x0000), 0x00);
+        mapper.vpoke(0x0000, 0x22);
+        mapper.poke(0xFFFF, 0);
+        assert_eq!(mapper.vpeek(0x0000), 0x11);
+    }
+
+    #[test]
+    fn four_screen_bit_with_horizontal_mirroring_selects_a_single_screen() {
+        let mut mapper = unrom512(false, true);
+        mapper.poke(0x8000, 0x80);
+        assert_eq!(mapper.current_mirroring(), MirrorMode::OneScreenHigh);
+        mapper.poke(0x8000, 0x00);
+        assert_eq!(mapper.current_mirroring(), MirrorMode::OneScreenLow);
+    }
+
+    #[test]
+    fn flash_programs_bytes_and_erases_sectors() {
+        let mut mapper = unrom512(true, false);
+        // the flashable board only takes bank writes at $C000-$FFFF
+        mapper.poke(0x8000, 3);
+        assert_eq!(mapper.peek(0x8000), 0);
+
+        flash_command(&mut mapper, 0xA0);
+        mapper.poke(0xC000, 2);
+        mapper.poke(0x8123, 0xF0);
+        // programming only clears bits, bank 2 is filled with 0x02
+        assert_eq!(mapper.peek(0x8123), 0x00);
+        assert_eq!(mapper.peek(0x8124), 0x02);
+
+        // the sector erase is a second unlock with the sector address last
+        flash_command(&mut mapper, 0x80);
+        for (bank, addr, value) in [(1, 0x9555, 0xAA), (0, 0xAAAA, 0x55), (2, 0x9000, 0x30)] {
+            mapper.poke(0xC000, bank);
+            mapper.poke(addr, value);
+        }
+        assert_eq!(mapper.peek(0x8FFF), 0x02);
+        assert!((0x9000..0xA000).all(|addr| mapper.peek(addr) == 0xFF));
+        assert_eq!(mapper.peek(0xA000), 0x02);
+    }
+
+    #[test]
+    fn software_id_mode_reads_the_chip_ids() {
+        let mut mapper = unrom512(true, false);
+        flash_command(&mut mapper, 0x90);
+        assert_eq!((mapper.peek(0x8000), mapper.peek(0x8001)), (0xBF, 0xB7));
+        mapper.poke(0x8000, 0xF0);
+        assert_eq!(mapper.peek(0x8000), 1);
+    }
+}
@@ -0,0 +1,731 @@
+use crate::cartridge::{BankType, BankWindow, BaseMapper, Mapper};
+use crate::cartridge::MirrorMode;
+use serde::{Deserialize, Serialize};
+
+pub const FDS_BIOS_SIZE: usize = 0x2000;
+pub const FDS_DISK_SIDE_SIZE: usize = 65500;
+
+// the drive needs about 150 cpu cycles per byte and a while to spin up
+const DISK_BYTE_CYCLES: usize = 150;
+const DISK_SPIN_UP_CYCLES: usize = 50000;
+
+// .fds images leave out the gaps and crcs the bios expects to see on the disk
+const DISK_LEADING_GAP_BYTES: usize = 28300 / 8;
+const DISK_BLOCK_GAP_BYTES: usize = 976 / 8;
+
+// wave * gain at full volume is roughly 2.4 times a single pulse channel
+const AUDIO_OUTPUT_SCALE: f32 = 0.36 / (63.0 * 32.0);
+const MASTER_VOLUME: [f32; 4] = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+const MOD_TABLE_STEPS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Envelope {
+    enabled: bool,
+    increase: bool,
+    speed: u8,
+    gain: u8,
+    timer: usize,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope { enabled: false, increase: false, speed: 0, gain: 0, timer: 0 }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.enabled = value & 0b1000_0000 == 0;
+        self.increase = value & 0b0100_0000 != 0;
+        self.speed = value & 0b0011_1111;
+        if !self.enabled {
+            self.gain = self.speed;
+        }
+        self.timer = 0;
+    }
+
+    fn tick(&mut self, master_speed: u8) {
+        if !self.enabled || master_speed == 0 {
+            return;
+        }
+        self.timer += 1;
+        if self.timer >= 8 * (self.speed as usize + 1) * master_speed as usize {
+            self.timer = 0;
+            if self.increase && self.gain < 32 {
+                self.gain += 1;
+            }
+            else if !self.increase && self.gain > 0 {
+                self.gain -= 1;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Audio {
+    wave_table: Vec<u8>,
+    wave_write_enabled: bool,
+    wave_halted: bool,
+    wave_frequency: u16,
+    wave_accumulator: u32,
+    wave_position: usize,
+    envelopes_halted: bool,
+    master_volume: u8,
+    master_envelope_speed: u8,
+    volume_envelope: Envelope,
+    mod_envelope: Envelope,
+    mod_table: Vec<u8>,
+    mod_halted: bool,
+    mod_frequency: u16,
+    mod_accumulator: u32,
+    mod_position: usize,
+    mod_counter: i8,
+    output: f32,
+}
+
+impl Audio {
+    fn new() -> Self {
+        Audio {
+            wave_table: vec![0; 64],
+            wave_write_enabled: false,
+            wave_halted: true,
+            wave_frequency: 0,
+            wave_accumulator: 0,
+            wave_position: 0,
+            envelopes_halted: false,
+            master_volume: 0,
+            master_envelope_speed: 0xE8,
+            volume_envelope: Envelope::new(),
+            mod_envelope: Envelope::new(),
+            mod_table: vec![0; 64],
+            mod_halted: true,
+            mod_frequency: 0,
+            mod_accumulator: 0,
+            mod_position: 0,
+            mod_counter: 0,
+            output: 0.0,
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x4040..=0x407F => self.wave_table[(addr & 0x3F) as usize] | 0x40,
+            0x4090 => self.volume_envelope.gain | 0x40,
+            0x4092 => self.mod_envelope.gain | 0x40,
+            _ => 0x40,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write_enabled => {
+                self.wave_table[(addr & 0x3F) as usize] = value & 0x3F;
+            }
+            0x4080 => self.volume_envelope.write(value),
+            0x4082 => {
+                self.wave_frequency = (self.wave_frequency & 0x0F00) | value as u16;
+            }
+            0x4083 => {
+                self.wave_frequency = (self.wave_frequency & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.envelopes_halted = value & 0b0100_0000 != 0;
+                self.wave_halted = value & 0b1000_0000 != 0;
+                if self.wave_halted {
+                    self.wave_accumulator = 0;
+                    self.wave_position = 0;
+                }
+            }
+            0x4084 => self.mod_envelope.write(value),
+            0x4085 => {
+                // 7-bit signed
+                self.mod_counter = ((value << 1) as i8) >> 1;
+            }
+            0x4086 => {
+                self.mod_frequency = (self.mod_frequency & 0x0F00) | value as u16;
+            }
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.mod_halted = value & 0b1000_0000 != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            0x4088 if self.mod_halted => {
+                // each write fills two consecutive entries
+                self.mod_table[self.mod_position] = value & 0b111;
+                self.mod_table[(self.mod_position + 1) & 0x3F] = value & 0b111;
+                self.mod_position = (self.mod_position + 2) & 0x3F;
+            }
+            0x4089 => {
+                self.wave_write_enabled = value & 0b1000_0000 != 0;
+                self.master_volume = value & 0b11;
+            }
+            0x408A => {
+                self.master_envelope_speed = value;
+            }
+            _ => {}
+        }
+    }
+
+    // the modulator bends the wave frequency by counter * gain
+    fn modulated_frequency(&self) -> u32 {
+        let mut temp = self.mod_counter as i32 * self.mod_envelope.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            if self.mod_counter < 0 { temp -= 1 } else { temp += 2 }
+        }
+        if temp >= 192 {
+            temp -= 256;
+        }
+        else if temp < -64 {
+            temp += 256;
+        }
+        let pitch = self.wave_frequency as i32;
+        let mut temp = pitch * temp;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        (pitch + temp).max(0) as u32
+    }
+
+    fn tick(&mut self) {
+        if !self.envelopes_halted && !self.wave_halted {
+            self.volume_envelope.tick(self.master_envelope_speed);
+            self.mod_envelope.tick(self.master_envelope_speed);
+        }
+
+        if !self.mod_halted && self.mod_frequency != 0 {
+            self.mod_accumulator += self.mod_frequency as u32;
+            if self.mod_accumulator >= 0x10000 {
+                self.mod_accumulator &= 0xFFFF;
+                match self.mod_table[self.mod_position] {
+                    4 => self.mod_counter = 0,
+                    step => {
+                        let counter = self.mod_counter as i16 + MOD_TABLE_STEPS[step as usize] as i16;
+                        // wraps within 7 bits
+                        self.mod_counter = (((counter as u8) << 1) as i8) >> 1;
+                    }
+                }
+                self.mod_position = (self.mod_position + 1) & 0x3F;
+            }
+        }
+
+        if !self.wave_halted && !self.wave_write_enabled {
+            let frequency = if self.mod_halted { self.wave_frequency as u32 } else { self.modulated_frequency() };
+            self.wave_accumulator += frequency;
+            if self.wave_accumulator >= 0x10000 {
+                self.wave_accumulator &= 0xFFFF;
+                self.wave_position = (self.wave_position + 1) & 0x3F;
+            }
+        }
+
+        // the wave output holds its last value while the table is being written
+        if !self.wave_write_enabled {
+            let gain = self.volume_envelope.gain.min(32) as f32;
+            let wave = self.wave_table[self.wave_position] as f32;
+            self.output = wave * gain * MASTER_VOLUME[self.master_volume as usize] * AUDIO_OUTPUT_SCALE;
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct State {
+    inner: BaseMapper,
+    sides: Vec<Vec<u8>>,
+    current_side: Option<usize>,
+
+    disk_registers_enabled: bool,
+    sound_registers_enabled: bool,
+
+    timer_irq_reload: u16,
+    timer_irq_counter: u16,
+    timer_irq_repeat: bool,
+    timer_irq_enabled: bool,
+    timer_irq_pending: bool,
+
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    previous_crc_control: bool,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+    disk_irq_pending: bool,
+    irq_acknowledge_flag: bool,
+
+    disk_position: usize,
+    disk_delay: usize,
+    end_of_head: bool,
+    scanning_disk: bool,
+    gap_ended: bool,
+    transfer_complete: bool,
+    read_data: u8,
+    write_data: u8,
+    crc_accumulator: u16,
+    external_connector: u8,
+
+    audio: Audio,
+}
+
+impl State {
+    pub fn new(sides: Vec<Vec<u8>>, bios: &[u8]) -> Self {
+        let mut inner = BaseMapper::new();
+        inner.initialize(&bios.to_vec(), &Vec::new(), 0x8000, 0x2000);
+
+        inner.map_cpu_address(0x6000, BankType::PRG_RAM, 0, BankWindow::Size8k);
+        inner.map_cpu_address(0x8000, BankType::PRG_RAM, 1, BankWindow::Size8k);
+        inner.map_cpu_address(0xA000, BankType::PRG_RAM, 2, BankWindow::Size8k);
+        inner.map_cpu_address(0xC000, BankType::PRG_RAM, 3, BankWindow::Size8k);
+        inner.map_cpu_address(0xE000, BankType::PRG_ROM, 0, BankWindow::Size8k);
+        inner.map_ppu_address(0x0000, BankType::CHR_MEM, 0, BankWindow::Size8k);
+        inner.initialize_and_map_nametable(MirrorMode::Horizontal);
+
+        let sides = sides.iter().map(|side| State::add_gaps(side)).collect();
+        State {
+            inner,
+            sides,
+            current_side: Some(0),
+            disk_registers_enabled: false,
+            sound_registers_enabled: false,
+            timer_irq_reload: 0,
+            timer_irq_counter: 0,
+            timer_irq_repeat: false,
+            timer_irq_enabled: false,
+            timer_irq_pending: false,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: false,
+            crc_control: false,
+            previous_crc_control: false,
+            disk_ready: false,
+            disk_irq_enabled: false,
+            disk_irq_pending: false,
+            irq_acknowledge_flag: false,
+            disk_position: 0,
+            disk_delay: 0,
+            end_of_head: true,
+            scanning_disk: false,
+            gap_ended: false,
+            transfer_complete: false,
+            read_data: 0,
+            write_data: 0,
+            crc_accumulator: 0,
+            external_connector: 0,
+            audio: Audio::new(),
+        }
+    }
+
+    // lays the blocks of an .fds side out the way the drive would see them
+    fn add_gaps(side: &[u8]) -> Vec<u8> {
+        let mut disk = vec![0u8; DISK_LEADING_GAP_BYTES];
+        let mut position = 0;
+        while position < side.len() {
+            let block_length = match side[position] {
+                1 => 56,
+                2 => 2,
+                3 => 16,
+                4 if position >= 3 => 1 + side[position - 3] as usize + ((side[position - 2] as usize) << 8),
+                _ => break,
+            };
+            let block_end = (position + block_length).min(side.len());
+            let block = &side[position..block_end];
+
+            let mut crc = 0u16;
+            for &value in [0x80u8].iter().chain(block.iter()).chain([0u8, 0u8].iter()) {
+                crc = State::update_crc(crc, value);
+            }
+            disk.push(0x80);
+            disk.extend_from_slice(block);
+            disk.extend_from_slice(&crc.to_le_bytes());
+            disk.resize(disk.len() + DISK_BLOCK_GAP_BYTES, 0);
+            position = block_end;
+        }
+        disk.resize(disk.len().max(FDS_DISK_SIDE_SIZE), 0);
+        disk
+    }
+
+    fn update_crc(mut crc: u16, value: u8) -> u16 {
+        for bit in 0..8 {
+            let carry = crc & 1;
+            crc >>= 1;
+            if carry != 0 {
+                crc ^= 0x8408;
+            }
+            if value & (1 << bit) != 0 {
+                crc ^= 0x8000;
+            }
+        }
+        crc
+    }
+
+    fn acknowledge_disk_irq(&mut self) {
+        if self.disk_irq_pending {
+            self.disk_irq_pending = false;
+            self.irq_acknowledge_flag = !self.timer_irq_pending;
+        }
+    }
+
+    fn acknowledge_timer_irq(&mut self) {
+        if self.timer_irq_pending {
+            self.timer_irq_pending = false;
+            self.irq_acknowledge_flag = !self.disk_irq_pending;
+        }
+    }
+
+    fn tick_timer_irq(&mut self) {
+        if !self.timer_irq_enabled {
+            return;
+        }
+        if self.timer_irq_counter == 0 {
+            self.timer_irq_pending = true;
+            self.timer_irq_counter = self.timer_irq_reload;
+            if !self.timer_irq_repeat {
+                self.timer_irq_enabled = false;
+            }
+        }
+        else {
+            self.timer_irq_counter -= 1;
+        }
+    }
+
+    fn tick_disk(&mut self) {
+        let side = match self.current_side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning_disk = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning_disk {
+            return;
+        }
+        if self.end_of_head {
+            self.disk_delay = DISK_SPIN_UP_CYCLES;
+            self.end_of_head = false;
+            self.disk_position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.disk_delay > 0 {
+            self.disk_delay -= 1;
+            return;
+        }
+
+        self.scanning_disk = true;
+        let mut raise_irq = self.disk_irq_enabled;
+        if self.read_mode {
+            let value = self.sides[side][self.disk_position];
+            if !self.previous_crc_control {
+                self.crc_accumulator = State::update_crc(self.crc_accumulator, value);
+            }
+            if !self.disk_ready {
+                self.gap_ended = false;
+                self.crc_accumulator = 0;
+            }
+            else if value != 0 && !self.gap_ended {
+                // the block start mark is not handed to the cpu
+                self.gap_ended = true;
+                raise_irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = value;
+                if raise_irq {
+                    self.disk_irq_pending = true;
+                }
+            }
+        }
+        else {
+            if !self.crc_control {
+                self.transfer_complete = true;
+                if raise_irq {
+                    self.disk_irq_pending = true;
+                }
+            }
+            let mut value = if self.previous_crc_control { 0 } else { self.write_data };
+            if !self.disk_ready {
+                value = 0;
+            }
+            if !self.crc_control {
+                self.crc_accumulator = State::update_crc(self.crc_accumulator, value);
+            }
+            else {
+                if !self.previous_crc_control {
+                    self.crc_accumulator = State::update_crc(self.crc_accumulator, 0);
+                    self.crc_accumulator = State::update_crc(self.crc_accumulator, 0);
+                }
+                value = self.crc_accumulator as u8;
+                self.crc_accumulator >>= 8;
+            }
+            self.sides[side][self.disk_position] = value;
+            self.gap_ended = false;
+        }
+        self.previous_crc_control = self.crc_control;
+
+        self.disk_position += 1;
+        if self.disk_position >= self.sides[side].len() {
+            self.motor_on = false;
+            if raise_irq {
+                self.disk_irq_pending = true;
+            }
+        }
+        else {
+            self.disk_delay = DISK_BYTE_CYCLES;
+        }
+    }
+}
+
+impl Mapper for State {
+    fn peek_expansion_rom(&mut self, addr: u16) -> Option<u8> {
+        let value = match addr {
+            0x4030 if self.disk_registers_enabled => {
+                let mut value = 0;
+                if self.timer_irq_pending {
+                    value |= 0b0000_0001;
+                }
+                if self.transfer_complete {
+                    value |= 0b0000_0010;
+                }
+                if self.read_mode && self.crc_accumulator != 0 {
+                    value |= 0b0001_0000;
+                }
+                self.transfer_complete = false;
+                self.acknowledge_timer_irq();
+                self.acknowledge_disk_irq();
+                value
+            }
+            0x4031 if self.disk_registers_enabled => {
+                self.transfer_complete = false;
+                self.acknowledge_disk_irq();
+                self.read_data
+            }
+            0x4032 if self.disk_registers_enabled => {
+                let inserted = self.current_side.is_some();
+                let mut value = 0x40;
+                if !inserted {
+                    value |= 0b0000_0001;
+                }
+                if !(inserted && self.scanning_disk) {
+                    value |= 0b0000_0010;
+                }
+                if !inserted {
+                    value |= 0b0000_0100;
+                }
+                value
+            }
+            0x4033 if self.disk_registers_enabled => {
+                // battery is fine
+                0x80
+            }
+            0x4040..=0x4097 if self.sound_registers_enabled => self.audio.read(addr),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn poke_expansion_rom(&mut self, addr: u16, value: u8) {
+        if (0x4024..=0x4026).contains(&addr) && !self.disk_registers_enabled {
+            return;
+        }
+        match addr {
+            0x4020 => {
+                self.timer_irq_reload = (self.timer_irq_reload & 0xFF00) | value as u16;
+            }
+            0x4021 => {
+                self.timer_irq_reload = (self.timer_irq_reload & 0x00FF) | ((value as u16) << 8);
+            }
+            0x4022 => {
+                self.timer_irq_repeat = value & 0b0000_0001 != 0;
+                self.timer_irq_enabled = value & 0b0000_0010 != 0 && self.disk_registers_enabled;
+                if self.timer_irq_enabled {
+                    self.timer_irq_counter = self.timer_irq_reload;
+                }
+                self.acknowledge_timer_irq();
+            }
+            0x4023 => {
+                self.disk_registers_enabled = value & 0b0000_0001 != 0;
+                self.sound_registers_enabled = value & 0b0000_0010 != 0;
+                if !self.disk_registers_enabled {
+                    self.timer_irq_enabled = false;
+                    self.acknowledge_timer_irq();
+                    self.acknowledge_disk_irq();
+                }
+            }
+            0x4024 => {
+                self.write_data = value;
+                self.transfer_complete = false;
+                self.acknowledge_disk_irq();
+            }
+            0x4025 => {
+                self.motor_on = value & 0b0000_0001 != 0;
+                self.reset_transfer = value & 0b0000_0010 != 0;
+                self.read_mode = value & 0b0000_0100 != 0;
+                if value & 0b0000_1000 != 0 {
+                    self.inner.initialize_and_map_nametable_horizontal();
+                }
+                else {
+                    self.inner.initialize_and_map_nametable_vertical();
+                }
+                self.crc_control = value & 0b0001_0000 != 0;
+                self.disk_ready = value & 0b0100_0000 != 0;
+                self.disk_irq_enabled = value & 0b1000_0000 != 0;
+                self.acknowledge_disk_irq();
+            }
+            0x4026 => {
+                self.external_connector = value;
+            }
+            0x4040..=0x4097 if self.sound_registers_enabled => self.audio.write(addr, value),
+            _ => {}
+        }
+    }
+
+    fn peek(&mut self, addr: u16) -> u8 {
+        self.inner.peek_cpu_memory(addr)
+    }
+
+    fn poke(&mut self, addr: u16, value: u8) {
+        self.inner.poke_cpu_memory(addr, value)
+    }
+
+    fn vpeek(&mut self, addr: u16) -> u8 {
+        self.inner.peek_ppu_memory(addr)
+    }
+
+    fn vpoke(&mut self, addr: u16, value: u8) {
+        self.inner.poke_ppu_memory(addr, value)
+    }
+
+    fn reset_mapper_to_defaults(&mut self) {
+        self.disk_registers_enabled = false;
+        self.sound_registers_enabled = false;
+        self.timer_irq_enabled = false;
+        self.timer_irq_pending = false;
+        self.disk_irq_pending = false;
+        self.irq_acknowledge_flag = false;
+        self.motor_on = false;
+        self.scanning_disk = false;
+        self.end_of_head = true;
+        self.transfer_complete = false;
+        self.audio = Audio::new();
+        self.inner.initialize_and_map_nametable(MirrorMode::Horizontal);
+    }
+
+    fn on_cpu_cycle(&mut self) -> bool {
+        self.tick_timer_irq();
+        self.tick_disk();
+        self.audio.tick();
+        self.timer_irq_pending || self.disk_irq_pending
+    }
+
+    fn irq_acknowledge(&mut self) -> bool {
+        let res = self.irq_acknowledge_flag;
+        self.irq_acknowledge_flag = false;
+        res
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output
+    }
+
+    fn current_mirroring(&self) -> MirrorMode {
+        self.inner.current_mirroring()
+    }
+
+    fn set_mirroring(&mut self, mirroring: MirrorMode) {
+        self.inner.initialize_and_map_nametable(mirroring)
+    }
+
+    fn load_state(&mut self, state: Vec<u8>) {
+        let state: Self = bincode::deserialize(&state[..]).unwrap();
+        *self = state;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(&self).unwrap()
+    }
+
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::{load_fds_from_bytes, FDS_MAPPER_ID};
+    use crate::error::LoadError;
+
+    // one side starting with the disk info block, under a bios of NOPs
+    fn fds() -> State {
+        let mut side = vec![0u8; FDS_DISK_SIDE_SIZE];
+        side[0] = 1;
+        side[1..15].copy_from_slice(b"*NINTENDO-HVC*");
+        State::new(vec![side], &[0xEA; FDS_BIOS_SIZE])
+    }
+
+    // waits for the transfer flag in $4030 and gives the byte in $4031
+    fn next_disk_byte(fds: &mut State) -> u8 {
+        let mut cycles = 0;
+        while fds.peek_expansion_rom(0x4030).unwrap() & 0b0000_0010 == 0 {
+            fds.on_cpu_cycle();
+            cycles += 1;
+            assert!(cycles < 1_000_000, "the drive never delivered a byte");
+        }
+        fds.peek_expansion_rom(0x4031).unwrap()
+    }
+
+    #[test]
+    fn loader_checks_the_bios_and_skips_the_fds_header() {
+        let mut image = b"FDS\x1a\x01".to_vec();
+        image.resize(16 + FDS_DISK_SIDE_SIZE, 0);
+        let bios = vec![0; FDS_BIOS_SIZE];
+        assert!(matches!(load_fds_from_bytes(&image, &bios[1..]), Err(LoadError::FdsBiosNotFound)));
+        assert!(matches!(load_fds_from_bytes(&image[..16 + 100], &bios), Err(LoadError::UnexpectedEof)));
+        let (header, _) = load_fds_from_bytes(&image, &bios).unwrap();
+        assert_eq!(header.mapper_id, FDS_MAPPER_ID);
+        assert!(header.has_battery);
+    }
+
+    #[test]
+    fn bios_sits_at_e000_over_32k_of_ram() {
+        let mut fds = fds();
+        assert_eq!(fds.peek(0xE000), 0xEA);
+        fds.poke(0x6000, 0x12);
+        fds.poke(0xDFFF, 0x34);
+        fds.poke(0xE000, 0x56);
+        assert_eq!((fds.peek(0x6000), fds.peek(0xDFFF), fds.peek(0xE000)), (0x12, 0x34, 0xEA));
+    }
+
+    #[test]
+    fn drive_reads_the_block_mark_then_the_side() {
+        let mut fds = fds();
+        fds.poke_expansion_rom(0x4023, 0x01);
+        // motor on, read mode, disk ready
+        fds.poke_expansion_rom(0x4025, 0x45);
+        assert_eq!(next_disk_byte(&mut fds), 0x80);
+        assert_eq!(next_disk_byte(&mut fds), 0x01);
+        assert_eq!(next_disk_byte(&mut fds), b'*');
+        assert_eq!(next_disk_byte(&mut fds), b'N');
+    }
+
+    #[test]
+    fn timer_irq_fires_once_after_the_reload_value() {
+        let mut fds = fds();
+        fds.poke_expansion_rom(0x4023, 0x01);
+        fds.poke_expansion_rom(0x4020, 10);
+        fds.poke_expansion_rom(0x4021, 0);
+        fds.poke_expansion_rom(0x4022, 0x02);
+        for _ in 0..10 {
+            assert!(!fds.on_cpu_cycle());
+        }
+        assert!(fds.on_cpu_cycle());
+
+        assert_eq!(fds.peek_expansion_rom(0x4030), Some(0b0000_0001));
+        assert!(fds.irq_acknowledge());
+        // without the repeat bit the timer stops
+        for _ in 0..20 {
+            assert!(!fds.on_cpu_cycle());
+        }
+    }
+}
@@ -4,25 +4,98 @@ mod mapper_001;
 mod mapper_002;
 mod mapper_003;
 mod mapper_004;
+mod mapper_019;
+mod mapper_030;
+mod mapper_fds;
 mod nesrom;
+mod unif;
 
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, SeekFrom};
 
 use crate::error::LoadError;
 // use crate::rom::Rom;
 pub use mapper::*;
 pub use nesrom::{NesHeader, NesVersion, MirrorMode, PrgRom, ChrRom, Trainner};
 
+pub const FDS_MAPPER_ID: u16 = 20;
+
+// the usual board name for the mappers create_mapper knows
+pub fn mapper_name(mapper_id: u16) -> &'static str {
+    match mapper_id {
+        0 => "NROM",
+        1 => "MMC1",
+        2 => "UxROM",
+        3 => "CNROM",
+        4 => "MMC3",
+        19 => "Namco 163",
+        FDS_MAPPER_ID => "FDS",
+        30 => "UNROM 512",
+        _ => "Unknown",
+    }
+}
+
 pub fn parse_stream<R: Read + Seek>(stream: &mut R) -> Result<(NesHeader, Box<dyn Mapper>), LoadError> {
+    let mut magic = [0u8; 4];
+    stream.read_exact(&mut magic)?;
+    stream.seek(SeekFrom::Current(-4))?;
+    if &magic == b"UNIF" {
+        return parse_unif_stream(stream);
+    }
+
     let (header, prg_rom, chr_rom, trainner) = nesrom::parse(stream)?;
+    create_mapper(header, &prg_rom, &chr_rom)
+}
 
+pub fn parse_unif_stream<R: Read + Seek>(stream: &mut R) -> Result<(NesHeader, Box<dyn Mapper>), LoadError> {
+    let (header, prg_rom, chr_rom) = unif::parse(stream)?;
+    create_mapper(header, &prg_rom, &chr_rom)
+}
+
+fn create_mapper(header: NesHeader, prg_rom: &PrgRom, chr_rom: &ChrRom) -> Result<(NesHeader, Box<dyn Mapper>), LoadError> {
     println!("MAPPER ID: {}", header.mapper_id);
     match header.mapper_id {
-        000 => Ok((header, Box::new(mapper_000::State::new(&header, &prg_rom, &chr_rom)))),
-        001 => Ok((header, Box::new(mapper_001::State::new(&header, &prg_rom, &chr_rom)))),
-        002 => Ok((header, Box::new(mapper_002::State::new(&header, &prg_rom, &chr_rom)))),
-        003 => Ok((header, Box::new(mapper_003::State::new(&header, &prg_rom, &chr_rom)))),
-        004 => Ok((header, Box::new(mapper_004::State::new(&header, &prg_rom, &chr_rom)))),
+        000 => Ok((header, Box::new(mapper_000::State::new(&header, prg_rom, chr_rom)))),
+        001 => Ok((header, Box::new(mapper_001::State::new(&header, prg_rom, chr_rom)))),
+        002 => Ok((header, Box::new(mapper_002::State::new(&header, prg_rom, chr_rom)))),
+        003 => Ok((header, Box::new(mapper_003::State::new(&header, prg_rom, chr_rom)))),
+        004 => Ok((header, Box::new(mapper_004::State::new(&header, prg_rom, chr_rom)))),
+        19 => Ok((header, Box::new(mapper_019::State::new(&header, prg_rom, chr_rom)))),
+        030 => Ok((header, Box::new(mapper_030::State::new(&header, prg_rom, chr_rom)))),
         _ => Err(LoadError::UnsupportedMapper(header.mapper_id)),
     }
 }
+
+// .fds images may carry a 16 byte "FDS\x1a" header in front of the 65500 byte sides,
+// the bios is the 8K disksys.rom
+pub fn load_fds_from_bytes(disk_data: &[u8], bios: &[u8]) -> Result<(NesHeader, Box<dyn Mapper>), LoadError> {
+    if bios.len() != mapper_fds::FDS_BIOS_SIZE {
+        return Err(LoadError::FdsBiosNotFound);
+    }
+    let disk_data = if disk_data.starts_with(b"FDS\x1a") {
+        &disk_data[16..]
+    } else {
+        disk_data
+    };
+    let sides: Vec<Vec<u8>> = disk_data
+        .chunks(mapper_fds::FDS_DISK_SIDE_SIZE)
+        .filter(|side| side.len() == mapper_fds::FDS_DISK_SIDE_SIZE)
+        .map(|side| side.to_vec())
+        .collect();
+    if sides.is_empty() {
+        return Err(LoadError::UnexpectedEof);
+    }
+
+    let header = NesHeader {
+        prg_banks: 0,
+        chr_banks: 0,
+        mirroring: MirrorMode::Horizontal,
+        four_screen_mode: false,
+        has_battery: true,
+        nes_version: NesVersion::V1,
+        mapper_id: FDS_MAPPER_ID,
+        submapper: 0,
+        region: None,
+        chr_ram_size: 0x2000,
+    };
+    Ok((header, Box::new(mapper_fds::State::new(sides, bios))))
+}
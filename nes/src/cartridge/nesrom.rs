@@ -1,21 +1,25 @@
 use crate::bitmisc::U8BitTest;
 use crate::error::LoadError;
+use crate::region::Region;
 use serde::{Deserialize, Serialize};
-use std::io::{prelude::*, Read};
+use std::io::{prelude::*, Read, SeekFrom};
 
-#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum NesVersion {
     V1,
     V2,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MirrorMode {
     Horizontal,
     Vertical,
+    OneScreenLow,
+    OneScreenHigh,
+    FourScreen,
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct NesHeader {
     pub prg_banks: usize,
     pub chr_banks: usize,
@@ -24,6 +28,10 @@ pub struct NesHeader {
     pub has_battery: bool,
     pub nes_version: NesVersion,
     pub mapper_id: u16,
+    // NES 2.0 only, 0 everywhere else
+    pub submapper: u8,
+    pub region: Option<Region>,
+    pub chr_ram_size: usize,
 }
 
 pub type PrgRom = Vec<u8>;
@@ -53,14 +61,43 @@ pub fn parse<R: Read + Seek>(
     let four_screen_mode = header[6].is_b3_set();
     let mapper_id_lo = (header[6] >> 4) & 0b1111;
     let mapper_id_hi = (header[7] >> 4) & 0b1111;
-    let mapper_id = ((mapper_id_hi << 4) | (mapper_id_lo)) as u16;
-    let nes_version = if (header[7] >> 2) | 0b11 == 0b10 {
+    let mut mapper_id = ((mapper_id_hi << 4) | (mapper_id_lo)) as u16;
+    let nes_version = if (header[7] >> 2) & 0b11 == 0b10 {
         NesVersion::V2
     } else {
         NesVersion::V1
     };
+
+    let mut submapper = 0;
+    let mut region = None;
+    let mut chr_ram_size = 0x2000;
     if nes_version == NesVersion::V2 {
-        todo!("Nes 2.0 format support")
+        let chr_ram_shift = header[11] & 0b1111;
+        if chr_ram_shift != 0 {
+            chr_ram_size = 64 << chr_ram_shift;
+        }
+        mapper_id |= ((header[8] & 0b1111) as u16) << 8;
+        submapper = header[8] >> 4;
+        // 2 is a multi-region rom, which keeps whatever region is configured
+        region = match header[12] & 0b11 {
+            0 => Some(Region::Ntsc),
+            1 => Some(Region::Pal),
+            3 => Some(Region::Dendy),
+            _ => None,
+        };
+    }
+    else if header[9].is_b0_set() {
+        region = Some(Region::Pal);
+    }
+
+    // reject truncated files before reading any bank
+    let trainner_size = if has_trainner { 0x200 } else { 0 };
+    let expected_size = (trainner_size + prg_banks * 0x4000 + chr_banks * 0x2000) as u64;
+    let position = stream.stream_position()?;
+    let stream_size = stream.seek(SeekFrom::End(0))?;
+    stream.seek(SeekFrom::Start(position))?;
+    if stream_size.saturating_sub(position) < expected_size {
+        return Err(LoadError::UnexpectedEof);
     }
 
     let mut trainner: Vec<u8> = Vec::new();
@@ -97,9 +134,28 @@ pub fn parse<R: Read + Seek>(
         has_battery,
         nes_version,
         mapper_id,
+        submapper,
+        region,
+        chr_ram_size,
         prg_banks,
         chr_banks,
     };
 
     Ok((header, prg_rom, chr_rom, trainner))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_rom::TestRom;
+    use std::io::Cursor;
+
+    #[test]
+    fn missing_prg_banks_are_an_unexpected_eof() {
+        // the header claims 8 PRG banks but only the first one follows it
+        let mut image = TestRom::nrom().build();
+        image[4] = 8;
+        image.truncate(16 + 0x4000);
+        assert!(matches!(parse(&mut Cursor::new(image)), Err(LoadError::UnexpectedEof)));
+    }
+}
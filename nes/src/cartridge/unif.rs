@@ -0,0 +1,176 @@
+use crate::error::LoadError;
+use crate::region::Region;
+use super::nesrom::{NesHeader, NesVersion, MirrorMode, PrgRom, ChrRom};
+use std::io::{prelude::*, Read, SeekFrom};
+
+const HEADER_SIZE: u64 = 32;
+
+// board name prefixes that don't change which mapper is used
+const BOARD_PREFIXES: [&str; 6] = ["NES-", "HVC-", "UNL-", "BTL-", "BMC-", "IREM-"];
+
+// UNIF names the board instead of numbering the mapper
+fn mapper_id_from_board(board: &str) -> Option<u16> {
+    let name = BOARD_PREFIXES
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+    match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => Some(0),
+        "MMC1" | "SAROM" | "SBROM" | "SCROM" | "SEROM" | "SFROM" | "SGROM" | "SHROM" | "SJROM"
+            | "SKROM" | "SLROM" | "SL1ROM" | "SNROM" | "SOROM" | "SUROM" | "SXROM" => Some(1),
+        "UNROM" | "UOROM" => Some(2),
+        "CNROM" => Some(3),
+        "MMC3" | "TBROM" | "TEROM" | "TFROM" | "TGROM" | "TKROM" | "TLROM" | "TNROM" | "TR1ROM"
+            | "TSROM" | "TVROM" => Some(4),
+        "UNROM-512-8" | "UNROM-512-16" | "UNROM-512-32" => Some(30),
+        _ => None,
+    }
+}
+
+// "UNIF", a 4 byte revision and 24 reserved bytes, followed by chunks of
+// 4 byte id, 4 byte little endian length and the data
+pub fn parse<R: Read + Seek>(
+    stream: &mut R,
+) -> Result<(NesHeader, PrgRom, ChrRom), LoadError> {
+    let mut magic = [0u8; 4];
+    stream.read_exact(&mut magic)?;
+    if &magic != b"UNIF" {
+        return Err(LoadError::NotNesRom);
+    }
+    let stream_size = stream.seek(SeekFrom::End(0))?;
+    stream.seek(SeekFrom::Start(HEADER_SIZE))?;
+
+    let mut board = None;
+    let mut mirroring = MirrorMode::Horizontal;
+    let mut four_screen_mode = false;
+    let mut has_battery = false;
+    let mut region = None;
+    // PRG0-PRGF and CHR0-CHRF are concatenated in order
+    let mut prg_chunks: [Vec<u8>; 16] = Default::default();
+    let mut chr_chunks: [Vec<u8>; 16] = Default::default();
+
+    while stream.stream_position()? < stream_size {
+        let mut chunk_header = [0u8; 8];
+        stream.read_exact(&mut chunk_header)?;
+        let length = u32::from_le_bytes([chunk_header[4], chunk_header[5], chunk_header[6], chunk_header[7]]) as u64;
+        if stream_size - stream.stream_position()? < length {
+            return Err(LoadError::UnexpectedEof);
+        }
+        let mut data = vec![0u8; length as usize];
+        stream.read_exact(&mut data)?;
+
+        let hex_digit = (chunk_header[3] as char).to_digit(16);
+        match (&chunk_header[0..3], hex_digit) {
+            (b"PRG", Some(n)) => prg_chunks[n as usize] = data,
+            (b"CHR", Some(n)) => chr_chunks[n as usize] = data,
+            _ => match &chunk_header[0..4] {
+                b"MAPR" => {
+                    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                    board = Some(String::from_utf8_lossy(&data[..end]).into_owned());
+                }
+                b"MIRR" => {
+                    // 5 is mapper controlled, which keeps the default
+                    match data.first() {
+                        Some(0) => mirroring = MirrorMode::Horizontal,
+                        Some(1) => mirroring = MirrorMode::Vertical,
+                        Some(2) => mirroring = MirrorMode::OneScreenLow,
+                        Some(3) => mirroring = MirrorMode::OneScreenHigh,
+                        Some(4) => {
+                            mirroring = MirrorMode::Vertical;
+                            four_screen_mode = true;
+                        }
+                        _ => {}
+                    }
+                }
+                b"BATR" => has_battery = true,
+                b"TVCI" => {
+                    // 2 runs on both, which keeps whatever region is configured
+                    region = match data.first() {
+                        Some(0) => Some(Region::Ntsc),
+                        Some(1) => Some(Region::Pal),
+                        _ => None,
+                    };
+                }
+                _ => {}
+            },
+        }
+    }
+
+    let board = board.ok_or(LoadError::NotNesRom)?;
+    let mapper_id = mapper_id_from_board(&board).ok_or(LoadError::UnifMapperUnknown(board))?;
+    let mut prg_rom: PrgRom = prg_chunks.concat();
+    let chr_rom: ChrRom = chr_chunks.concat();
+    // an 8K PRG chip shows up twice in a 16K bank, anything else has to fill whole banks
+    if prg_rom.len() == 0x2000 {
+        prg_rom = prg_rom.repeat(2);
+    }
+    if prg_rom.is_empty() || prg_rom.len() & 0x3FFF != 0 {
+        return Err(LoadError::UnexpectedEof);
+    }
+
+    let header = NesHeader {
+        prg_banks: prg_rom.len() / 0x4000,
+        chr_banks: chr_rom.len() / 0x2000,
+        mirroring,
+        four_screen_mode,
+        has_battery,
+        nes_version: NesVersion::V1,
+        mapper_id,
+        submapper: 0,
+        region,
+        chr_ram_size: 0x2000,
+    };
+
+    Ok((header, prg_rom, chr_rom))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn unif(board: &str, prg_size: usize) -> Vec<u8> {
+        let mut image = b"UNIF".to_vec();
+        image.resize(HEADER_SIZE as usize, 0);
+        image.extend(chunk(b"MAPR", format!("{}\0", board).as_bytes()));
+        image.extend(chunk(b"PRG0", &vec![0x11; prg_size]));
+        image.extend(chunk(b"CHR0", &vec![0x22; 0x2000]));
+        image.extend(chunk(b"MIRR", &[1]));
+        image.extend(chunk(b"BATR", &[0]));
+        image
+    }
+
+    #[test]
+    fn board_names_pick_the_mapper() {
+        for (board, mapper_id) in [("NES-NROM-256", 0), ("HVC-SNROM", 1), ("UNL-UNROM", 2), ("TLROM", 4), ("UNROM-512-32", 30)] {
+            let (header, _, _) = parse(&mut Cursor::new(unif(board, 0x8000))).unwrap();
+            assert_eq!(header.mapper_id, mapper_id, "{}", board);
+        }
+        assert!(matches!(parse(&mut Cursor::new(unif("NES-FOO", 0x8000))),
+            Err(LoadError::UnifMapperUnknown(board)) if board == "NES-FOO"));
+    }
+
+    #[test]
+    fn chunks_fill_in_the_header() {
+        let (header, prg_rom, chr_rom) = parse(&mut Cursor::new(unif("NES-NROM-256", 0x8000))).unwrap();
+        assert_eq!((header.prg_banks, header.chr_banks), (2, 1));
+        assert_eq!((prg_rom.len(), chr_rom.len()), (0x8000, 0x2000));
+        assert_eq!(header.mirroring, MirrorMode::Vertical);
+        assert!(header.has_battery);
+    }
+
+    #[test]
+    fn an_8k_prg_chip_is_mirrored_into_a_16k_bank() {
+        let (header, prg_rom, _) = parse(&mut Cursor::new(unif("NES-NROM-128", 0x2000))).unwrap();
+        assert_eq!(header.prg_banks, 1);
+        assert_eq!(prg_rom, vec![0x11; 0x4000]);
+        assert!(matches!(parse(&mut Cursor::new(unif("NES-NROM-128", 0x3000))), Err(LoadError::UnexpectedEof)));
+    }
+}
@@ -30,7 +30,7 @@ const INT_NMI_ADDRESS: u16 = 0xFFFA;
 const INT_IRQ_BRK_ADDRESS: u16 = 0xFFFE;
 const INT_RESET_ADDRESS: u16 = 0xFFFC;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 #[allow(non_snake_case)]
 pub struct Registers {
     pub A: u8, pub X: u8, pub Y: u8, pub SP: u8, pub PC: u16, pub P: Flags,
@@ -57,11 +57,18 @@ impl Registers {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct State {
     pub regs: Registers,
+    // latched on the nmi edge, cleared when the nmi vector is taken
     pub nmi: bool,
+    // irq line level, held until the source is acknowledged
     pub irq: bool,
+    // what the last two end of cycle polls saw, an instruction acts on the one before its last cycle
+    polled_nmi: bool,
+    prev_polled_nmi: bool,
+    polled_irq: bool,
+    prev_polled_irq: bool,
 }
 
 impl State {
@@ -70,6 +77,10 @@ impl State {
             regs: Registers::new(),
             nmi: false,
             irq: false,
+            polled_nmi: false,
+            prev_polled_nmi: false,
+            polled_irq: false,
+            prev_polled_irq: false,
         }
     }
 }
@@ -87,17 +98,25 @@ pub trait Interface: Sized + Context {
     }
 
     fn step(&mut self) {
-        if self.state().nmi {
-            self.hardware_interrupt();
-            self.state_mut().nmi = false;
-        } else if self.state().irq && !self.state().regs.P.contains(Flags::I) {
+        if self.state().prev_polled_nmi || self.state().prev_polled_irq {
             self.hardware_interrupt();
-            self.state_mut().irq = false;
         }
         else {
             Private::execute_one_instruction(self);
         }
     }
+
+    // called at the end of every cpu cycle. The I flag is sampled here too, so CLI, SEI and PLP
+    // only take effect after the following instruction
+    fn poll_interrupts(&mut self) {
+        let nmi = self.state().nmi;
+        let irq = self.state().irq && !self.state().regs.P.contains(Flags::I);
+        let state = self.state_mut();
+        state.prev_polled_nmi = state.polled_nmi;
+        state.polled_nmi = nmi;
+        state.prev_polled_irq = state.polled_irq;
+        state.polled_irq = irq;
+    }
 }
 
 impl<T: Context> Interface for T {}
@@ -190,11 +209,6 @@ trait Private: Sized + Context {
 
     #[inline]
     fn hardware_interrupt(&mut self) {
-        let interrupt_addr = if self.state().nmi {
-            INT_NMI_ADDRESS
-        } else {
-            INT_IRQ_BRK_ADDRESS
-        };
         self.dummy_load(self.regs().PC);
         self.dummy_load(self.regs().PC);
         self.push(self.regs().PC.fetch_hi());
@@ -202,9 +216,20 @@ trait Private: Sized + Context {
         self.regs_mut().P.set(Flags::B, false);
         self.push(self.regs().P.bits);
         self.regs_mut().P.set(Flags::I, true);
+        // an nmi that arrives before the vector fetch hijacks an irq
+        let interrupt_addr = self.take_interrupt_vector();
         self.regs_mut().PC = self.load16(interrupt_addr);
     }
 
+    fn take_interrupt_vector(&mut self) -> u16 {
+        if self.state().nmi {
+            self.state_mut().nmi = false;
+            INT_NMI_ADDRESS
+        } else {
+            INT_IRQ_BRK_ADDRESS
+        }
+    }
+
     #[inline]
     fn reset(&mut self) {
         // FIXME
@@ -901,13 +926,9 @@ fn imp_inner<CPU: Private>(cpu: &mut CPU, instruction: Instruction) {
             let pch = (pc >> 8) as u8;
             let pcl = pc as u8;
             cpu.push(pch); cpu.push(pcl);
-            let interrupt_addr = if cpu.state().nmi {
-                INT_NMI_ADDRESS
-            } else {
-                INT_IRQ_BRK_ADDRESS
-            };
             cpu.regs_mut().P.set(Flags::B, true);
             cpu.push(cpu.regs().P.bits);
+            let interrupt_addr = cpu.take_interrupt_vector();
             cpu.regs_mut().P.set(Flags::I, true);
             cpu.regs_mut().PC = cpu.load16(interrupt_addr)
         },
@@ -949,4 +970,200 @@ fn rel_inner<CPU: Private>(cpu: &mut CPU, instruction: Instruction) {
         Operation::Unimplemented => {panic!("Unimplemented instruction: {:?}", instruction)},
         _ => panic!("Invalid instruction `{:?}` for `REL`", instruction),
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM_ADDR: u16 = 0x0200;
+    const NMI_HANDLER: u16 = 0x0300;
+    const IRQ_HANDLER: u16 = 0x0400;
+
+    // 64K of ram filled with NOPs. A line can be raised at the start of a given cycle, before
+    // that cycle's poll, the way the ppu raises nmi in the middle of an instruction
+    struct Bus {
+        ram: Vec<u8>,
+        cpu: State,
+        cycle: usize,
+        nmi_at: Option<usize>,
+        irq_at: Option<usize>,
+    }
+
+    impl Bus {
+        fn new(program: &[u8]) -> Self {
+            let mut ram = vec![0xEA; 0x10000];
+            let start = PROGRAM_ADDR as usize;
+            ram[start..start + program.len()].copy_from_slice(program);
+            ram[0xFFFA..0xFFFC].copy_from_slice(&NMI_HANDLER.to_le_bytes());
+            ram[0xFFFE..0x10000].copy_from_slice(&IRQ_HANDLER.to_le_bytes());
+            let mut cpu = State::new();
+            cpu.regs.PC = PROGRAM_ADDR;
+            cpu.regs.P = Flags::U;
+            Bus { ram, cpu, cycle: 0, nmi_at: None, irq_at: None }
+        }
+
+        fn tick(&mut self) {
+            self.cycle += 1;
+            if self.nmi_at == Some(self.cycle) {
+                self.cpu.nmi = true;
+            }
+            if self.irq_at == Some(self.cycle) {
+                self.cpu.irq = true;
+            }
+        }
+
+        fn pushed_flags(&self) -> Flags {
+            Flags::from_bits_truncate(self.ram[0x0100 + self.cpu.regs.SP as usize + 1])
+        }
+    }
+
+    impl Context for Bus {
+        fn peek(&mut self, addr: u16) -> u8 {
+            self.tick();
+            let value = self.ram[addr as usize];
+            Interface::poll_interrupts(self);
+            value
+        }
+
+        fn poke(&mut self, addr: u16, val: u8) {
+            self.tick();
+            self.ram[addr as usize] = val;
+            Interface::poll_interrupts(self);
+        }
+
+        fn state(&self) -> &State {
+            &self.cpu
+        }
+
+        fn state_mut(&mut self) -> &mut State {
+            &mut self.cpu
+        }
+    }
+
+    fn nop_cycles() -> usize {
+        let mut bus = Bus::new(&[]);
+        Interface::step(&mut bus);
+        bus.cycle
+    }
+
+    #[test]
+    fn interrupt_raised_on_the_last_cycle_waits_one_more_instruction() {
+        let last = nop_cycles();
+        for (line, handler) in [("nmi", NMI_HANDLER), ("irq", IRQ_HANDLER)] {
+            let raise = |cycle| {
+                let mut bus = Bus::new(&[]);
+                match line {
+                    "nmi" => bus.nmi_at = Some(cycle),
+                    _ => bus.irq_at = Some(cycle),
+                }
+                Interface::step(&mut bus);
+                bus
+            };
+
+            // seen by the poll before the last cycle, so it is taken right after the NOP
+            let mut bus = raise(last - 1);
+            Interface::step(&mut bus);
+            assert_eq!(bus.cpu.regs.PC, handler, "{}", line);
+
+            // only seen by the last poll, so the next NOP still runs first
+            let mut bus = raise(last);
+            Interface::step(&mut bus);
+            assert_eq!(bus.cpu.regs.PC, PROGRAM_ADDR + 2, "{}", line);
+            Interface::step(&mut bus);
+            assert_eq!(bus.cpu.regs.PC, handler, "{}", line);
+        }
+    }
+
+    #[test]
+    fn nmi_hijacks_brk_before_the_vector_fetch() {
+        let mut bus = Bus::new(&[0x00, 0x00]);
+        bus.nmi_at = Some(4);
+        Interface::step(&mut bus);
+        assert_eq!(bus.cpu.regs.PC, NMI_HANDLER);
+        assert!(bus.pushed_flags().contains(Flags::B));
+        assert!(!bus.cpu.nmi);
+
+        // too late to change the vector, it is taken after the handler's first instruction
+        let mut bus = Bus::new(&[0x00, 0x00]);
+        bus.nmi_at = Some(7);
+        Interface::step(&mut bus);
+        assert_eq!(bus.cpu.regs.PC, IRQ_HANDLER);
+        Interface::step(&mut bus);
+        Interface::step(&mut bus);
+        assert_eq!(bus.cpu.regs.PC, NMI_HANDLER);
+    }
+
+    #[test]
+    fn nmi_hijacks_an_irq_before_the_vector_fetch() {
+        let last = nop_cycles();
+        for (nmi_at, handler) in [(None, IRQ_HANDLER), (Some(last + 4), NMI_HANDLER)] {
+            let mut bus = Bus::new(&[]);
+            bus.cpu.irq = true;
+            bus.nmi_at = nmi_at;
+            Interface::step(&mut bus);
+            Interface::step(&mut bus);
+            assert_eq!(bus.cpu.regs.PC, handler);
+            assert!(!bus.pushed_flags().contains(Flags::B));
+        }
+    }
+
+    #[test]
+    fn irq_is_level_triggered() {
+        let mut bus = Bus::new(&[]);
+        bus.ram[IRQ_HANDLER as usize] = 0x40;
+        bus.cpu.irq = true;
+        Interface::step(&mut bus);
+        Interface::step(&mut bus);
+        assert_eq!(bus.cpu.regs.PC, IRQ_HANDLER);
+
+        // RTI clears I again and the line is still held, so the irq is taken again
+        Interface::step(&mut bus);
+        assert_eq!(bus.cpu.regs.PC, PROGRAM_ADDR + 1);
+        Interface::step(&mut bus);
+        assert_eq!(bus.cpu.regs.PC, IRQ_HANDLER);
+
+        bus.cpu.irq = false;
+        Interface::step(&mut bus);
+        Interface::step(&mut bus);
+        assert_eq!(bus.cpu.regs.PC, PROGRAM_ADDR + 2);
+    }
+
+    fn run(bus: &mut Bus, instructions: usize) {
+        for _ in 0..instructions {
+            Interface::step(bus);
+        }
+    }
+
+    #[test]
+    fn decimal_flag_survives_the_stack() {
+        // SED, PHP, CLD, PLP
+        let mut bus = Bus::new(&[0xF8, 0x08, 0xD8, 0x28]);
+        run(&mut bus, 4);
+        assert!(bus.cpu.regs.P.contains(Flags::D));
+
+        // SED, BRK into a handler that does CLD, RTI
+        let mut bus = Bus::new(&[0xF8, 0x00, 0x00]);
+        let handler = IRQ_HANDLER as usize;
+        bus.ram[handler..handler + 2].copy_from_slice(&[0xD8, 0x40]);
+        run(&mut bus, 2);
+        assert!(bus.pushed_flags().contains(Flags::D));
+        run(&mut bus, 2);
+        assert_eq!(bus.cpu.regs.PC, PROGRAM_ADDR + 3);
+        assert!(bus.cpu.regs.P.contains(Flags::D));
+    }
+
+    #[test]
+    fn arithmetic_ignores_decimal_mode() {
+        // the 2A03 has no BCD: SED, CLC, LDA #$09, ADC #$01 gives $0A, not $10
+        let mut bus = Bus::new(&[0xF8, 0x18, 0xA9, 0x09, 0x69, 0x01]);
+        run(&mut bus, 4);
+        assert_eq!(bus.cpu.regs.A, 0x0A);
+        assert!(!bus.cpu.regs.P.contains(Flags::C));
+
+        // SED, SEC, LDA #$10, SBC #$01 gives $0F, not $09
+        let mut bus = Bus::new(&[0xF8, 0x38, 0xA9, 0x10, 0xE9, 0x01]);
+        run(&mut bus, 4);
+        assert_eq!(bus.cpu.regs.A, 0x0F);
+        assert!(bus.cpu.regs.P.contains(Flags::C));
+    }
+}
@@ -1,9 +1,10 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct State {
     ppu_dma_request: Option<u16>,
     dmc_dma_request: Option<u16>,
+    // halt and dummy cycles the dmc still has to wait before it can read
     dmc_dma_halt_cycle: u8,
 }
 
@@ -27,12 +28,6 @@ pub trait Context: Sized {
 }
 
 pub trait Interface: Sized + Context {
-    fn on_cpu_tick(&mut self) {
-        if self.state().dmc_dma_request.is_some() && self.state().dmc_dma_halt_cycle > 0 {
-            self.state_mut().dmc_dma_halt_cycle -= 1;
-        }
-    }
-
     fn dma_hijack(&mut self, cpu_peek_addr: u16) {
         Private::dma_hijack(self, cpu_peek_addr);
     }
@@ -42,58 +37,204 @@ pub trait Interface: Sized + Context {
         self.state_mut().ppu_dma_request = Some(addr);
     }
 
+    // the dmc owes a halt and a dummy cycle before its read, any dma cycle pays for them.
+    // The apu keeps asking until the byte arrives, which doesn't start the wait over
     fn activate_dmc_dma(&mut self, addr: u16) {
-        self.state_mut().dmc_dma_request = Some(addr);
+        if self.state().dmc_dma_request.is_none() {
+            self.state_mut().dmc_dma_request = Some(addr);
+            self.state_mut().dmc_dma_halt_cycle = 2;
+        }
     }
 }
 
 impl<T: Context> Interface for T {}
 impl<T: Context> Private for T {}
 
+// the NESdev wiki's get/put model: the cpu is halted on a read cycle, then each cycle is either
+// a get (read) or a put (write) cycle. The dmc reads on a get cycle once its halt and dummy cycles
+// are done, taking that get away from the sprite dma, which then spends the next put realigning.
+// Alone a dmc read costs 3 or 4 cycles and sprite dma 513 or 514, a dmc read in the middle of
+// sprite dma adds 2, near its end 1 or 3
 trait Private: Sized + Context {
     fn dma_hijack(&mut self, cpu_peek_addr: u16) {
-        self.state_mut().dmc_dma_halt_cycle = 2;
-        if self.state().dmc_dma_request.is_some() || self.state().ppu_dma_request.is_some() {
-            self.peek_memory(cpu_peek_addr);
-            let mut ppu_dma_data_cache = None;
-            let mut ppu_dma_data_offset = 0;
-            loop {
-                let dmc_data_transfer_ready = self.state().dmc_dma_halt_cycle == 0;
-                match (
-                    self.is_odd_cpu_cycle(),
-                    self.state().dmc_dma_request,
-                    dmc_data_transfer_ready,
-                    self.state().ppu_dma_request,
-                    ppu_dma_data_cache,
-                ) {
-                    (true, Some(addr), true, _, _) => {
-                        // dmc read
-                        let value = self.peek_memory(addr);
-                        self.on_dmc_dma_transfer(value);
-                        self.state_mut().dmc_dma_request = None;
-                    }
-                    (true, None, _, Some(addr), None) => {
-                        // sprite read
-                        let addr = addr + ppu_dma_data_offset as u16;
-                        ppu_dma_data_cache = Some(self.peek_memory(addr));
-                    }
-                    (false, _, _, Some(_), Some(value)) => {
-                        // sprite write
-                        self.on_ppu_dma_transfer(value, ppu_dma_data_offset);
-                        ppu_dma_data_cache = None;
-                        ppu_dma_data_offset += 1;
-                        if ppu_dma_data_offset >= 256 {
-                            self.state_mut().ppu_dma_request = None;
-                        }
-                    }
-                    (_, None, _, None, _) => {
-                        break;
-                    }
-                    _ => {
-                        self.peek_memory(cpu_peek_addr);
+        if self.state().dmc_dma_request.is_none() && self.state().ppu_dma_request.is_none() {
+            return;
+        }
+        // halt, the cpu's read happens and is thrown away
+        self.pay_dmc_halt_cycle();
+        self.peek_memory(cpu_peek_addr);
+
+        let mut ppu_dma_data_cache = None;
+        let mut ppu_dma_data_offset = 0;
+        while self.state().dmc_dma_request.is_some() || self.state().ppu_dma_request.is_some() {
+            let get_cycle = self.is_odd_cpu_cycle();
+            let dmc_ready = self.state().dmc_dma_halt_cycle == 0;
+            match (get_cycle, self.state().dmc_dma_request, self.state().ppu_dma_request, ppu_dma_data_cache) {
+                (true, Some(addr), _, _) if dmc_ready => {
+                    let value = self.peek_memory(addr);
+                    self.state_mut().dmc_dma_request = None;
+                    self.on_dmc_dma_transfer(value);
+                }
+                (true, _, Some(addr), _) => {
+                    self.pay_dmc_halt_cycle();
+                    let addr = addr + ppu_dma_data_offset as u16;
+                    ppu_dma_data_cache = Some(self.peek_memory(addr));
+                }
+                (false, _, Some(_), Some(value)) => {
+                    self.pay_dmc_halt_cycle();
+                    self.on_ppu_dma_transfer(value, ppu_dma_data_offset);
+                    ppu_dma_data_cache = None;
+                    ppu_dma_data_offset += 1;
+                    if ppu_dma_data_offset >= 256 {
+                        self.state_mut().ppu_dma_request = None;
                     }
                 }
+                _ => {
+                    // dmc halt or dummy cycle, or alignment to the next get cycle
+                    self.pay_dmc_halt_cycle();
+                    self.peek_memory(cpu_peek_addr);
+                }
+            }
+        }
+    }
+
+    fn pay_dmc_halt_cycle(&mut self) {
+        if self.state().dmc_dma_request.is_some() && self.state().dmc_dma_halt_cycle > 0 {
+            self.state_mut().dmc_dma_halt_cycle -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DMC_ADDR: u16 = 0xC000;
+    const CPU_ADDR: u16 = 0x8000;
+
+    // counts cpu cycles, the dmc asks for its byte during cycle `dmc_request_cycle`
+    struct Bus {
+        dma: State,
+        cycle: usize,
+        dmc_request_cycle: Option<usize>,
+        dmc_read_cycle: Option<usize>,
+        oam: Vec<u8>,
+    }
+
+    impl Bus {
+        fn new(cycle: usize) -> Self {
+            Bus { dma: State::new(), cycle, dmc_request_cycle: None, dmc_read_cycle: None, oam: vec![0; 256] }
+        }
+
+        // like the apu, the dmc keeps asking until its byte arrives
+        fn tick(&mut self) {
+            self.cycle += 1;
+            let asking = self.dmc_request_cycle.map_or(false, |cycle| self.cycle >= cycle);
+            if asking && self.dmc_read_cycle.is_none() {
+                Interface::activate_dmc_dma(self, DMC_ADDR);
             }
         }
+
+        // a cpu read as the emulator does it, returns the cycles the dma stole from it
+        fn cpu_read(&mut self) -> usize {
+            let start = self.cycle;
+            Interface::dma_hijack(self, CPU_ADDR);
+            self.peek_memory(CPU_ADDR);
+            self.cycle - start - 1
+        }
+    }
+
+    impl Context for Bus {
+        fn state(&mut self) -> &State {
+            &self.dma
+        }
+
+        fn state_mut(&mut self) -> &mut State {
+            &mut self.dma
+        }
+
+        fn peek_memory(&mut self, addr: u16) -> u8 {
+            self.tick();
+            addr as u8
+        }
+
+        fn is_odd_cpu_cycle(&self) -> bool {
+            self.cycle & 1 == 1
+        }
+
+        fn on_dmc_dma_transfer(&mut self, _value: u8) {
+            self.dmc_read_cycle = Some(self.cycle);
+        }
+
+        fn on_ppu_dma_transfer(&mut self, value: u8, offset: usize) {
+            self.tick();
+            self.oam[offset] = value;
+        }
+    }
+
+    // cycles stolen from a cpu read on cycle `start` + 1, with sprite dma pending and the dmc
+    // asking during cycle `start` + `dmc_request`
+    fn run(start: usize, sprite_dma: bool, dmc_request: Option<usize>) -> (usize, Bus) {
+        let mut bus = Bus::new(start);
+        if sprite_dma {
+            Interface::activate_ppu_dma(&mut bus, 0x02);
+        }
+        bus.dmc_request_cycle = dmc_request.map(|offset| start + offset);
+        let stolen = bus.cpu_read();
+        assert_eq!(bus.dmc_read_cycle.is_some(), dmc_request.is_some());
+        if sprite_dma {
+            assert!(bus.oam.iter().enumerate().all(|(i, &value)| value == i as u8));
+        }
+        (stolen, bus)
+    }
+
+    #[test]
+    fn sprite_dma_alone_takes_513_or_514_cycles() {
+        assert_eq!(run(0, true, None).0, 513);
+        assert_eq!(run(1, true, None).0, 514);
+    }
+
+    #[test]
+    fn dmc_dma_alone_takes_3_or_4_cycles() {
+        for (start, expected) in [(0, 4), (1, 3)] {
+            let mut bus = Bus::new(start);
+            Interface::activate_dmc_dma(&mut bus, DMC_ADDR);
+            assert_eq!(bus.cpu_read(), expected);
+            // the read is the last stolen cycle, on a get cycle
+            assert_eq!(bus.dmc_read_cycle, Some(start + expected));
+            assert!(bus.dmc_read_cycle.unwrap() & 1 == 0);
+        }
+    }
+
+    #[test]
+    fn dmc_dma_during_the_sprite_copy_adds_2_cycles() {
+        for offset in 3..=509 {
+            assert_eq!(run(0, true, Some(offset)).0, 513 + 2, "dmc at +{}", offset);
+            assert_eq!(run(1, true, Some(offset)).0, 514 + 2, "dmc at +{}", offset);
+        }
+    }
+
+    #[test]
+    fn dmc_dma_during_the_halt_and_alignment_adds_2_cycles() {
+        // asked for during the halt cycle, or on the alignment cycle when there is one
+        assert_eq!(run(0, true, Some(1)).0, 513 + 2);
+        assert_eq!(run(1, true, Some(1)).0, 514 + 2);
+        assert_eq!(run(1, true, Some(2)).0, 514 + 2);
+        // already waiting when the sprite dma starts
+        let mut bus = Bus::new(0);
+        Interface::activate_ppu_dma(&mut bus, 0x02);
+        Interface::activate_dmc_dma(&mut bus, DMC_ADDR);
+        assert_eq!(bus.cpu_read(), 513 + 2);
+    }
+
+    #[test]
+    fn dmc_dma_at_the_end_of_sprite_dma_adds_1_or_3_cycles() {
+        // the last sprite reads hide only one of the dmc's halt and dummy cycles, or none
+        assert_eq!(run(0, true, Some(510)).0, 513 + 1);
+        assert_eq!(run(0, true, Some(511)).0, 513 + 1);
+        assert_eq!(run(0, true, Some(512)).0, 513 + 3);
+        assert_eq!(run(0, true, Some(513)).0, 513 + 3);
+        assert_eq!(run(1, true, Some(511)).0, 514 + 1);
+        assert_eq!(run(1, true, Some(514)).0, 514 + 3);
     }
 }
@@ -1,8 +1,9 @@
-use crate::{bitmisc::U8BitTest, error::LoadError};
+use crate::{bitmisc::U8BitTest, error::LoadError, error::PaletteError, error::FramebufferError, error::RunError};
 use crate::cpu;
 use crate::ppu;
 use crate::apu;
 use crate::dma;
+use crate::region::Region;
 
 use crate::cartridge;
 
@@ -23,7 +24,7 @@ enum AccessMode {
     Write(u8),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum DmaState {
     NoDma,
     OmaDma(u8),
@@ -43,7 +44,131 @@ bitflags! {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+impl StandardInput {
+    // a real d-pad can't press both of left+right or up+down, so either pair is dropped as a whole
+    fn without_opposite_directions(self) -> Self {
+        let mut input = self;
+        for pair in [StandardInput::LEFT | StandardInput::RIGHT, StandardInput::UP | StandardInput::DOWN] {
+            if input.contains(pair) {
+                input.remove(pair);
+            }
+        }
+        input
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FourScoreType {
+    FourScore,
+    Hori,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputMode {
+    OnePlayer,
+    TwoPlayer,
+    FourScore(FourScoreType),
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct FourScoreState {
+    pub p3: StandardInput,
+    pub p4: StandardInput,
+}
+
+// controller ports 1/2, and the Four Score's 3/4
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Player {
+    One,
+    Two,
+    Three,
+    Four,
+}
+
+// rates are in frames per half cycle, 0 disables turbo for that button
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct TurboConfig {
+    pub a_turbo_rate: u8,
+    pub b_turbo_rate: u8,
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ZapperState {
+    pub x: u8,
+    pub y: u8,
+    pub trigger: bool,
+}
+
+// blargg's test roms report through cartridge ram: a status byte at $6000,
+// the signature DE B0 61 at $6001 and a null-terminated message from $6004
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TestStatus {
+    NoRom,
+    Running,
+    Passed(String),
+    Failed(u8, String),
+}
+
+const TEST_STATUS_ADDR: u16 = 0x6000;
+const TEST_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEST_MESSAGE_ADDR: u16 = 0x6004;
+// status 0x81 asks for the reset button to be pressed after at least 100ms
+const TEST_RESET_DELAY_FRAMES: usize = 6;
+
+#[cfg(feature = "flate2")]
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+// 64-bit FNV-1a, unlike std's hasher it gives the same value on every build, so hashes can be committed
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+fn fnv1a_hash<I: IntoIterator<Item = u8>>(bytes: I) -> u64 {
+    bytes.into_iter().fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+// which interrupt sources currently assert the cpu's irq line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IrqSources {
+    pub apu_frame: bool,
+    pub dmc: bool,
+    pub mapper: bool,
+}
+
+pub const FRAME_WIDTH: usize = 256;
+pub const FRAME_HEIGHT: usize = 240;
+
+// all four nametables side by side, as drawn by debug_render_nametables
+pub const NAMETABLE_VIEW_WIDTH: usize = 512;
+pub const NAMETABLE_VIEW_HEIGHT: usize = 480;
+
+// both pattern tables side by side, 16x16 tiles each, as drawn by debug_render_pattern_tables
+pub const PATTERN_TABLE_VIEW_WIDTH: usize = 256;
+pub const PATTERN_TABLE_VIEW_HEIGHT: usize = 128;
+
+// a screen sized window in the nametable view, wrapping around at the right and bottom edges
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScrollWindow {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// current is the ppu's v register, temporary is t (what the next frame starts from)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NametableScroll {
+    pub current: ScrollWindow,
+    pub temporary: ScrollWindow,
+}
+
+// $4016/$4017 only drive d0-d4, the rest is usually $40 left over from the address operand
+const CONTROLLER_OPEN_BUS_MASK: u8 = 0b1110_0000;
+
+// how many scanlines the photodiode keeps sensing light after the beam passed
+const ZAPPER_SENSE_SCANLINES: usize = 20;
+const ZAPPER_SENSE_RADIUS: usize = 2;
+
+#[derive(Clone, Serialize, Deserialize)]
 struct NesState {
     dma: dma::State,
     apu: apu::State,
@@ -57,6 +182,18 @@ struct NesState {
     input_1_mask: StandardInput,
     input_2_mask: StandardInput,
     input_strobe: bool,
+    input_mode: InputMode,
+    four_score: FourScoreState,
+    zapper: Option<ZapperState>,
+    turbo_config: [TurboConfig; 4],
+    turbo_counter_a: [u8; 4],
+    turbo_counter_b: [u8; 4],
+    // last irq level reported by the mapper
+    mapper_irq: bool,
+    // frame counter or dmc irq pending in the apu
+    apu_irq: bool,
+    // last value on the cpu data bus, undriven bits read back as this
+    open_bus: u8,
     sample_buffer: Vec<f32>,
 }
 
@@ -75,32 +212,269 @@ impl NesState {
             input_1_mask: StandardInput::empty(),
             input_2_mask: StandardInput::empty(),
             input_strobe: false,
+            input_mode: InputMode::TwoPlayer,
+            four_score: FourScoreState { p3: StandardInput::empty(), p4: StandardInput::empty() },
+            zapper: None,
+            turbo_config: [TurboConfig::default(); 4],
+            turbo_counter_a: [0; 4],
+            turbo_counter_b: [0; 4],
+            mapper_irq: false,
+            apu_irq: false,
+            open_bus: 0,
             sample_buffer: Vec::new(),
         }
     }
 }
 
+// pixels hidden on each edge of the frame, applied when the framebuffer is copied out
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+// mixes each frame with the one before when it's copied out, to soften games that flicker
+// sprites every other frame. The framebuffer itself is left alone
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FrameBlend {
+    #[default]
+    Off,
+    // half of each frame
+    Average,
+    // the brighter of the current frame and the faded previous output, per channel
+    Phosphor,
+}
+
+// how much of the previous output is left after a frame in Phosphor mode
+const PHOSPHOR_DECAY: f32 = 0.75;
+
+// what cpu ram holds after loading a rom or a power cycle. Random fills it from a
+// xorshift32 generator (a seed of 0 counts as 1), so the same seed gives the same ram
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RamPattern {
+    #[default]
+    AllZero,
+    AllOnes,
+    Random(u32),
+}
+
+impl RamPattern {
+    fn fill(self, ram: &mut [u8]) {
+        match self {
+            RamPattern::AllZero => ram.fill(0x00),
+            RamPattern::AllOnes => ram.fill(0xFF),
+            RamPattern::Random(seed) => {
+                let mut x = seed.max(1);
+                for byte in ram.iter_mut() {
+                    x ^= x << 13;
+                    x ^= x >> 17;
+                    x ^= x << 5;
+                    *byte = x as u8;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct EmulatorConfig {
+    // for roms whose header gives no region
+    pub region: Region,
+    // samples are interleaved left/right when stereo is enabled
+    pub stereo: bool,
+    pub channel_pan: [f32; 4],
+    pub indexed_output: bool,
+    pub overscan: Overscan,
+    pub power_on_ram: RamPattern,
+    pub dpad_filtering: bool,
+    // the 2C02's OAM corruption from OAMADDR at the start of rendering and from $2003 writes
+    // while rendering
+    pub oam_corruption: bool,
+    pub frame_blend: FrameBlend,
+}
+
+impl Default for EmulatorConfig {
+    fn default() -> Self {
+        EmulatorConfig {
+            region: Region::default(),
+            stereo: false,
+            channel_pan: [0.0; 4],
+            indexed_output: false,
+            overscan: Overscan::default(),
+            power_on_ram: RamPattern::default(),
+            dpad_filtering: false,
+            oam_corruption: true,
+            frame_blend: FrameBlend::Off,
+        }
+    }
+}
+
 pub struct Emulator {
     mapper: Option<Box<dyn cartridge::Mapper>>,
+    header: Option<cartridge::NesHeader>,
     nes: NesState,
+    config: EmulatorConfig,
+    // kept out of NesState so save states don't carry it
+    palette: ppu::Palette,
+    paused: bool,
+    // what the copy functions blend the framebuffer with, empty while frame blending is off
+    blend_frame: Vec<ppu::RgbColor>,
+    scanline_callback: Option<Box<dyn FnMut(usize)>>,
+    sample_callback: Option<Box<dyn FnMut(f32)>>,
+}
+
+// the callbacks belong to the frontend and are not carried over to the copy
+impl Clone for Emulator {
+    fn clone(&self) -> Self {
+        Emulator {
+            mapper: self.mapper.as_ref().map(|mapper| mapper.clone_box()),
+            header: self.header,
+            nes: self.nes.clone(),
+            config: self.config,
+            palette: self.palette.clone(),
+            paused: self.paused,
+            blend_frame: self.blend_frame.clone(),
+            scanline_callback: None,
+            sample_callback: None,
+        }
+    }
 }
 
 impl Emulator {
     pub fn new() -> Self {
+        Emulator::with_config(EmulatorConfig::default())
+    }
+
+    pub fn with_config(config: EmulatorConfig) -> Self {
         Emulator {
             mapper: None,
+            header: None,
             nes: NesState::new(),
+            config,
+            palette: ppu::Palette::default_palette(),
+            paused: false,
+            blend_frame: Vec::new(),
+            scanline_callback: None,
+            sample_callback: None,
         }
     }
 
+    pub fn get_config(&self) -> EmulatorConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: EmulatorConfig) {
+        self.config = config;
+    }
+
+    // what the loaded cartridge runs at: its header's region, or the configured one when the
+    // header doesn't say
+    pub fn region(&self) -> Region {
+        self.header.and_then(|header| header.region).unwrap_or(self.config.region)
+    }
+
+    pub fn load_rom<R: Read + Seek>(&mut self, reader: &mut R) -> Result<(), LoadError> {
+        let (header, mapper) = cartridge::parse_stream(reader)?;
+        self.insert_cartridge(header, mapper);
+        Ok(())
+    }
+
+    // bios is the 8K FDS BIOS (disksys.rom)
+    pub fn load_fds_from_bytes(&mut self, disk_data: &[u8], bios: &[u8]) -> Result<(), LoadError> {
+        let (header, mapper) = cartridge::load_fds_from_bytes(disk_data, bios)?;
+        self.insert_cartridge(header, mapper);
+        Ok(())
+    }
+
+    fn insert_cartridge(&mut self, header: cartridge::NesHeader, mapper: Box<dyn cartridge::Mapper>) {
+        self.nes = NesState::new();
+        self.config.power_on_ram.fill(&mut self.nes.ram);
+        self.mapper = Some(mapper);
+        self.header = Some(header);
+    }
+
     pub fn load_rom_from_file(&mut self, path: &Path) -> Result<(), LoadError>  {
         let mut file = File::open(path).unwrap();
-        self.load_from_stream(&mut file)
+        self.load_rom(&mut file)
     }
 
     pub fn load_rom_from_bytes(&mut self, data: &[u8]) -> Result<(), LoadError>  {
+        #[cfg(feature = "flate2")]
+        if data.starts_with(&GZIP_MAGIC) {
+            let mut rom = Vec::new();
+            flate2::read::GzDecoder::new(data).read_to_end(&mut rom)?;
+            return self.load_rom(&mut Cursor::new(rom));
+        }
         let mut stream = Cursor::new(data);
-        self.load_from_stream(&mut stream)
+        self.load_rom(&mut stream)
+    }
+
+    // loads the named entry, or the only .nes entry of the archive when none is named
+    #[cfg(feature = "zip")]
+    pub fn load_rom_from_zip(&mut self, path: &Path, entry: Option<&str>) -> Result<(), LoadError> {
+        self.load_rom_from_zip_reader(File::open(path)?, entry)
+    }
+
+    #[cfg(feature = "zip")]
+    pub fn load_rom_from_zip_reader<R: Read + Seek>(&mut self, reader: R, entry: Option<&str>) -> Result<(), LoadError> {
+        let mut archive = zip::ZipArchive::new(reader)?;
+        let name = match entry {
+            Some(name) => name.to_string(),
+            None => {
+                let mut names: Vec<String> = archive.file_names()
+                    .filter(|name| name.to_ascii_lowercase().ends_with(".nes"))
+                    .map(String::from)
+                    .collect();
+                names.sort();
+                match names.len() {
+                    0 => return Err(LoadError::NotNesRom),
+                    1 => names.remove(0),
+                    _ => return Err(LoadError::AmbiguousZipEntry(names)),
+                }
+            }
+        };
+
+        let mut data = Vec::new();
+        archive.by_name(&name)?.read_to_end(&mut data)?;
+        self.load_rom(&mut Cursor::new(data))
+    }
+
+    pub fn is_rom_loaded(&self) -> bool {
+        self.mapper.is_some()
+    }
+
+    pub fn get_header(&self) -> Option<&cartridge::NesHeader> {
+        self.header.as_ref()
+    }
+
+    // NES 2.0 mapper numbers go up to 4095, so this is wider than a byte
+    pub fn get_mapper_id(&self) -> Option<u16> {
+        self.header.map(|header| header.mapper_id)
+    }
+
+    pub fn get_mapper_name(&self) -> Option<&'static str> {
+        self.get_mapper_id().map(cartridge::mapper_name)
+    }
+
+    // for title bars and logs, 0 and "None" until a rom is loaded
+    pub fn mapper_id(&self) -> u16 {
+        self.get_mapper_id().unwrap_or(0)
+    }
+
+    pub fn mapper_name(&self) -> &'static str {
+        self.get_mapper_name().unwrap_or("None")
+    }
+
+    // only the battery backed ram, unlike save_state which also carries the rom. Write to
+    // get_sram_mut to restore a .sav after loading the rom
+    pub fn get_sram(&self) -> Option<&[u8]> {
+        self.mapper.as_ref().and_then(|mapper| mapper.sram())
+    }
+
+    pub fn get_sram_mut(&mut self) -> Option<&mut [u8]> {
+        self.mapper.as_mut().and_then(|mapper| mapper.sram_mut())
     }
 
     pub fn load_state(&mut self, state: &Vec<u8>) {
@@ -115,7 +489,47 @@ impl Emulator {
         bincode::serialize(&(serialized_nes, serialized_mapper)).unwrap()
     }
 
+    // save_state's blob gzipped, the ram, ppu and apu state compress well
+    #[cfg(feature = "flate2")]
+    pub fn save_state_compressed(&mut self) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&self.save_state()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[cfg(feature = "flate2")]
+    pub fn load_state_compressed(&mut self, state: &[u8]) -> std::io::Result<()> {
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(state).read_to_end(&mut decompressed)?;
+        self.load_state(&decompressed);
+        Ok(())
+    }
+
+    // for regression tests: run a rom for some frames and compare against a known value
+    pub fn framebuffer_hash(&self) -> u64 {
+        fnv1a_hash(self.get_framebuffer().iter().flat_map(|color| [color.r, color.g, color.b]))
+    }
+
+    // console state only, the mapper's state isn't included
+    pub fn state_hash(&self) -> u64 {
+        fnv1a_hash(bincode::serialize(&self.nes).unwrap())
+    }
+
+    // while paused run_for_one_frame does nothing and the last frame stays in the framebuffer
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
     pub fn run_for_one_frame(&mut self) {
+        if self.paused {
+            return;
+        }
+        self.update_blend_frame();
+        self.apply_turbo();
         while !self.nes.frame_generated {
             cpu::Interface::step(self);
         }
@@ -123,10 +537,44 @@ impl Emulator {
         self.clear_input_mask();
     }
 
+    // the NES reset button, ram is kept and the ppu goes through its warm-up again
     pub fn reset(&mut self) {
+        ppu::Interface::reset(self);
+        apu::Interface::reset(self);
         cpu::Interface::reset(self);
     }
 
+    // the Famicom reset button, which doesn't reach the ppu. A reset right after power on
+    // still leaves the ppu warming up
+    pub fn soft_reset(&mut self) {
+        if self.get_cycle() > 0 {
+            ppu::Interface::end_warmup(self);
+        }
+        apu::Interface::reset(self);
+        cpu::Interface::reset(self);
+    }
+
+    // cold boot: everything but the cartridge, the input setup and the ppu model starts over
+    pub fn power_cycle(&mut self) {
+        let mut nes = NesState::new();
+        nes.input_mode = self.nes.input_mode;
+        nes.zapper = self.nes.zapper;
+        nes.turbo_config = self.nes.turbo_config;
+        self.config.power_on_ram.fill(&mut nes.ram);
+        let ppu_model = ppu::Interface::get_model(self);
+        self.nes = nes;
+        ppu::Interface::set_model(self, ppu_model);
+        if let Some(mapper) = self.mapper.as_mut() {
+            mapper.reset_mapper_to_defaults();
+            self.reset();
+        }
+    }
+
+    #[deprecated(note = "renamed to power_cycle")]
+    pub fn reset_to_power_on(&mut self) {
+        self.power_cycle();
+    }
+
     pub fn get_cycle(&self) -> usize {
         self.nes.cpu_cycle.0
     }
@@ -135,19 +583,391 @@ impl Emulator {
         ppu::Interface::get_framebuffer(self)
     }
 
+    // only filled while indexed output is enabled. u16 because each entry is the 6 bit palette
+    // index plus the 3 emphasis bits of $2001 above it, `index | emphasis << 6`, the same indexing
+    // as a 512 color .pal file
+    pub fn get_framebuffer_indexed(&self) -> &Vec<u16> {
+        ppu::Interface::get_framebuffer_indexed(self)
+    }
+
+    pub fn set_indexed_output(&mut self, enable: bool) {
+        self.config.indexed_output = enable;
+    }
+
+    pub fn set_overscan(&mut self, overscan: Overscan) {
+        self.config.overscan = overscan;
+    }
+
+    // takes effect on the next rom load or power cycle
+    pub fn set_power_on_ram_pattern(&mut self, pattern: RamPattern) {
+        self.config.power_on_ram = pattern;
+    }
+
+    // off by default, keyboards can hold opposite directions and some games break on it
+    pub fn set_dpad_filtering(&mut self, enable: bool) {
+        self.config.dpad_filtering = enable;
+    }
+
+    pub fn set_oam_corruption(&mut self, enable: bool) {
+        self.config.oam_corruption = enable;
+    }
+
+    pub fn set_frame_blend(&mut self, blend: FrameBlend) {
+        self.config.frame_blend = blend;
+        self.blend_frame.clear();
+    }
+
+    // called before a frame is drawn over the finished one. Average blends with the previous
+    // frame as it was drawn, Phosphor with what was shown, so its trails fade out over frames.
+    // The buffer is kept between frames and written over in place
+    fn update_blend_frame(&mut self) {
+        match self.config.frame_blend {
+            FrameBlend::Off => self.blend_frame.clear(),
+            // with nothing to blend with yet both show the framebuffer as it is
+            _ if self.blend_frame.is_empty() => {
+                let mut blend_frame = std::mem::take(&mut self.blend_frame);
+                blend_frame.extend_from_slice(self.get_framebuffer());
+                self.blend_frame = blend_frame;
+            }
+            FrameBlend::Average => {
+                let mut blend_frame = std::mem::take(&mut self.blend_frame);
+                blend_frame.copy_from_slice(self.get_framebuffer());
+                self.blend_frame = blend_frame;
+            }
+            // each pixel only mixes with its own previous value, so it can be updated in place
+            FrameBlend::Phosphor => {
+                for i in 0..self.blend_frame.len() {
+                    self.blend_frame[i] = self.output_pixel(i);
+                }
+            }
+        }
+    }
+
+    // the framebuffer pixel at `index` after frame blending
+    fn output_pixel(&self, index: usize) -> ppu::RgbColor {
+        let current = self.get_framebuffer()[index];
+        let previous = match self.blend_frame.get(index) {
+            Some(&previous) => previous,
+            None => return current,
+        };
+        let mix = |previous: u8, current: u8| match self.config.frame_blend {
+            FrameBlend::Off => current,
+            FrameBlend::Average => ((previous as u16 + current as u16) / 2) as u8,
+            FrameBlend::Phosphor => ((previous as f32 * PHOSPHOR_DECAY) as u8).max(current),
+        };
+        ppu::RgbColor {
+            r: mix(previous.r, current.r),
+            g: mix(previous.g, current.g),
+            b: mix(previous.b, current.b),
+        }
+    }
+
+    pub fn visible_width(&self) -> usize {
+        let overscan = self.config.overscan;
+        FRAME_WIDTH.saturating_sub(overscan.left + overscan.right)
+    }
+
+    pub fn visible_height(&self) -> usize {
+        let overscan = self.config.overscan;
+        FRAME_HEIGHT.saturating_sub(overscan.top + overscan.bottom)
+    }
+
+    // the framebuffer with the overscan cropped off and frame blending applied, row by row
+    fn visible_pixels(&self) -> impl Iterator<Item = ppu::RgbColor> + '_ {
+        let overscan = self.config.overscan;
+        let (width, height) = (self.visible_width(), self.visible_height());
+        (overscan.top..overscan.top + height).flat_map(move |y| {
+            (overscan.left..overscan.left + width).map(move |x| self.output_pixel(y * FRAME_WIDTH + x))
+        })
+    }
+
+    /// Packs the visible framebuffer into 16-bit RGB565 pixels: red in bits 15-11,
+    /// green in bits 10-5 and blue in bits 4-0. `out` needs `visible_width() * visible_height()` pixels.
+    pub fn copy_framebuffer_rgb565(&self, out: &mut [u16]) -> Result<(), FramebufferError> {
+        let size = self.visible_width() * self.visible_height();
+        if out.len() < size {
+            return Err(FramebufferError::BufferTooSmall(size));
+        }
+        for (pixel, rgb) in out.iter_mut().zip(self.visible_pixels()) {
+            *pixel = ((rgb.r as u16 >> 3) << 11) | ((rgb.g as u16 >> 2) << 5) | (rgb.b as u16 >> 3);
+        }
+        Ok(())
+    }
+
+    /// Writes the visible framebuffer as 4 bytes per pixel in R, G, B, A order,
+    /// with alpha always 0xFF. `out` needs `visible_width() * visible_height() * 4` bytes.
+    pub fn copy_framebuffer_rgba8888(&self, out: &mut [u8]) -> Result<(), FramebufferError> {
+        let size = self.visible_width() * self.visible_height() * 4;
+        if out.len() < size {
+            return Err(FramebufferError::BufferTooSmall(size));
+        }
+        for (pixel, rgb) in out.chunks_exact_mut(4).zip(self.visible_pixels()) {
+            pixel.copy_from_slice(&[rgb.r, rgb.g, rgb.b, 0xFF]);
+        }
+        Ok(())
+    }
+
+    /// Writes the visible framebuffer as tightly packed RGB24, `visible_width() * visible_height() * 3`
+    /// bytes, which is 256 * 240 * 3 without overscan.
+    pub fn copy_framebuffer_into(&self, dst: &mut [u8]) -> Result<(), FramebufferError> {
+        let size = self.visible_width() * self.visible_height() * 3;
+        if dst.len() < size {
+            return Err(FramebufferError::BufferTooSmall(size));
+        }
+        for (pixel, rgb) in dst.chunks_exact_mut(3).zip(self.visible_pixels()) {
+            pixel.copy_from_slice(&[rgb.r, rgb.g, rgb.b]);
+        }
+        Ok(())
+    }
+
+    // takes a 64 color (192 bytes) or 512 color (1536 bytes) .pal file
+    pub fn set_palette_from_pal(&mut self, data: &[u8]) -> Result<(), PaletteError> {
+        self.palette = ppu::Palette::new(data)?;
+        Ok(())
+    }
+
+    pub fn reset_palette(&mut self) {
+        self.palette = ppu::Palette::default_palette();
+    }
+
+    // RGB ppus ignore the palette set above. Loading a rom goes back to the 2C02
+    pub fn set_ppu_model(&mut self, model: ppu::PpuModel) {
+        ppu::Interface::set_model(self, model);
+    }
+
+    pub fn get_ppu_model(&self) -> ppu::PpuModel {
+        ppu::Interface::get_model(self)
+    }
+
+    // peeks the ppu address space for tools, without ticking or touching mapper irq state.
+    // Palette ram lives in the ppu and always reads, the rest is None without a cartridge
+    pub fn read_vram(&mut self, addr: u16) -> Option<u8> {
+        let addr = addr & 0x3FFF;
+        if addr >= 0x3F00 {
+            return Some(self.nes.ppu.palette_ram[ppu::palette_ram_index(addr)]);
+        }
+        let addr = if addr > 0x2FFF { addr & 0x2FFF } else { addr };
+        self.mapper.as_mut().map(|mapper| mapper.vpeek(addr))
+    }
+
+    // reads cartridge ram directly, before the rom wrote the signature the test counts as running
+    pub fn test_status(&mut self) -> TestStatus {
+        let mapper = match self.mapper.as_mut() {
+            Some(mapper) => mapper,
+            None => return TestStatus::NoRom,
+        };
+        let signature = [mapper.peek(TEST_STATUS_ADDR + 1), mapper.peek(TEST_STATUS_ADDR + 2), mapper.peek(TEST_STATUS_ADDR + 3)];
+        if signature != TEST_SIGNATURE {
+            return TestStatus::Running;
+        }
+
+        let mut message = String::new();
+        for addr in TEST_MESSAGE_ADDR..=0x7FFF {
+            match mapper.peek(addr) {
+                0 => break,
+                c => message.push(c as char),
+            }
+        }
+
+        match mapper.peek(TEST_STATUS_ADDR) {
+            0x80 | 0x81 => TestStatus::Running,
+            0x00 => TestStatus::Passed(message),
+            code => TestStatus::Failed(code, message),
+        }
+    }
+
+    pub fn run_until_test_done(&mut self, max_frames: usize) -> TestStatus {
+        if !self.is_rom_loaded() {
+            return TestStatus::NoRom;
+        }
+        let mut reset_delay = 0;
+        for _ in 0..max_frames {
+            self.run_for_one_frame();
+            match self.test_status() {
+                TestStatus::Running => {}
+                status => return status,
+            }
+            if self.mapper.as_mut().unwrap().peek(TEST_STATUS_ADDR) == 0x81 {
+                reset_delay += 1;
+                if reset_delay == TEST_RESET_DELAY_FRAMES {
+                    reset_delay = 0;
+                    self.reset();
+                }
+            }
+        }
+        self.test_status()
+    }
+
+    // runs whole instructions until `addr` reads `value` or `max_cycles` cpu cycles have passed,
+    // returns whether the value was seen. Only ram and cartridge space ($4020-$FFFF) are
+    // checked, registers are never read. Note blargg roms start with $6000 = 0, run_until_test_done
+    // checks their signature as well.
+    pub fn run_until_memory_equals(&mut self, addr: u16, value: u8, max_cycles: usize) -> Result<bool, RunError> {
+        if !self.is_rom_loaded() {
+            return Err(RunError::NoRom);
+        }
+        let start = self.get_cycle();
+        while self.get_cycle().wrapping_sub(start) < max_cycles {
+            cpu::Interface::step(self);
+            if self.nes.frame_generated {
+                self.nes.frame_generated = false;
+                self.clear_input_mask();
+            }
+            let current = match addr {
+                0x0000..=0x1FFF => self.nes.ram[(addr & 0x7FF) as usize],
+                0x4020..=0xFFFF => self.mapper.as_mut().unwrap().peek(addr),
+                _ => continue,
+            };
+            if current == value {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // draws the four nametables into `out` (NAMETABLE_VIEW_WIDTH * NAMETABLE_VIEW_HEIGHT pixels)
+    // with the current background pattern table and palette, ignoring scroll. Takes &mut self only
+    // because mapper vpeek does, nothing is ticked.
+    pub fn debug_render_nametables(&mut self, out: &mut [ppu::RgbColor]) -> NametableScroll {
+        let pattern_table = ppu::Interface::get_bg_pattern_table_addr(self);
+        for nametable in 0..4usize {
+            let base = 0x2000 + nametable as u16 * 0x400;
+            let (origin_x, origin_y) = ((nametable & 1) * FRAME_WIDTH, (nametable >> 1) * FRAME_HEIGHT);
+            for tile_y in 0..30u16 {
+                for tile_x in 0..32u16 {
+                    let tile = self.read_vram(base + tile_y * 32 + tile_x).unwrap_or(0) as u16;
+                    let attribute = self.read_vram(base + 0x3C0 + (tile_y / 4) * 8 + tile_x / 4).unwrap_or(0);
+                    let shift = ((tile_y & 2) << 1) | (tile_x & 2);
+                    let palette = ((attribute >> shift) & 0b11) as usize;
+                    for row in 0..8u16 {
+                        let lo = self.read_vram(pattern_table + tile * 16 + row).unwrap_or(0);
+                        let hi = self.read_vram(pattern_table + tile * 16 + row + 8).unwrap_or(0);
+                        for col in 0..8usize {
+                            let pixel = ((lo >> (7 - col)) & 1) | (((hi >> (7 - col)) & 1) << 1);
+                            let palette_index = if pixel == 0 {
+                                self.nes.ppu.palette_ram[0]
+                            } else {
+                                self.nes.ppu.palette_ram[palette * 4 + pixel as usize]
+                            };
+                            let x = origin_x + tile_x as usize * 8 + col;
+                            let y = origin_y + tile_y as usize * 8 + row as usize;
+                            out[y * NAMETABLE_VIEW_WIDTH + x] = self.palette.get_rgb((palette_index & 0x3F) as usize);
+                        }
+                    }
+                }
+            }
+        }
+
+        let (current, temporary) = ppu::Interface::get_scroll_positions(self);
+        let window = |(x, y): (usize, usize)| ScrollWindow { x, y, width: FRAME_WIDTH, height: FRAME_HEIGHT };
+        NametableScroll {
+            current: window(current),
+            temporary: window(temporary),
+        }
+    }
+
+    // draws $0000-$1FFF as currently banked in with palette row `palette_group` (0-3 background,
+    // 4-7 sprites) into `out` (PATTERN_TABLE_VIEW_WIDTH * PATTERN_TABLE_VIEW_HEIGHT pixels)
+    pub fn debug_render_pattern_tables(&mut self, palette_group: u8, out: &mut [ppu::RgbColor]) {
+        let palette_base = (palette_group as usize & 0b111) * 4;
+        for tile in 0..512u16 {
+            let (table, index) = (tile / 256, tile % 256);
+            let origin_x = table as usize * 128 + (index % 16) as usize * 8;
+            let origin_y = (index / 16) as usize * 8;
+            for row in 0..8u16 {
+                let lo = self.read_vram(tile * 16 + row).unwrap_or(0);
+                let hi = self.read_vram(tile * 16 + row + 8).unwrap_or(0);
+                for col in 0..8usize {
+                    let pixel = (((lo >> (7 - col)) & 1) | (((hi >> (7 - col)) & 1) << 1)) as usize;
+                    let palette_index = self.nes.ppu.palette_ram[if pixel == 0 { 0 } else { palette_base + pixel }];
+                    let (x, y) = (origin_x + col, origin_y + row as usize);
+                    out[y * PATTERN_TABLE_VIEW_WIDTH + x] = self.palette.get_rgb((palette_index & 0x3F) as usize);
+                }
+            }
+        }
+    }
+
+    // the raw 8K of CHR currently visible to the ppu, 16 bytes per tile (8 low plane then 8 high plane)
+    pub fn debug_copy_chr(&mut self, out: &mut [u8]) {
+        for (addr, byte) in out.iter_mut().take(0x2000).enumerate() {
+            *byte = self.read_vram(addr as u16).unwrap_or(0);
+        }
+    }
+
+    // the color of one of the 64 palette indices in the loaded palette, without emphasis
+    pub fn get_palette_rgb(&self, index: u8) -> ppu::RgbColor {
+        self.palette.get_rgb((index & 0x3F) as usize)
+    }
+
+    // the palette index stored in one of the 32 palette ram entries
+    pub fn get_palette_ram_entry(&self, entry: u8) -> u8 {
+        self.nes.ppu.palette_ram[(entry & 0x1F) as usize]
+    }
+
+    // the color a palette ram entry currently shows
+    pub fn get_effective_color(&self, palette_entry: u8) -> ppu::RgbColor {
+        self.get_palette_rgb(self.get_palette_ram_entry(palette_entry))
+    }
+
     pub fn dbg_list_palette_ram(&self) -> [ppu::RgbColor; 32] {
         let mut result = [ppu::RgbColor::default(); 32];
         for i in 0x00..=0x1fusize {
-            let rgb = self.nes.ppu.palette.get_rgb(self.nes.ppu.palette_ram[i] as usize);
-            result[i] = rgb;
+            result[i] = self.get_effective_color(i as u8);
         }
         result
     }
 
+    // called at dot 0 of every scanline, including vblank and pre-render; None removes it
+    pub fn set_scanline_callback(&mut self, callback: Option<Box<dyn FnMut(usize)>>) {
+        self.scanline_callback = callback;
+    }
+
+    // while set, samples go to the callback instead of the get_sample buffer. Stereo calls it
+    // twice per sample, left then right
+    pub fn set_sample_callback(&mut self, callback: Option<Box<dyn FnMut(f32)>>) {
+        self.sample_callback = callback;
+    }
+
     pub fn set_input_1(&mut self, input_1: StandardInput, value: bool) {
         self.nes.input_1_mask.set(input_1, value);
     }
 
+    pub fn set_input_2(&mut self, input_2: StandardInput, value: bool) {
+        self.nes.input_2_mask.set(input_2, value);
+    }
+
+    pub fn set_input_3(&mut self, input_3: StandardInput, value: bool) {
+        self.nes.four_score.p3.set(input_3, value);
+    }
+
+    pub fn set_input_4(&mut self, input_4: StandardInput, value: bool) {
+        self.nes.four_score.p4.set(input_4, value);
+    }
+
+    pub fn set_turbo_config(&mut self, player: Player, config: TurboConfig) {
+        let index = player as usize;
+        self.nes.turbo_config[index] = config;
+        self.nes.turbo_counter_a[index] = 0;
+        self.nes.turbo_counter_b[index] = 0;
+    }
+
+    pub fn set_input_mode(&mut self, mode: InputMode) {
+        self.nes.input_mode = mode;
+    }
+
+    // plugs a zapper into port 2, replacing the standard controller
+    pub fn set_zapper_state(&mut self, zapper: ZapperState) {
+        self.nes.zapper = Some(zapper);
+    }
+
+    pub fn set_stereo(&mut self, stereo: bool) {
+        self.config.stereo = stereo;
+    }
+
+    pub fn set_channel_pan(&mut self, channel: apu::AudioChannel, pan: f32) {
+        self.config.channel_pan[channel as usize] = pan;
+    }
+
     pub fn get_sample(&self) -> Vec<f32> {
         self.nes.sample_buffer.clone()
     }
@@ -156,20 +976,117 @@ impl Emulator {
         self.nes.sample_buffer.clear();
     }
 
+    pub fn irq_sources(&self) -> IrqSources {
+        IrqSources {
+            apu_frame: apu::Interface::get_frame_interrupt_flag(self),
+            dmc: apu::Interface::get_dmc_interrupt_flag(self),
+            mapper: self.nes.mapper_irq,
+        }
+    }
+
     pub fn get_apu_output(&self) -> f32 {
         apu::Interface::mixer_output(self)
     }
 
+    pub fn apu_debug_state(&self) -> apu::ApuDebug {
+        apu::Interface::debug_state(self)
+    }
+
+    pub fn current_mirroring(&self) -> Option<cartridge::MirrorMode> {
+        self.mapper.as_ref().map(|mapper| mapper.current_mirroring())
+    }
+
+    // debugging override of the cartridge's nametable layout, does nothing without a cartridge
+    pub fn set_mirroring(&mut self, mirroring: cartridge::MirrorMode) {
+        if let Some(mapper) = self.mapper.as_mut() {
+            mapper.set_mirroring(mirroring);
+        }
+    }
+
     fn clear_input_mask(&mut self) {
         self.nes.input_1_mask = StandardInput::empty();
         self.nes.input_2_mask = StandardInput::empty();
+        self.nes.four_score.p3 = StandardInput::empty();
+        self.nes.four_score.p4 = StandardInput::empty();
     }
 
-    fn load_from_stream<R: Read + Seek>(&mut self, stream: &mut R) -> Result<(), LoadError> {
-        let (_, mapper) = cartridge::parse_stream(stream)?;
-        self.nes = NesState::new();
-        self.mapper = Some(mapper);
-        Ok(())
+    // held turbo buttons are released on every other `rate` frames
+    fn apply_turbo(&mut self) {
+        for player in 0..4 {
+            let config = self.nes.turbo_config[player];
+            let a_released = Emulator::advance_turbo_counter(&mut self.nes.turbo_counter_a[player], config.a_turbo_rate);
+            let b_released = Emulator::advance_turbo_counter(&mut self.nes.turbo_counter_b[player], config.b_turbo_rate);
+            let mask = match player {
+                0 => &mut self.nes.input_1_mask,
+                1 => &mut self.nes.input_2_mask,
+                2 => &mut self.nes.four_score.p3,
+                _ => &mut self.nes.four_score.p4,
+            };
+            if a_released {
+                mask.remove(StandardInput::A);
+            }
+            if b_released {
+                mask.remove(StandardInput::B);
+            }
+        }
+    }
+
+    fn advance_turbo_counter(counter: &mut u8, rate: u8) -> bool {
+        if rate == 0 {
+            return false;
+        }
+        let released = *counter >= rate;
+        *counter = (*counter + 1) % (rate.saturating_mul(2));
+        released
+    }
+
+    // serial bit `offset` of controller port 0 or 1, buttons are shifted out from A to RIGHT
+    fn serial_input_bit(&self, port: usize, offset: usize) -> u8 {
+        let (primary, secondary) = if port == 0 {
+            (self.nes.input_1_mask, self.nes.four_score.p3)
+        } else {
+            (self.nes.input_2_mask, self.nes.four_score.p4)
+        };
+        let (primary, secondary) = if self.config.dpad_filtering {
+            (primary.without_opposite_directions(), secondary.without_opposite_directions())
+        } else {
+            (primary, secondary)
+        };
+        // players 1/2, then players 3/4 and a signature byte identifying the adapter.
+        // A standard controller reads 1 once its 8 buttons are out
+        let (stream, after_stream) = match self.nes.input_mode {
+            InputMode::OnePlayer if port == 1 => (0, 0),
+            InputMode::OnePlayer | InputMode::TwoPlayer => ((primary.bits as u32) << 16 | 0xFFFF, 1),
+            InputMode::FourScore(adapter_type) => {
+                let signature = match (adapter_type, port) {
+                    (FourScoreType::FourScore, 0) | (FourScoreType::Hori, 1) => 0x10,
+                    _ => 0x20,
+                };
+                ((primary.bits as u32) << 16 | (secondary.bits as u32) << 8 | signature, 0)
+            }
+        };
+        if offset < 24 { ((stream >> (23 - offset)) & 1) as u8 } else { after_stream }
+    }
+
+    fn is_bright_at_zapper_position(&self, zapper: &ZapperState) -> bool {
+        let (scanline, dot) = ppu::Interface::get_beam_position(self);
+        let (x, y) = (zapper.x as usize, zapper.y as usize);
+
+        // the beam has to have drawn the aimed spot recently; pixel x goes out on dot x + 1
+        if scanline < y || scanline > y + ZAPPER_SENSE_SCANLINES || (scanline == y && dot <= x + 1) {
+            return false;
+        }
+
+        let framebuffer = self.get_framebuffer();
+        for py in y.saturating_sub(ZAPPER_SENSE_RADIUS)..=(y + ZAPPER_SENSE_RADIUS).min(FRAME_HEIGHT - 1) {
+            for px in x.saturating_sub(ZAPPER_SENSE_RADIUS)..=(x + ZAPPER_SENSE_RADIUS).min(FRAME_WIDTH - 1) {
+                let rgb = framebuffer[py * FRAME_WIDTH + px];
+                if (rgb.r as u16 + rgb.g as u16 + rgb.b as u16) / 3 > 128 {
+                    return true;
+                }
+            }
+        }
+        false
     }
 }
 
@@ -187,6 +1104,9 @@ impl Emulator {
                 }
             },
             0x2000..=0x3FFF => {
+                if let AccessMode::Write(value) = mode {
+                    ppu::Interface::write_io_latch(self, value);
+                }
                 match (addr & 7, mode) {
                     (0, AccessMode::Write(value)) => {
                         ppu::Interface::write_ppuctrl(self, value); value
@@ -219,8 +1139,7 @@ impl Emulator {
                         ppu::Interface::write_ppudata(self, value); value
                     }
                     (_, _) => {
-                        println!("Invalid register access 0x{:x}", addr);
-                        0
+                        ppu::Interface::read_io_latch(self)
                     },
                 }
             },
@@ -286,18 +1205,15 @@ impl Emulator {
             0x4016 => {
                 match mode {
                     AccessMode::Read => {
-                        if !self.nes.input_strobe {
-                            let d0 = if ((self.nes.input_1_mask.bits << self.nes.input_1_offset) & 0b1000_0000) == 0 { 
-                                0u8 
-                            } else { 
-                                1u8 
-                            } << 0;
+                        let d0 = if !self.nes.input_strobe {
+                            let d0 = self.serial_input_bit(0, self.nes.input_1_offset);
                             self.nes.input_1_offset += 1;
                             d0
                         }
                         else {
                             0u8
-                        }
+                        };
+                        d0 | (self.nes.open_bus & CONTROLLER_OPEN_BUS_MASK)
                     },
                     AccessMode::Write(value) => {
                         self.nes.input_strobe = value.is_b0_set();
@@ -312,18 +1228,21 @@ impl Emulator {
             0x4017 => {
                 match mode {
                     AccessMode::Read => {
-                        if !self.nes.input_strobe {
-                            let d0 = if ((self.nes.input_2_mask.bits << self.nes.input_2_offset) & 0b1000_0000) == 0 { 
-                                0u8 
-                            } else { 
-                                1u8 
-                            } << 0;
+                        let value = if let Some(zapper) = self.nes.zapper {
+                            // d3 is low while light is sensed, d4 is high while the trigger is pulled
+                            let d3 = if self.is_bright_at_zapper_position(&zapper) { 0u8 } else { 1u8 } << 3;
+                            let d4 = if zapper.trigger { 1u8 } else { 0u8 } << 4;
+                            d3 | d4
+                        }
+                        else if !self.nes.input_strobe {
+                            let d0 = self.serial_input_bit(1, self.nes.input_2_offset);
                             self.nes.input_2_offset += 1;
                             d0
                         }
                         else {
                             0u8
-                        }
+                        };
+                        value | (self.nes.open_bus & CONTROLLER_OPEN_BUS_MASK)
                     },
                     AccessMode::Write(value) => {
                         apu::Interface::set_frame(self, value); value
@@ -331,13 +1250,18 @@ impl Emulator {
                 }
             }
             0x4018..=0x401F => {
-                0  // FIXME
+                // the apu test registers, disabled on retail consoles
+                match mode {
+                    AccessMode::Read => self.nes.open_bus,
+                    AccessMode::Write(value) => value,
+                }
             },
             0x4020..=0x5FFF => {
+                let open_bus = self.nes.open_bus;
                 let mapper = self.mapper.as_mut().unwrap();
                 match mode {
                     AccessMode::Read => {
-                        mapper.peek_expansion_rom(addr)
+                        mapper.peek_expansion_rom(addr).unwrap_or(open_bus)
                     },
                     AccessMode::Write(value) => {
                         mapper.poke_expansion_rom(addr, value); value
@@ -360,6 +1284,7 @@ impl Emulator {
 
     fn vaccess(&mut self, addr: u16, mode: AccessMode) -> u8 {
         let mapper =  self.mapper.as_mut().unwrap();
+        mapper.on_ppu_address(addr);
         match addr {
             0x0000..= 0x3EFF => {
                 let addr = if addr > 0x2FFF { addr & 0x2FFF } else { addr };
@@ -378,15 +1303,22 @@ impl Emulator {
 
     fn on_cpu_cycle(&mut self) {
         self.nes.cpu_cycle += Wrapping(1);
+        ppu::Interface::on_cpu_cycle(self);
         ppu::Interface::tick(self);
         ppu::Interface::tick(self);
         ppu::Interface::tick(self);
+        // PAL runs 16 ppu dots every 5 cpu cycles
+        if self.region() == Region::Pal && self.nes.cpu_cycle.0 % 5 == 0 {
+            ppu::Interface::tick(self);
+        }
         apu::Interface::on_cpu_tick(self);
-        dma::Interface::on_cpu_tick(self);
         let mapper = self.mapper.as_mut().unwrap();
+        self.nes.mapper_irq = mapper.on_cpu_cycle();
         if mapper.irq_acknowledge() {
-            self.nes.mos6502.irq = false;
+            self.nes.mapper_irq = false;
         }
+        // the cpu's irq input is wired-or between the apu and the cartridge
+        self.nes.mos6502.irq = self.nes.apu_irq || self.nes.mapper_irq;
     }
 }
 
@@ -394,12 +1326,17 @@ impl cpu::Context for Emulator {
     fn peek(&mut self, addr: u16) -> u8 {
         dma::Interface::dma_hijack(self, addr);
         self.on_cpu_cycle();
-        self.access(addr, AccessMode::Read)
+        let value = self.access(addr, AccessMode::Read);
+        self.nes.open_bus = value;
+        cpu::Interface::poll_interrupts(self);
+        value
     }
 
     fn poke(&mut self, addr: u16, val: u8) {
         self.on_cpu_cycle();
         self.access(addr, AccessMode::Write(val));
+        self.nes.open_bus = val;
+        cpu::Interface::poll_interrupts(self);
     }
 
     fn state(&self) -> &cpu::State {
@@ -436,12 +1373,27 @@ impl ppu::Context for Emulator {
         self.nes.mos6502.nmi = true;
     }
 
-    fn irq_scanline(&mut self) {
-        let mapper = self.mapper.as_mut().unwrap();
-        if mapper.irq() {
-            self.nes.mos6502.irq = true;
+    fn on_scanline_start(&mut self, scanline: usize) {
+        if let Some(callback) = self.scanline_callback.as_mut() {
+            callback(scanline);
         }
     }
+
+    fn region(&self) -> Region {
+        Emulator::region(self)
+    }
+
+    fn is_indexed_output_enabled(&self) -> bool {
+        self.config.indexed_output
+    }
+
+    fn is_oam_corruption_enabled(&self) -> bool {
+        self.config.oam_corruption
+    }
+
+    fn palette(&self) -> &ppu::Palette {
+        &self.palette
+    }
 }
 
 impl apu::Context for Emulator {
@@ -454,9 +1406,8 @@ impl apu::Context for Emulator {
     }
 
     fn set_irq(&mut self, irq_enable: bool) {
-        // conflict with mmc3 irq, fixme later
-        
-        // self.nes.mos6502.irq = irq_enable;
+        self.nes.apu_irq = irq_enable;
+        self.nes.mos6502.irq = self.nes.apu_irq || self.nes.mapper_irq;
     }
 
     fn activate_dma(&mut self, addr: u16) {
@@ -464,12 +1415,27 @@ impl apu::Context for Emulator {
     }
 
     fn on_sample(&mut self, sample: f32) {
-        self.nes.sample_buffer.push(sample);
+        match self.sample_callback.as_mut() {
+            Some(callback) => callback(sample),
+            None => self.nes.sample_buffer.push(sample),
+        }
     }
 
     fn is_on_odd_cpu_cycle(&mut self) -> bool {
         self.get_cycle() & 1 == 1
     }
+
+    fn region(&self) -> Region {
+        Emulator::region(self)
+    }
+
+    fn stereo_pan(&self) -> Option<[f32; 4]> {
+        if self.config.stereo { Some(self.config.channel_pan) } else { None }
+    }
+
+    fn expansion_audio(&self) -> f32 {
+        self.mapper.as_ref().map_or(0.0, |mapper| mapper.audio_output())
+    }
 }
 
 impl dma::Context for Emulator {
@@ -496,7 +1462,885 @@ impl dma::Context for Emulator {
 
     fn on_ppu_dma_transfer(&mut self, value: u8, offset: usize) {
         self.on_cpu_cycle();
-        let index = (offset + self.nes.ppu.oamaddr) & 0xFF;
-        self.nes.ppu.oamdata[index] = value;
+        ppu::Interface::write_oamdata_from_dma(self, offset, value);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_rom::{TestRom, CODE_ADDR};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // an MMC3 program that enables the scanline irq and waits with I clear. The irq handler
+    // at $FF10 is `handler` followed by INC $10, RTI. Steps until the handler first runs,
+    // then 30 more instructions, and gives how often it was entered
+    fn mmc3_irq_handler_entries(handler: &[u8]) -> u8 {
+        let mut handler = handler.to_vec();
+        handler.extend_from_slice(&[0xE6, 0x10, 0x40]);
+        let mut emulator = TestRom::new(4)
+            .code(CODE_ADDR, &[
+                0xA9, 0x40, 0x8D, 0x17, 0x40,   // frame irq off
+                0xA9, 0x08, 0x8D, 0x00, 0x20,   // sprites from $1000
+                0xA9, 0x18, 0x8D, 0x01, 0x20,
+                0xA9, 0x10, 0x8D, 0x00, 0xC0,   // latch 16
+                0x8D, 0x01, 0xC0, 0x8D, 0x01, 0xE0,
+                0x58,
+                0x4C, 0x1B, 0xE0,
+            ])
+            .code(0xFF10, &handler)
+            .vector(0xFFFE, 0xFF10)
+            .emulator();
+        ppu::Interface::end_warmup(&mut emulator);
+        let mut steps = 0;
+        while emulator.nes.ram[0x10] == 0 {
+            cpu::Interface::step(&mut emulator);
+            steps += 1;
+            assert!(steps < 100_000, "the irq never reached the cpu");
+        }
+        for _ in 0..30 {
+            cpu::Interface::step(&mut emulator);
+        }
+        emulator.nes.ram[0x10]
+    }
+
+    #[test]
+    fn mapper_irq_holds_the_line_until_the_mapper_is_acknowledged() {
+        // STA $E000 acknowledges it, the handler then runs once for this irq
+        assert_eq!(mmc3_irq_handler_entries(&[0x8D, 0x00, 0xE0]), 1);
+        // without that RTI lands straight back in the handler
+        assert!(mmc3_irq_handler_entries(&[]) > 1);
+    }
+
+    // writes through $2006/$2007 as the cpu would, once the ppu has warmed up
+    fn write_vram(emulator: &mut Emulator, addr: u16, data: &[u8]) {
+        ppu::Interface::end_warmup(emulator);
+        ppu::Interface::write_ppuaddr(emulator, (addr >> 8) as u8);
+        ppu::Interface::write_ppuaddr(emulator, addr as u8);
+        for _ in 0..3 {
+            ppu::Interface::tick(emulator);
+        }
+        for &value in data {
+            ppu::Interface::write_ppudata(emulator, value);
+        }
+    }
+
+    #[test]
+    fn indexed_framebuffer_agrees_with_rgb_through_the_palette() {
+        let mut emulator = TestRom::nrom().emulator();
+        emulator.set_indexed_output(true);
+        let patterns: Vec<u8> = (0..0x1000u32).map(|i| (i.wrapping_mul(0x9E37_79B9) >> 13) as u8).collect();
+        write_vram(&mut emulator, 0x0000, &patterns);
+        let nametable: Vec<u8> = (0..0x400u32).map(|i| (i * 7) as u8).collect();
+        write_vram(&mut emulator, 0x2000, &nametable);
+        let colors: Vec<u8> = (0..32u8).map(|i| (i * 5) & 0x3F).collect();
+        write_vram(&mut emulator, 0x3F00, &colors);
+        // red and blue emphasis on, everything shown
+        ppu::Interface::write_ppumask(&mut emulator, 0xBE);
+        emulator.run_for_one_frame();
+        emulator.run_for_one_frame();
+
+        let indexed = emulator.get_framebuffer_indexed();
+        assert!(indexed.iter().all(|&index| index >> 6 == 0b101));
+        assert!(indexed.iter().any(|&index| index != indexed[0]));
+        for (rgb, &index) in emulator.get_framebuffer().iter().zip(indexed.iter()) {
+            let expected = emulator.palette.get_rgb(index as usize);
+            assert_eq!((rgb.r, rgb.g, rgb.b), (expected.r, expected.g, expected.b));
+        }
+    }
+
+    // which of A and B player 3 sees on each frame while holding both
+    fn turbo_pattern(emulator: &mut Emulator, frames: usize) -> Vec<(bool, bool)> {
+        (0..frames).map(|_| {
+            emulator.set_input_3(StandardInput::A | StandardInput::B, true);
+            emulator.apply_turbo();
+            let held = emulator.nes.four_score.p3;
+            emulator.clear_input_mask();
+            (held.contains(StandardInput::A), held.contains(StandardInput::B))
+        }).collect()
+    }
+
+    #[test]
+    fn turbo_duty_cycle_is_rate_frames_on_then_off() {
+        let mut emulator = Emulator::new();
+        emulator.set_turbo_config(Player::Three, TurboConfig { a_turbo_rate: 2, b_turbo_rate: 1 });
+        let (a, b): (Vec<bool>, Vec<bool>) = turbo_pattern(&mut emulator, 8).into_iter().unzip();
+        assert_eq!(a, [true, true, false, false, true, true, false, false]);
+        assert_eq!(b, [true, false, true, false, true, false, true, false]);
+
+        // a new config starts on the pressed half, rate 0 holds the button
+        emulator.set_turbo_config(Player::Three, TurboConfig { a_turbo_rate: 3, b_turbo_rate: 0 });
+        let (a, b): (Vec<bool>, Vec<bool>) = turbo_pattern(&mut emulator, 7).into_iter().unzip();
+        assert_eq!(a, [true, true, true, false, false, false, true]);
+        assert_eq!(b, [true; 7]);
+    }
+
+    #[test]
+    fn turbo_only_affects_its_player() {
+        let mut emulator = Emulator::new();
+        emulator.set_turbo_config(Player::One, TurboConfig { a_turbo_rate: 1, b_turbo_rate: 1 });
+        assert_eq!(turbo_pattern(&mut emulator, 4), [(true, true); 4]);
+    }
+
+    #[test]
+    fn read_vram_sees_what_ppudata_wrote() {
+        assert_eq!(Emulator::new().read_vram(0x2000), None);
+
+        let mut emulator = TestRom::nrom().emulator();
+        write_vram(&mut emulator, 0x2000, &[0x12, 0x34]);
+        write_vram(&mut emulator, 0x3F00, &[0x0F]);
+        write_vram(&mut emulator, 0x3F11, &[0x21]);
+        assert_eq!(emulator.read_vram(0x2000), Some(0x12));
+        assert_eq!(emulator.read_vram(0x2001), Some(0x34));
+        // $3000-$3EFF mirrors the nametables
+        assert_eq!(emulator.read_vram(0x3001), Some(0x34));
+        assert_eq!(emulator.read_vram(0x3F10), Some(0x0F));
+        assert_eq!(emulator.read_vram(0x3F11), Some(0x21));
+        assert_eq!(emulator.read_vram(0x3F01), Some(0x00));
+        // the palette repeats up to $3FFF
+        assert_eq!(emulator.read_vram(0x3FF0), Some(0x0F));
+    }
+
+    #[test]
+    fn reset_keeps_ram_and_power_cycle_refills_it() {
+        let mut emulator = TestRom::nrom().emulator();
+        emulator.nes.ram[0x123] = 0x55;
+        emulator.reset();
+        emulator.run_for_one_frame();
+        assert_eq!(emulator.nes.ram[0x123], 0x55);
+        emulator.soft_reset();
+        assert_eq!(emulator.nes.ram[0x123], 0x55);
+
+        emulator.power_cycle();
+        assert_eq!(emulator.nes.ram[0x123], 0x00);
+
+        emulator.set_power_on_ram_pattern(RamPattern::AllOnes);
+        emulator.nes.ram[0x123] = 0x55;
+        #[allow(deprecated)]
+        emulator.reset_to_power_on();
+        assert!(emulator.nes.ram.iter().all(|&byte| byte == 0xFF));
+    }
+
+    #[test]
+    fn one_screen_lower_mirrors_every_nametable_to_2000() {
+        Emulator::new().set_mirroring(cartridge::MirrorMode::OneScreenLow);
+
+        let mut emulator = TestRom::nrom().emulator();
+        emulator.set_mirroring(cartridge::MirrorMode::OneScreenLow);
+        assert_eq!(emulator.current_mirroring(), Some(cartridge::MirrorMode::OneScreenLow));
+        write_vram(&mut emulator, 0x2000, &[0xAB]);
+        write_vram(&mut emulator, 0x2C01, &[0xCD]);
+        for base in [0x2000, 0x2400, 0x2800, 0x2C00] {
+            assert_eq!(emulator.read_vram(base), Some(0xAB));
+            assert_eq!(emulator.read_vram(base + 1), Some(0xCD));
+        }
+    }
+
+    #[test]
+    fn current_mirroring_follows_the_cartridge() {
+        assert_eq!(Emulator::new().current_mirroring(), None);
+        let emulator = TestRom::nrom().emulator();
+        assert_eq!(emulator.current_mirroring(), Some(cartridge::MirrorMode::Horizontal));
+    }
+
+    #[test]
+    fn mapper_name_comes_from_the_loaded_header() {
+        let emulator = TestRom::new(1).emulator();
+        assert_eq!(emulator.mapper_id(), 1);
+        assert_eq!(emulator.mapper_name(), "MMC1");
+        assert_eq!(Emulator::new().mapper_name(), "None");
+    }
+
+    #[test]
+    fn irq_line_is_held_while_either_source_asserts_it() {
+        let mut emulator = TestRom::new(4).emulator();
+        ppu::Interface::end_warmup(&mut emulator);
+        // background from $0000 and sprites from $1000, so the mmc3 counter is clocked every scanline
+        cpu::Context::poke(&mut emulator, 0x2000, 0x08);
+        cpu::Context::poke(&mut emulator, 0x2001, 0x18);
+        cpu::Context::poke(&mut emulator, 0xC000, 1);
+        cpu::Context::poke(&mut emulator, 0xC001, 0);
+        cpu::Context::poke(&mut emulator, 0xE001, 0);
+        // 4-step frame counter with its irq enabled
+        cpu::Context::poke(&mut emulator, 0x4017, 0);
+        emulator.run_for_one_frame();
+        emulator.run_for_one_frame();
+        assert!(emulator.nes.apu_irq && emulator.nes.mapper_irq);
+        assert!(emulator.nes.mos6502.irq);
+
+        // reading $4015 acknowledges the frame irq, the mapper keeps the line low
+        cpu::Context::peek(&mut emulator, 0x4015);
+        cpu::Context::peek(&mut emulator, 0x0000);
+        assert!(!emulator.nes.apu_irq);
+        assert!(emulator.nes.mos6502.irq);
+
+        cpu::Context::poke(&mut emulator, 0xE000, 0);
+        cpu::Context::peek(&mut emulator, 0x0000);
+        assert!(!emulator.nes.mos6502.irq);
+    }
+
+    // pseudo random tiles over palettes of different colours, with the background shown.
+    // The second frame is the first one drawn with everything in place
+    fn noise_frame() -> Emulator {
+        let mut emulator = TestRom::nrom().emulator();
+        let patterns: Vec<u8> = (0..0x1000u32).map(|i| (i.wrapping_mul(0x9E37_79B9) >> 13) as u8).collect();
+        write_vram(&mut emulator, 0x0000, &patterns);
+        let nametable: Vec<u8> = (0..0x400u32).map(|i| (i * 7) as u8).collect();
+        write_vram(&mut emulator, 0x2000, &nametable);
+        let colors: Vec<u8> = (0..32u8).map(|i| (i * 5) & 0x3F).collect();
+        write_vram(&mut emulator, 0x3F00, &colors);
+        ppu::Interface::write_ppumask(&mut emulator, 0x0A);
+        emulator.run_for_one_frame();
+        emulator.run_for_one_frame();
+        emulator
+    }
+
+    #[test]
+    fn rgb565_keeps_the_top_bits_of_each_channel() {
+        let emulator = noise_frame();
+        let mut out = vec![0; 256 * 240];
+        emulator.copy_framebuffer_rgb565(&mut out).unwrap();
+        for (&pixel, rgb) in out.iter().zip(emulator.get_framebuffer()) {
+            assert_eq!(pixel >> 11, rgb.r as u16 >> 3);
+            assert_eq!(pixel >> 5 & 0x3F, rgb.g as u16 >> 2);
+            assert_eq!(pixel & 0x1F, rgb.b as u16 >> 3);
+        }
+        assert!(out.iter().any(|&pixel| pixel != out[0]));
+        assert!(matches!(emulator.copy_framebuffer_rgb565(&mut out[1..]), Err(FramebufferError::BufferTooSmall(0xF000))));
+    }
+
+    #[test]
+    fn rgba8888_is_the_framebuffer_with_opaque_alpha() {
+        let emulator = noise_frame();
+        let mut out = vec![0; 256 * 240 * 4];
+        emulator.copy_framebuffer_rgba8888(&mut out).unwrap();
+        let expected: Vec<u8> = emulator.get_framebuffer().iter().flat_map(|rgb| [rgb.r, rgb.g, rgb.b, 0xFF]).collect();
+        assert!(out == expected);
+        assert!(out.chunks_exact(4).any(|pixel| pixel != &out[0..4]));
+        assert!(matches!(emulator.copy_framebuffer_rgba8888(&mut out[1..]), Err(FramebufferError::BufferTooSmall(0x3C000))));
+    }
+
+    #[test]
+    fn rgb24_is_the_framebuffer_packed_tightly() {
+        let emulator = noise_frame();
+        let mut out = vec![0; 256 * 240 * 3];
+        emulator.copy_framebuffer_into(&mut out).unwrap();
+        let expected: Vec<u8> = emulator.get_framebuffer().iter().flat_map(|rgb| [rgb.r, rgb.g, rgb.b]).collect();
+        assert!(out == expected);
+        assert!(matches!(emulator.copy_framebuffer_into(&mut out[1..]), Err(FramebufferError::BufferTooSmall(0x2D000))));
+    }
+
+    // cpu cycles from one end of frame to the next, the first call lines up on a frame edge
+    fn cpu_cycles_per_frame(emulator: &mut Emulator) -> usize {
+        let mut cycles = 0;
+        while !emulator.nes.frame_generated {
+            emulator.on_cpu_cycle();
+            cycles += 1;
+        }
+        emulator.nes.frame_generated = false;
+        cycles
+    }
+
+    #[test]
+    fn dendy_frames_are_312_scanlines_at_3_dots_per_cycle() {
+        let mut emulator = TestRom::nrom().emulator();
+        emulator.set_config(EmulatorConfig { region: Region::Dendy, ..emulator.get_config() });
+        ppu::Interface::end_warmup(&mut emulator);
+        ppu::Interface::write_ppumask(&mut emulator, 0x08);
+        cpu_cycles_per_frame(&mut emulator);
+        // no odd frame dot skip either
+        for _ in 0..3 {
+            assert_eq!(cpu_cycles_per_frame(&mut emulator), 341 * 312 / 3);
+        }
+    }
+
+    // ppu dots from one end of frame to the next, the first call lines up on a frame edge
+    fn dots_per_frame(emulator: &mut Emulator) -> usize {
+        let mut dots = 0;
+        while !emulator.nes.frame_generated {
+            ppu::Interface::tick(emulator);
+            dots += 1;
+        }
+        emulator.nes.frame_generated = false;
+        dots
+    }
+
+    #[test]
+    fn pal_has_no_odd_frame_dot_skip() {
+        let mut emulator = TestRom::nrom().emulator();
+        emulator.set_config(EmulatorConfig { region: Region::Pal, ..emulator.get_config() });
+        ppu::Interface::end_warmup(&mut emulator);
+        ppu::Interface::write_ppumask(&mut emulator, 0x08);
+        dots_per_frame(&mut emulator);
+        assert_eq!([dots_per_frame(&mut emulator), dots_per_frame(&mut emulator)], [341 * 312; 2]);
+
+        // NTSC drops a dot from every other frame with rendering on
+        emulator.set_config(EmulatorConfig { region: Region::Ntsc, ..emulator.get_config() });
+        dots_per_frame(&mut emulator);
+        let mut frames = [dots_per_frame(&mut emulator), dots_per_frame(&mut emulator)];
+        frames.sort();
+        assert_eq!(frames, [341 * 262 - 1, 341 * 262]);
+    }
+
+    // the scanline numbers the callback got over one whole frame
+    fn scanlines_reported(emulator: &mut Emulator) -> Vec<usize> {
+        let scanlines = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&scanlines);
+        emulator.set_scanline_callback(Some(Box::new(move |scanline| sink.borrow_mut().push(scanline))));
+        cpu_cycles_per_frame(emulator);
+        scanlines.borrow_mut().clear();
+        cpu_cycles_per_frame(emulator);
+        emulator.set_scanline_callback(None);
+        Rc::try_unwrap(scanlines).unwrap().into_inner()
+    }
+
+    #[test]
+    fn scanline_callback_runs_once_per_visible_scanline() {
+        let mut emulator = TestRom::nrom().emulator();
+        let visible: Vec<usize> = scanlines_reported(&mut emulator).into_iter().filter(|&scanline| scanline < 240).collect();
+        assert_eq!(visible, (0..240).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn scanline_callback_runs_on_every_ntsc_scanline() {
+        let mut emulator = TestRom::nrom().emulator();
+        // a frame ends at vblank, so the count starts at 242
+        let mut scanlines = scanlines_reported(&mut emulator);
+        scanlines.sort_unstable();
+        assert_eq!(scanlines, (0..262).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn custom_palette_colours_the_next_frame() {
+        let mut emulator = noise_frame();
+        emulator.set_indexed_output(true);
+        let default_colours: Vec<[u8; 3]> = emulator.get_framebuffer().iter().map(|rgb| [rgb.r, rgb.g, rgb.b]).collect();
+        let pal: Vec<u8> = (0..64u8).flat_map(|i| [i * 4, 0xFF - i, i ^ 0x2A]).collect();
+        emulator.set_palette_from_pal(&pal).unwrap();
+        emulator.run_for_one_frame();
+        for (rgb, &index) in emulator.get_framebuffer().iter().zip(emulator.get_framebuffer_indexed()) {
+            let entry = index as usize * 3;
+            assert_eq!([rgb.r, rgb.g, rgb.b], pal[entry..entry + 3]);
+        }
+        assert!(emulator.get_framebuffer().iter().zip(default_colours).any(|(rgb, default)| [rgb.r, rgb.g, rgb.b] != default));
+    }
+
+    // the colour of the top left pixel after a frame with rendering off, which shows the
+    // backdrop, colour 0
+    fn backdrop_after_a_frame(emulator: &mut Emulator, ppumask: u8) -> [u8; 3] {
+        ppu::Interface::write_ppumask(emulator, ppumask);
+        emulator.run_for_one_frame();
+        let rgb = emulator.get_framebuffer()[0];
+        [rgb.r, rgb.g, rgb.b]
+    }
+
+    #[test]
+    fn palette_files_replace_colours_and_emphasis() {
+        let mut emulator = TestRom::nrom().emulator();
+        write_vram(&mut emulator, 0x3F00, &[0x00]);
+        write_vram(&mut emulator, 0x2000, &[]);
+        let default_backdrop = backdrop_after_a_frame(&mut emulator, 0x00);
+
+        // a 512 colour file where colour 0 is pure red, and green with red emphasis
+        let mut pal = vec![0x80; 512 * 3];
+        pal[0..3].copy_from_slice(&[0xFF, 0x00, 0x00]);
+        pal[64 * 3..64 * 3 + 3].copy_from_slice(&[0x00, 0xFF, 0x00]);
+        emulator.set_palette_from_pal(&pal).unwrap();
+        assert_eq!(backdrop_after_a_frame(&mut emulator, 0x00), [0xFF, 0x00, 0x00]);
+        assert_eq!(backdrop_after_a_frame(&mut emulator, 0x20), [0x00, 0xFF, 0x00]);
+
+        assert!(matches!(emulator.set_palette_from_pal(&pal[3..]), Err(PaletteError::InvalidSize(1533))));
+        emulator.reset_palette();
+        assert_eq!(backdrop_after_a_frame(&mut emulator, 0x00), default_backdrop);
+    }
+
+    // bits 3 and 4 of $4017, light sensed (active low) and trigger pulled, read without
+    // moving the beam
+    fn zapper_bits(emulator: &mut Emulator) -> (bool, bool) {
+        let value = emulator.access(0x4017, AccessMode::Read);
+        (value & 0x08 == 0, value & 0x10 != 0)
+    }
+
+    #[test]
+    fn zapper_senses_a_white_pixel_once_the_beam_has_drawn_it() {
+        let mut emulator = TestRom::nrom().emulator();
+        // the top left pixel of tile 0 is colour 1, so every tile starts with one white pixel
+        write_vram(&mut emulator, 0x0000, &[0x80]);
+        write_vram(&mut emulator, 0x3F00, &[0x0F, 0x30]);
+        write_vram(&mut emulator, 0x2000, &[]);
+        // the $2006 writes leave a fine y scroll of 2 in t
+        ppu::Interface::write_ppuscroll(&mut emulator, 0);
+        ppu::Interface::write_ppuscroll(&mut emulator, 0);
+        ppu::Interface::write_ppumask(&mut emulator, 0x0A);
+        emulator.run_for_one_frame();
+        emulator.run_for_one_frame();
+        emulator.set_zapper_state(ZapperState { x: 16, y: 8, trigger: false });
+
+        // pixel 16 of scanline 8 goes out on dot 17
+        while ppu::Interface::get_beam_position(&emulator) != (8, 17) {
+            ppu::Interface::tick(&mut emulator);
+        }
+        assert_eq!(zapper_bits(&mut emulator), (false, false));
+        ppu::Interface::tick(&mut emulator);
+        assert_eq!(zapper_bits(&mut emulator), (true, false));
+
+        emulator.set_zapper_state(ZapperState { x: 16, y: 8, trigger: true });
+        assert_eq!(zapper_bits(&mut emulator), (true, true));
+
+        // aimed at a black pixel
+        emulator.set_zapper_state(ZapperState { x: 20, y: 12, trigger: false });
+        while ppu::Interface::get_beam_position(&emulator) != (20, 0) {
+            ppu::Interface::tick(&mut emulator);
+        }
+        assert_eq!(zapper_bits(&mut emulator), (false, false));
+    }
+
+    // wraps the cartridge's mapper and holds its irq line from the given cpu cycle on
+    struct IrqFromCycle {
+        inner: Box<dyn cartridge::Mapper>,
+        cycles_left: u32,
+    }
+
+    impl cartridge::Mapper for IrqFromCycle {
+        fn peek(&mut self, addr: u16) -> u8 { self.inner.peek(addr) }
+        fn poke(&mut self, addr: u16, val: u8) { self.inner.poke(addr, val) }
+        fn vpeek(&mut self, addr: u16) -> u8 { self.inner.vpeek(addr) }
+        fn vpoke(&mut self, addr: u16, val: u8) { self.inner.vpoke(addr, val) }
+
+        fn on_cpu_cycle(&mut self) -> bool {
+            self.cycles_left = self.cycles_left.saturating_sub(1);
+            self.cycles_left == 0
+        }
+
+        fn current_mirroring(&self) -> cartridge::MirrorMode { self.inner.current_mirroring() }
+        fn set_mirroring(&mut self, mirroring: cartridge::MirrorMode) { self.inner.set_mirroring(mirroring) }
+        fn load_state(&mut self, state: Vec<u8>) { self.inner.load_state(state) }
+        fn save_state(&self) -> Vec<u8> { self.inner.save_state() }
+
+        fn clone_box(&self) -> Box<dyn cartridge::Mapper> {
+            Box::new(IrqFromCycle { inner: self.inner.clone_box(), cycles_left: self.cycles_left })
+        }
+    }
+
+    #[test]
+    fn mapper_irq_from_on_cpu_cycle_reaches_the_cpu() {
+        // CLI and wait, the handler at $FF10 is INC $10 and a loop
+        let mut emulator = TestRom::nrom()
+            .code(CODE_ADDR, &[0x58, 0x4C, 0x01, 0xE0])
+            .code(0xFF10, &[0xE6, 0x10, 0x4C, 0x12, 0xFF])
+            .vector(0xFFFE, 0xFF10)
+            .emulator();
+        let inner = emulator.mapper.take().unwrap();
+        emulator.mapper = Some(Box::new(IrqFromCycle { inner, cycles_left: 1000 }));
+
+        while emulator.get_cycle() < 990 {
+            cpu::Interface::step(&mut emulator);
+        }
+        assert_eq!(emulator.nes.ram[0x10], 0);
+        assert!(!emulator.nes.mos6502.irq);
+        for _ in 0..20 {
+            cpu::Interface::step(&mut emulator);
+        }
+        assert!(emulator.nes.mapper_irq);
+        assert_eq!(emulator.nes.ram[0x10], 1);
+    }
+
+    // a frame of samples with pulse 1, triangle and noise playing
+    fn tone_samples(stereo: bool) -> Vec<f32> {
+        let mut emulator = TestRom::nrom().emulator();
+        emulator.set_stereo(stereo);
+        for (addr, value) in [(0x4015, 0x0D), (0x4000, 0xBF), (0x4002, 0x80), (0x4003, 0x01),
+                              (0x4008, 0xFF), (0x400A, 0x40), (0x400B, 0x01),
+                              (0x400C, 0x3F), (0x400E, 0x04), (0x400F, 0x01)] {
+            cpu::Context::poke(&mut emulator, addr, value);
+        }
+        emulator.run_for_one_frame();
+        emulator.get_sample()
+    }
+
+    #[test]
+    fn centered_stereo_mix_matches_mono() {
+        let mono = tone_samples(false);
+        let stereo = tone_samples(true);
+        assert!(mono.iter().any(|&sample| sample != mono[0]));
+        assert_eq!(stereo.len(), mono.len() * 2);
+        for (pair, &sample) in stereo.chunks_exact(2).zip(mono.iter()) {
+            assert_eq!(pair, [sample, sample]);
+        }
+    }
+
+    // strobes the controllers and reads `count` bits from port 0
+    fn controller_1_bits(emulator: &mut Emulator, count: usize) -> Vec<u8> {
+        emulator.access(0x4016, AccessMode::Write(1));
+        emulator.access(0x4016, AccessMode::Write(0));
+        (0..count).map(|_| emulator.access(0x4016, AccessMode::Read) & 1).collect()
+    }
+
+    #[test]
+    fn standard_controllers_read_1_after_their_8_buttons() {
+        let mut emulator = TestRom::nrom().emulator();
+        emulator.set_input_1(StandardInput::A | StandardInput::RIGHT, true);
+        let mut expected = vec![1, 0, 0, 0, 0, 0, 0, 1];
+        expected.extend([1; 24]);
+        assert_eq!(controller_1_bits(&mut emulator, 32), expected);
+
+        // the four score's report ends with its signature, then 0s
+        emulator.set_input_mode(InputMode::FourScore(FourScoreType::FourScore));
+        let bits = controller_1_bits(&mut emulator, 32);
+        assert_eq!(bits[16..24], [0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(bits[24..], [0; 8]);
+    }
+
+    #[test]
+    fn overscan_crops_rows_and_columns_from_the_copies() {
+        let mut emulator = noise_frame();
+        emulator.set_overscan(Overscan { top: 8, bottom: 16, left: 4, right: 12 });
+        assert_eq!((emulator.visible_width(), emulator.visible_height()), (240, 216));
+
+        let mut out = vec![0; 240 * 216 * 3];
+        emulator.copy_framebuffer_into(&mut out).unwrap();
+        let framebuffer = emulator.get_framebuffer();
+        for (y, row) in out.chunks_exact(240 * 3).enumerate() {
+            let expected: Vec<u8> = framebuffer[(y + 8) * 256 + 4..(y + 8) * 256 + 244]
+                .iter()
+                .flat_map(|rgb| [rgb.r, rgb.g, rgb.b])
+                .collect();
+            assert!(row == &expected[..], "row {}", y);
+        }
+        assert!(matches!(emulator.copy_framebuffer_into(&mut out[1..]), Err(FramebufferError::BufferTooSmall(155520))));
+    }
+
+    #[test]
+    fn nametable_viewer_draws_tiles_at_their_nametable_position() {
+        let mut emulator = TestRom::nrom().emulator();
+        // tile 1 is solid colour 3, placed at column 5 row 3 of $2800 with attribute palette 2
+        write_vram(&mut emulator, 0x0010, &[0xFF; 16]);
+        write_vram(&mut emulator, 0x2800 + 3 * 32 + 5, &[0x01]);
+        write_vram(&mut emulator, 0x2800 + 0x3C1, &[0x20]);
+        write_vram(&mut emulator, 0x3F00, &[0x0F]);
+        write_vram(&mut emulator, 0x3F0B, &[0x16]);
+        let mut out = vec![ppu::RgbColor::default(); NAMETABLE_VIEW_WIDTH * NAMETABLE_VIEW_HEIGHT];
+        emulator.debug_render_nametables(&mut out);
+
+        let rgb = |color: ppu::RgbColor| [color.r, color.g, color.b];
+        let tile_color = rgb(emulator.get_palette_rgb(0x16));
+        let backdrop = rgb(emulator.get_palette_rgb(0x0F));
+        let at = |x: usize, y: usize| rgb(out[y * NAMETABLE_VIEW_WIDTH + x]);
+        // horizontal mirroring shows $2800 in the lower left and right quarters
+        for origin_x in [0, 256] {
+            for y in 240 + 24..240 + 32 {
+                for x in origin_x + 40..origin_x + 48 {
+                    assert_eq!(at(x, y), tile_color, "({}, {})", x, y);
+                }
+            }
+            assert_eq!(at(origin_x + 39, 240 + 24), backdrop);
+            assert_eq!(at(origin_x + 48, 240 + 31), backdrop);
+            assert_eq!(at(origin_x + 40, 240 + 23), backdrop);
+            assert_eq!(at(origin_x + 47, 240 + 32), backdrop);
+            assert_eq!(at(origin_x + 40, 24), backdrop);
+        }
+    }
+
+    #[test]
+    fn pattern_table_viewer_decodes_both_bit_planes() {
+        let mut emulator = TestRom::nrom().emulator();
+        // tile $21: the low plane sets the left half, the high plane the top half
+        let mut tile = [0x00; 16];
+        tile[0..8].fill(0xF0);
+        tile[8..12].fill(0xFF);
+        write_vram(&mut emulator, 0x0210, &tile);
+        write_vram(&mut emulator, 0x3F00, &[0x0F, 0x01, 0x02, 0x03, 0x0F, 0x16, 0x2A, 0x30]);
+        let mut out = vec![ppu::RgbColor::default(); PATTERN_TABLE_VIEW_WIDTH * PATTERN_TABLE_VIEW_HEIGHT];
+        emulator.debug_render_pattern_tables(1, &mut out);
+
+        let rgb = |color: ppu::RgbColor| [color.r, color.g, color.b];
+        let colors = [0x0F, 0x16, 0x2A, 0x30].map(|index| rgb(emulator.get_palette_rgb(index)));
+        // tile $21 is column 1 of row 2 in the left table
+        let at = |x: usize, y: usize| rgb(out[(16 + y) * PATTERN_TABLE_VIEW_WIDTH + 8 + x]);
+        assert_eq!(at(0, 0), colors[3]);
+        assert_eq!(at(7, 0), colors[2]);
+        assert_eq!(at(3, 7), colors[1]);
+        assert_eq!(at(4, 7), colors[0]);
+
+        let mut chr = vec![0; 0x2000];
+        emulator.debug_copy_chr(&mut chr);
+        assert_eq!(chr[0x210..0x220], tile);
+    }
+
+    #[test]
+    fn region_comes_from_each_new_header_or_the_config() {
+        let mut pal_image = TestRom::nrom().build();
+        pal_image[9] = 0x01;
+        let mut emulator = Emulator::new();
+        emulator.load_rom_from_bytes(&pal_image).unwrap();
+        assert_eq!(emulator.region(), Region::Pal);
+        assert_eq!(emulator.get_config().region, Region::Ntsc);
+
+        // an iNES 1.0 header without the PAL bit says nothing, so the configured NTSC applies
+        emulator.load_rom_from_bytes(&TestRom::nrom().build()).unwrap();
+        emulator.reset();
+        assert_eq!(emulator.region(), Region::Ntsc);
+        ppu::Interface::end_warmup(&mut emulator);
+        cpu_cycles_per_frame(&mut emulator);
+        let cycles: usize = (0..3).map(|_| cpu_cycles_per_frame(&mut emulator)).sum();
+        assert_eq!(cycles, 341 * 262);
+
+        emulator.set_config(EmulatorConfig { region: Region::Dendy, ..emulator.get_config() });
+        assert_eq!(emulator.region(), Region::Dendy);
+    }
+
+    #[test]
+    fn unmapped_reads_see_the_last_bus_value() {
+        // LDA abs leaves the high byte of the address on the bus just before the read
+        let mut emulator = TestRom::nrom()
+            .code(CODE_ADDR, &[
+                0xAD, 0x18, 0x40, 0x85, 0x10,   // LDA $4018, STA $10
+                0xAD, 0xFF, 0x5F, 0x85, 0x11,   // LDA $5FFF, STA $11
+                0x4C, 0x0A, 0xE0,
+            ])
+            .emulator();
+        for _ in 0..5 {
+            cpu::Interface::step(&mut emulator);
+        }
+        assert_eq!(emulator.nes.ram[0x10..0x12], [0x40, 0x5F]);
+
+        cpu::Context::poke(&mut emulator, 0x0000, 0xA5);
+        assert_eq!(cpu::Context::peek(&mut emulator, 0x401F), 0xA5);
+        assert_eq!(cpu::Context::peek(&mut emulator, 0x4020), 0xA5);
+    }
+
+    #[test]
+    fn write_only_ppu_registers_read_the_io_latch() {
+        let mut emulator = TestRom::nrom().emulator();
+        cpu::Context::poke(&mut emulator, 0x2005, 0x96);
+        assert_eq!(cpu::Context::peek(&mut emulator, 0x2001), 0x96);
+        assert_eq!(cpu::Context::peek(&mut emulator, 0x2006), 0x96);
+        // $2002 drives only its top 3 bits
+        assert_eq!(cpu::Context::peek(&mut emulator, 0x2002) & 0x1F, 0x16);
+    }
+
+    #[test]
+    fn greyscale_renders_the_grey_of_each_colour_column() {
+        let mut emulator = TestRom::nrom().emulator();
+        let rgb = |emulator: &Emulator, index| {
+            let rgb = emulator.get_palette_rgb(index);
+            [rgb.r, rgb.g, rgb.b]
+        };
+        for (color, expected) in [(0x16, 0x10), (0x2A, 0x20), (0x3C, 0x30), (0x01, 0x00)] {
+            write_vram(&mut emulator, 0x3F00, &[color]);
+            write_vram(&mut emulator, 0x2000, &[]);
+            assert_eq!(backdrop_after_a_frame(&mut emulator, 0x00), rgb(&emulator, color));
+            assert_eq!(backdrop_after_a_frame(&mut emulator, 0x01), rgb(&emulator, expected));
+            // the same with the background shown
+            assert_eq!(backdrop_after_a_frame(&mut emulator, 0x09), rgb(&emulator, expected));
+        }
+    }
+
+    #[test]
+    fn reading_4015_clears_the_frame_irq_but_not_the_dmc_irq() {
+        let mut emulator = TestRom::nrom().emulator();
+        // a one byte sample with its irq enabled, and the 4-step frame counter irq
+        for (addr, value) in [(0x4010, 0x8F), (0x4012, 0x00), (0x4013, 0x00), (0x4015, 0x10), (0x4017, 0x00)] {
+            cpu::Context::poke(&mut emulator, addr, value);
+        }
+        emulator.run_for_one_frame();
+        emulator.run_for_one_frame();
+        assert_eq!(emulator.irq_sources(), IrqSources { apu_frame: true, dmc: true, mapper: false });
+
+        assert_eq!(cpu::Context::peek(&mut emulator, 0x4015) & 0xC0, 0xC0);
+        assert_eq!(emulator.irq_sources(), IrqSources { apu_frame: false, dmc: true, mapper: false });
+        cpu::Context::peek(&mut emulator, 0x0000);
+        assert!(emulator.nes.mos6502.irq);
+        assert_eq!(cpu::Context::peek(&mut emulator, 0x4015) & 0xC0, 0x80);
+
+        // writing $4015 acknowledges the dmc irq
+        cpu::Context::poke(&mut emulator, 0x4015, 0x00);
+        cpu::Context::peek(&mut emulator, 0x0000);
+        assert_eq!(emulator.irq_sources(), IrqSources::default());
+        assert!(!emulator.nes.mos6502.irq);
+    }
+
+    #[test]
+    fn reset_silences_the_channels_and_keeps_the_frame_counter_mode() {
+        let mut emulator = TestRom::nrom().emulator();
+        for (addr, value) in [(0x4015, 0x0F), (0x4003, 0x08), (0x4007, 0x08), (0x400B, 0x08), (0x400F, 0x08), (0x4017, 0x80)] {
+            cpu::Context::poke(&mut emulator, addr, value);
+        }
+        let debug = emulator.apu_debug_state();
+        assert!(debug.pulse1.enabled && debug.pulse2.enabled && debug.triangle.enabled && debug.noise.enabled);
+
+        emulator.reset();
+        let debug = emulator.apu_debug_state();
+        assert!(!debug.pulse1.enabled && !debug.pulse2.enabled && !debug.triangle.enabled && !debug.noise.enabled);
+        assert!(apu::Context::state(&emulator).frame.is_5_step());
+        // so the 4-step frame irq never comes
+        emulator.run_for_one_frame();
+        emulator.run_for_one_frame();
+        assert!(!emulator.irq_sources().apu_frame);
+    }
+
+    #[test]
+    fn rgb_ppu_models_render_their_own_palettes() {
+        let mut emulator = TestRom::nrom().emulator();
+        write_vram(&mut emulator, 0x3F00, &[0x00]);
+        write_vram(&mut emulator, 0x2000, &[]);
+        for (model, expected) in [
+            (ppu::PpuModel::Rp2C03, [109, 109, 109]),
+            (ppu::PpuModel::Rc2C05, [109, 109, 109]),
+            // color 0 is the 2C03's 0x35 on this one
+            (ppu::PpuModel::Rp2C04_0001, [255, 182, 182]),
+            // and a 2C04-only grey on this one
+            (ppu::PpuModel::Rp2C04_0002, [0, 0, 0]),
+        ] {
+            emulator.set_ppu_model(model);
+            assert_eq!(backdrop_after_a_frame(&mut emulator, 0x00), expected);
+        }
+
+        // emphasis turns a channel fully on
+        emulator.set_ppu_model(ppu::PpuModel::Rp2C03);
+        write_vram(&mut emulator, 0x3F00, &[0x0F]);
+        write_vram(&mut emulator, 0x2000, &[]);
+        assert_eq!(backdrop_after_a_frame(&mut emulator, 0x20), [255, 0, 0]);
+        assert_eq!(backdrop_after_a_frame(&mut emulator, 0xC0), [0, 255, 255]);
+
+        emulator.set_ppu_model(ppu::PpuModel::Rp2C02);
+        let rgb = emulator.get_palette_rgb(0x0F);
+        assert_eq!(backdrop_after_a_frame(&mut emulator, 0x00), [rgb.r, rgb.g, rgb.b]);
+    }
+
+    #[cfg(feature = "zip")]
+    fn zip_of(entries: &[(&str, &[u8])]) -> Cursor<Vec<u8>> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            writer.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        let mut archive = writer.finish().unwrap();
+        archive.set_position(0);
+        archive
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn zip_loads_its_only_nes_entry_or_the_named_one() {
+        let rom = TestRom::nrom().build();
+        let mut emulator = Emulator::new();
+        emulator.load_rom_from_zip_reader(zip_of(&[("readme.txt", b"hi"), ("Game.NES", &rom)]), None).unwrap();
+        assert!(emulator.is_rom_loaded());
+
+        let two_roms = [("b.nes", &rom[..]), ("a.nes", &rom[..])];
+        let mut emulator = Emulator::new();
+        match emulator.load_rom_from_zip_reader(zip_of(&two_roms), None) {
+            Err(LoadError::AmbiguousZipEntry(names)) => assert_eq!(names, ["a.nes", "b.nes"]),
+            _ => panic!("two .nes entries need a name"),
+        }
+        emulator.load_rom_from_zip_reader(zip_of(&two_roms), Some("b.nes")).unwrap();
+        assert!(emulator.is_rom_loaded());
+
+        assert!(matches!(Emulator::new().load_rom_from_zip_reader(zip_of(&[("readme.txt", b"hi")]), None), Err(LoadError::NotNesRom)));
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn gzipped_roms_and_savestates_round_trip() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&TestRom::nrom().build()).unwrap();
+        let mut emulator = Emulator::new();
+        emulator.load_rom_from_bytes(&encoder.finish().unwrap()).unwrap();
+        assert!(emulator.is_rom_loaded());
+
+        emulator.reset();
+        emulator.run_for_one_frame();
+        let state = emulator.save_state_compressed();
+        assert!(state.starts_with(&GZIP_MAGIC));
+        let cycle = emulator.get_cycle();
+        emulator.run_for_one_frame();
+        emulator.load_state_compressed(&state).unwrap();
+        assert_eq!(emulator.get_cycle(), cycle);
+        assert!(emulator.load_state_compressed(b"not gzip").is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn ppuaddr_writes_are_ignored_while_the_ppu_warms_up() {
+        let write_2100 = |emulator: &mut Emulator, value: u8| {
+            for (addr, value) in [(0x2006, 0x21), (0x2006, 0x00), (0x2007, value)] {
+                cpu::Context::poke(emulator, addr, value);
+            }
+        };
+        let mut emulator = TestRom::nrom().emulator();
+        write_2100(&mut emulator, 0x55);
+        assert_eq!(emulator.read_vram(0x2100), Some(0x00));
+
+        emulator.run_for_one_frame();
+        emulator.run_for_one_frame();
+        write_2100(&mut emulator, 0x55);
+        assert_eq!(emulator.read_vram(0x2100), Some(0x55));
+
+        // the famicom's reset doesn't reach the ppu, the nes's does
+        emulator.soft_reset();
+        write_2100(&mut emulator, 0x66);
+        assert_eq!(emulator.read_vram(0x2100), Some(0x66));
+        emulator.reset();
+        write_2100(&mut emulator, 0x77);
+        assert_eq!(emulator.read_vram(0x2100), Some(0x66));
+    }
+
+    #[test]
+    fn run_for_one_frame_does_nothing_while_paused() {
+        let mut emulator = TestRom::nrom().emulator();
+        write_vram(&mut emulator, 0x3F00, &[0x16]);
+        write_vram(&mut emulator, 0x2000, &[]);
+        emulator.run_for_one_frame();
+        let backdrop = |emulator: &Emulator| {
+            let rgb = emulator.get_framebuffer()[0];
+            [rgb.r, rgb.g, rgb.b]
+        };
+        let frame = backdrop(&emulator);
+        let cycle = emulator.get_cycle();
+
+        emulator.set_paused(true);
+        write_vram(&mut emulator, 0x3F00, &[0x2A]);
+        write_vram(&mut emulator, 0x2000, &[]);
+        emulator.run_for_one_frame();
+        assert_eq!(emulator.get_cycle(), cycle);
+        assert_eq!(backdrop(&emulator), frame);
+
+        emulator.set_paused(false);
+        emulator.run_for_one_frame();
+        assert!(emulator.get_cycle() > cycle);
+        assert_ne!(backdrop(&emulator), frame);
+    }
+
+    #[test]
+    fn frame_blending_mixes_the_copies_but_not_the_framebuffer() {
+        let mut emulator = TestRom::nrom().emulator();
+        let frame_with_backdrop = |emulator: &mut Emulator, color: u8| {
+            write_vram(emulator, 0x3F00, &[color]);
+            write_vram(emulator, 0x2000, &[]);
+            let framebuffer = backdrop_after_a_frame(emulator, 0x00);
+            let mut copy = vec![0; emulator.visible_width() * emulator.visible_height() * 3];
+            emulator.copy_framebuffer_into(&mut copy).unwrap();
+            (framebuffer, [copy[0], copy[1], copy[2]])
+        };
+        let white = frame_with_backdrop(&mut emulator, 0x30).0;
+        let black = frame_with_backdrop(&mut emulator, 0x0F).0;
+        let average = |previous: [u8; 3]| previous.map(|channel| channel / 2);
+
+        // blending starts with the frame already drawn
+        emulator.set_frame_blend(FrameBlend::Average);
+        assert_eq!(frame_with_backdrop(&mut emulator, 0x30), (white, average(white)));
+        assert_eq!(frame_with_backdrop(&mut emulator, 0x0F), (black, average(white)));
+        assert_eq!(frame_with_backdrop(&mut emulator, 0x0F), (black, black));
+
+        // phosphor fades what was shown, over more than one frame
+        let faded = |shown: [u8; 3]| shown.map(|channel| (channel as f32 * PHOSPHOR_DECAY) as u8);
+        emulator.set_frame_blend(FrameBlend::Phosphor);
+        assert_eq!(frame_with_backdrop(&mut emulator, 0x30), (white, white));
+        assert_eq!(frame_with_backdrop(&mut emulator, 0x0F), (black, faded(white)));
+        assert_eq!(frame_with_backdrop(&mut emulator, 0x0F), (black, faded(faded(white))));
+
+        emulator.set_frame_blend(FrameBlend::Off);
+        assert_eq!(frame_with_backdrop(&mut emulator, 0x30), (white, white));
+        assert_eq!(frame_with_backdrop(&mut emulator, 0x0F), (black, black));
+    }
+}
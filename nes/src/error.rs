@@ -3,12 +3,49 @@ use std::io;
 #[derive(Debug)]
 pub enum LoadError {
     NotNesRom,
+    UnexpectedEof,
     IoError(io::Error),
     UnsupportedMapper(u16),
+    FdsBiosNotFound,
+    // the board name from a UNIF MAPR chunk
+    UnifMapperUnknown(String),
+    // the .nes entries of a zip archive when more than one could be loaded
+    AmbiguousZipEntry(Vec<String>),
+}
+
+#[derive(Debug)]
+pub enum PaletteError {
+    InvalidSize(usize),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RunError {
+    // the call needs a cartridge to run
+    NoRom,
+}
+
+#[derive(Debug)]
+pub enum FramebufferError {
+    // the length the copy needs, in bytes, or in pixels for RGB565
+    BufferTooSmall(usize),
 }
 
 impl From<io::Error> for LoadError {
     fn from(e: io::Error) -> LoadError {
-        LoadError::IoError(e)
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => LoadError::UnexpectedEof,
+            _ => LoadError::IoError(e),
+        }
+    }
+}
+
+#[cfg(feature = "zip")]
+impl From<zip::result::ZipError> for LoadError {
+    fn from(e: zip::result::ZipError) -> LoadError {
+        match e {
+            zip::result::ZipError::Io(e) => e.into(),
+            zip::result::ZipError::FileNotFound => LoadError::IoError(io::Error::new(io::ErrorKind::NotFound, e)),
+            _ => LoadError::IoError(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
     }
-}
\ No newline at end of file
+}
@@ -11,6 +11,13 @@ mod cartridge;
 mod emulator;
 mod apu;
 mod dma;
+mod region;
+#[cfg(test)]
+mod test_rom;
 
-pub use emulator::{StandardInput, Emulator};
-pub use error::LoadError;
+pub use emulator::{StandardInput, Emulator, EmulatorConfig, ZapperState, InputMode, FourScoreType, FourScoreState, Player, TurboConfig, TestStatus, Overscan, FrameBlend, RamPattern, IrqSources, ScrollWindow, NametableScroll, FRAME_WIDTH, FRAME_HEIGHT, NAMETABLE_VIEW_WIDTH, NAMETABLE_VIEW_HEIGHT, PATTERN_TABLE_VIEW_WIDTH, PATTERN_TABLE_VIEW_HEIGHT};
+pub use region::Region;
+pub use apu::{AudioChannel, ApuDebug, ApuChannelDebug};
+pub use ppu::{RgbColor, PpuModel};
+pub use error::{LoadError, PaletteError, FramebufferError, RunError};
+pub use cartridge::{MirrorMode, NesHeader, NesVersion};
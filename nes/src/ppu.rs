@@ -1,10 +1,33 @@
 // #![allow(dead_code)]
 use super::bitmisc::{ U16Address, U8BitTest };
+use crate::region::Region;
+use crate::error::PaletteError;
 use serde::{Serialize, Deserialize};
 
 pub const SCREEN_SIZE: usize = 256 * 240;
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+const NTSC_VBLANK_SCANLINE: usize = 241;
+const NTSC_PRE_RENDER_SCANLINE: usize = 261;
+const DENDY_VBLANK_SCANLINE: usize = 291;
+const DENDY_PRE_RENDER_SCANLINE: usize = 311;
+const PAL_VBLANK_SCANLINE: usize = 241;
+const PAL_PRE_RENDER_SCANLINE: usize = 311;
+// cpu cycles after power on or reset before the ppu accepts writes to $2000, $2001, $2005 and $2006
+const WARMUP_CYCLES: u32 = 29658;
+// dots between the second $2006 write and v being copied from t
+const CURRENT_ADDR_UPDATE_DELAY: u8 = 3;
+// dots between a $2001 write and the new mask taking effect
+const PMASK_UPDATE_DELAY: u8 = 3;
+// frames until a bit of the register i/o latch that isn't refreshed fades to 0, about 600ms
+const IO_LATCH_DECAY_FRAMES: usize = 36;
+
+// $3F10/$3F14/$3F18/$3F1C are the same cells as $3F00/$3F04/$3F08/$3F0C
+pub fn palette_ram_index(address: u16) -> usize {
+    let index = (address & 0x1F) as usize;
+    if index & 0b1_0011 == 0b1_0000 { index & 0x0F } else { index }
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
 pub struct RgbColor {
     pub r: u8,
     pub g: u8,
@@ -15,25 +38,55 @@ impl RgbColor {
     pub fn new(r: u8, g: u8, b:u8) -> Self {
         RgbColor{ r, g, b }
     }
-
-    pub fn default() -> Self {
-        RgbColor::new(0, 0, 0)
-    }
 }
 
-#[derive(Serialize, Deserialize)]
+// 64 colors for each of the 8 emphasis combinations, indexed by `color | emphasis << 6`
+#[derive(Clone)]
 pub struct Palette(Vec<RgbColor>);
 impl Palette {
-    fn new(data: &[u8]) -> Self {
-        assert!(data.len() == 64*3);
-        let mut palette = [RgbColor::default(); 64];
+    // accepts plain 64 color .pal files, or 512 color ones that already include emphasis
+    pub fn new(data: &[u8]) -> Result<Self, PaletteError> {
+        if data.len() != 64*3 && data.len() != 512*3 {
+            return Err(PaletteError::InvalidSize(data.len()));
+        }
+        let mut palette = [RgbColor::default(); 512];
 
         for (index, rgb) in data.chunks(3).enumerate() {
             palette[index].r = rgb[0];
             palette[index].g = rgb[1];
             palette[index].b = rgb[2];
         }
-        Palette(palette.to_vec())
+        if data.len() == 64*3 {
+            for emphasis in 1..8 {
+                for index in 0..64 {
+                    palette[emphasis << 6 | index] = Palette::emphasize(palette[index], emphasis as u8);
+                }
+            }
+        }
+        Ok(Palette(palette.to_vec()))
+    }
+
+    pub fn default_palette() -> Self {
+        Palette::new(include_bytes!("./palette.pal")).unwrap()
+    }
+
+    fn emphasize(mut rgb: RgbColor, emphasis: u8) -> RgbColor {
+        if emphasis.is_b0_set() {
+            rgb.r = (rgb.r as f32 *1.1) as u8;
+            rgb.g = (rgb.g as f32 *0.9) as u8;
+            rgb.b = (rgb.b as f32 *0.9) as u8;
+        }
+        if emphasis.is_b1_set() {
+            rgb.r = (rgb.r as f32 *0.9) as u8;
+            rgb.g = (rgb.g as f32 *1.1) as u8;
+            rgb.b = (rgb.b as f32 *0.9) as u8;
+        }
+        if emphasis.is_b2_set() {
+            rgb.r = (rgb.r as f32 *0.9) as u8;
+            rgb.g = (rgb.g as f32 *0.9) as u8;
+            rgb.b = (rgb.b as f32 *1.1) as u8;
+        }
+        rgb
     }
 
     pub fn get_rgb(&self, index: usize) -> RgbColor {
@@ -41,6 +94,84 @@ impl Palette {
     }
 }
 
+// the Vs. System and PlayChoice boards use RGB ppus with fixed palettes, the 2C04s
+// also scramble the order of the colors, differently for each variant
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PpuModel {
+    #[default]
+    Rp2C02,
+    Rp2C03,
+    Rp2C04_0001,
+    Rp2C04_0002,
+    Rp2C04_0003,
+    Rp2C04_0004,
+    Rc2C05,
+}
+
+impl PpuModel {
+    // index into RGB_PPU_PALETTE, None for the 2C02 which uses the configurable palette
+    fn rgb_palette_index(self, index: usize) -> Option<usize> {
+        match self {
+            PpuModel::Rp2C02 => None,
+            PpuModel::Rp2C03 | PpuModel::Rc2C05 => Some(index),
+            PpuModel::Rp2C04_0001 => Some(RP2C04_ORDERINGS[0][index] as usize),
+            PpuModel::Rp2C04_0002 => Some(RP2C04_ORDERINGS[1][index] as usize),
+            PpuModel::Rp2C04_0003 => Some(RP2C04_ORDERINGS[2][index] as usize),
+            PpuModel::Rp2C04_0004 => Some(RP2C04_ORDERINGS[3][index] as usize),
+        }
+    }
+}
+
+// 2C03/2C05 colors, 3 bits each of red, green and blue
+const RGB_PPU_PALETTE: [u16; 64] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420, 0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630, 0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750, 0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772, 0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000,
+];
+
+// 2C04 color index to the 2C03 color it shows. The few greys and browns only the 2C04s
+// have aren't in the 2C03 palette and come out as 0x2E, black, instead of their real color
+const RP2C04_ORDERINGS: [[u8; 64]; 4] = [
+    [
+        0x35, 0x23, 0x16, 0x22, 0x1C, 0x09, 0x1D, 0x15, 0x20, 0x00, 0x27, 0x05, 0x04, 0x28, 0x08, 0x20,
+        0x21, 0x3E, 0x1F, 0x29, 0x3C, 0x32, 0x36, 0x12, 0x3F, 0x2B, 0x2E, 0x1E, 0x3D, 0x2D, 0x24, 0x01,
+        0x0E, 0x31, 0x33, 0x2A, 0x2C, 0x0C, 0x1B, 0x14, 0x2E, 0x07, 0x34, 0x06, 0x13, 0x02, 0x26, 0x2E,
+        0x2E, 0x19, 0x10, 0x0A, 0x39, 0x03, 0x37, 0x17, 0x0F, 0x11, 0x0B, 0x0D, 0x38, 0x25, 0x18, 0x3A,
+    ],
+    [
+        0x2E, 0x27, 0x18, 0x39, 0x3A, 0x25, 0x1C, 0x31, 0x16, 0x13, 0x38, 0x34, 0x20, 0x23, 0x3C, 0x0B,
+        0x0F, 0x21, 0x06, 0x3D, 0x1B, 0x29, 0x1E, 0x22, 0x1D, 0x24, 0x0E, 0x2B, 0x32, 0x08, 0x2E, 0x03,
+        0x04, 0x36, 0x26, 0x33, 0x11, 0x1F, 0x10, 0x02, 0x14, 0x3F, 0x00, 0x09, 0x12, 0x2E, 0x28, 0x20,
+        0x3E, 0x0D, 0x2A, 0x17, 0x0C, 0x01, 0x15, 0x19, 0x2E, 0x2C, 0x07, 0x37, 0x35, 0x05, 0x0A, 0x2D,
+    ],
+    [
+        0x14, 0x25, 0x3A, 0x10, 0x0B, 0x20, 0x31, 0x09, 0x01, 0x2E, 0x36, 0x08, 0x15, 0x3D, 0x3E, 0x3C,
+        0x22, 0x1C, 0x05, 0x12, 0x19, 0x18, 0x17, 0x1B, 0x00, 0x03, 0x2E, 0x02, 0x16, 0x06, 0x34, 0x35,
+        0x23, 0x0F, 0x0E, 0x37, 0x0D, 0x27, 0x26, 0x20, 0x29, 0x04, 0x21, 0x24, 0x11, 0x2D, 0x2E, 0x1F,
+        0x2C, 0x1E, 0x39, 0x33, 0x07, 0x2A, 0x28, 0x1D, 0x0A, 0x2E, 0x32, 0x38, 0x13, 0x2B, 0x3F, 0x0C,
+    ],
+    [
+        0x18, 0x03, 0x1C, 0x28, 0x2E, 0x35, 0x01, 0x17, 0x10, 0x1F, 0x2A, 0x0E, 0x36, 0x37, 0x0B, 0x39,
+        0x25, 0x1E, 0x12, 0x34, 0x2E, 0x1D, 0x06, 0x26, 0x3E, 0x1B, 0x22, 0x19, 0x04, 0x2E, 0x3A, 0x21,
+        0x05, 0x0A, 0x07, 0x02, 0x13, 0x14, 0x00, 0x15, 0x0C, 0x3D, 0x11, 0x0F, 0x0D, 0x38, 0x2D, 0x24,
+        0x33, 0x20, 0x08, 0x16, 0x3F, 0x2B, 0x20, 0x3C, 0x2E, 0x27, 0x23, 0x31, 0x29, 0x32, 0x2C, 0x09,
+    ],
+];
+
+// on RGB ppus each emphasis bit turns its channel fully on, instead of dimming the other two
+fn rgb_ppu_color(index: usize, emphasis: u8) -> RgbColor {
+    let color = RGB_PPU_PALETTE[index];
+    let channel = |shift: u16, emphasized: bool| {
+        if emphasized {
+            0xFF
+        } else {
+            (((color >> shift) & 0b111) * 255 / 7) as u8
+        }
+    };
+    RgbColor::new(channel(6, emphasis.is_b0_set()), channel(3, emphasis.is_b1_set()), channel(0, emphasis.is_b2_set()))
+}
+
 #[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct PpuAddr(u16);
 impl PpuAddr {
@@ -93,6 +224,13 @@ impl PpuAddr {
         (self.0 & 0b0_000_11_00000_00000) >> 10
     }
 
+    // pixel position in the 512x480 space of the four nametables
+    pub fn get_nametable_pixel(&self, fine_x: u8) -> (usize, usize) {
+        let x = (self.get_nn() & 1) * 256 + self.get_corase_x() * 8 + fine_x as u16;
+        let y = (self.get_nn() >> 1) * 240 + self.get_corase_y() * 8 + self.get_fine_y();
+        (x as usize, y as usize)
+    }
+
     #[inline]
     pub fn get_tile_address(&self) -> u16 {
         0x2000 | (self.0 & 0b0_000_11_11111_11111)
@@ -189,7 +327,7 @@ impl PpuAddr {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PCtrl(u8);
 impl PCtrl {
     pub fn new(v: u8) -> Self {
@@ -248,7 +386,7 @@ impl PCtrl {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PMask(u8);
 impl PMask {
     pub fn new(v: u8) -> Self {
@@ -275,24 +413,13 @@ impl PMask {
         self.0 & (1 << 4) != 0
     }
 
-    pub fn emphasize_red(&self) -> bool {
-        self.0 & (1 << 5) != 0
-    }
-
-    pub fn emphasize_green(&self) -> bool {
-        self.0 & (1 << 6) != 0
-    }
-
-    pub fn emphasize_blue(&self) -> bool {
-        self.0 & (1 << 7) != 0
-    }
-
+    // red, green and blue emphasis in bits 0, 1 and 2
     pub fn emphasize_bits(&self) -> u8 {
         (self.0 >> 5) & 0b111
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PStatus(u8);
 impl PStatus {
     pub fn new(v: u8) -> Self {
@@ -339,9 +466,19 @@ impl PStatus {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum SpriteEvaluationState {
-    Idle, Copy, Search,
+    Idle, Copy, Search, Overflow,
+}
+
+// the ppu's /NMI output is (vblank flag && nmi enable), the cpu gets the nmi one dot after
+// it goes active, so a $2002 read or a $2000 write in between still cancels it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum NmiState {
+    Inactive,
+    Rising,
+    // signalled, waits for the output to go inactive before it can fire again
+    Triggered,
 }
 
 #[derive(Clone, Copy, Serialize, Deserialize)]
@@ -389,11 +526,12 @@ impl Sprite {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct State {
     frame_buffer: Vec<RgbColor>,
+    // palette index in bits 0-5 and emphasis bits in bits 6-8
+    frame_buffer_indexed: Vec<u16>,
     frame_buffer_cursor: usize,
-    pub palette: Palette,
 
     n_dot: usize,
     n_scanline: usize,
@@ -445,22 +583,39 @@ pub struct State {
     attribute_shift_lo: u16,
     attribute_shift_hi: u16,
 
-    nmi_already_triggered: bool,
-    nmi_ready_to_trigger: bool,
+    nmi_state: NmiState,
 
     skip_one_tick: bool,
 
+    // set by a $2002 read, stops the vblank flag being set on the following dot
     vblank_suppress_flag: bool,
 
+    warmup_cycles_remaining: u32,
+
+    // the second $2006 write reaches v a few dots late, so during rendering the
+    // dot's own increments happen first and are then overwritten
+    current_addr_update_delay: u8,
+
+    // a $2001 write waiting to take effect, turning rendering on or off mid-scanline
+    // shows up a few pixels late
+    pending_pmask: u8,
+    pmask_update_delay: u8,
+
+    // open bus of $2000-$2007, the value last driven onto the ppu's data bus
+    io_latch: u8,
+    // frame each latch bit was last driven, bits decay separately
+    io_latch_refresh_frame: [usize; 8],
+    frame_count: usize,
+
+    model: PpuModel,
 }
 
 impl State {
     pub fn new() -> Self {
-        let palette_bytes = include_bytes!("./palette.pal");
         State {
             frame_buffer: [RgbColor::new(0, 0, 0); SCREEN_SIZE].to_vec(),
+            frame_buffer_indexed: [0; SCREEN_SIZE].to_vec(),
             frame_buffer_cursor: 0,
-            palette: Palette::new(palette_bytes),
             n_dot: 0,
             n_scanline: 261,
             pctrl: PCtrl::new(0),
@@ -497,10 +652,17 @@ impl State {
             background_shift_hi: 0,
             attribute_shift_lo: 0,
             attribute_shift_hi: 0,
-            nmi_already_triggered: false,
+            nmi_state: NmiState::Inactive,
             skip_one_tick: false,
             vblank_suppress_flag: false,
-            nmi_ready_to_trigger: false,
+            warmup_cycles_remaining: WARMUP_CYCLES,
+            current_addr_update_delay: 0,
+            pending_pmask: 0,
+            pmask_update_delay: 0,
+            io_latch: 0,
+            io_latch_refresh_frame: [0; 8],
+            frame_count: 0,
+            model: PpuModel::Rp2C02,
         }
     }
 }
@@ -512,7 +674,11 @@ pub trait Context: Sized {
     fn state_mut(&mut self) -> &mut State;
     fn trigger_nmi(&mut self);
     fn generate_frame(&mut self);
-    fn irq_scanline(&mut self);
+    fn on_scanline_start(&mut self, scanline: usize);
+    fn region(&self) -> Region;
+    fn is_indexed_output_enabled(&self) -> bool;
+    fn is_oam_corruption_enabled(&self) -> bool;
+    fn palette(&self) -> &Palette;
 }
 
 pub trait Interface: Sized + Context {
@@ -524,16 +690,77 @@ pub trait Interface: Sized + Context {
         &self.state().frame_buffer
     }
 
+    fn get_framebuffer_indexed(&self) -> &Vec<u16> {
+        &self.state().frame_buffer_indexed
+    }
+
+    fn get_beam_position(&self) -> (usize, usize) {
+        (self.state().n_scanline, self.state().n_dot)
+    }
+
+    fn get_bg_pattern_table_addr(&self) -> u16 {
+        self.state().pctrl.bg_pattern_table_addr()
+    }
+
+    // where v and t point in the nametables, in pixels
+    fn get_scroll_positions(&self) -> ((usize, usize), (usize, usize)) {
+        let fine_x = self.state().fine_x;
+        (self.state().current_addr.get_nametable_pixel(fine_x), self.state().temporary_addr.get_nametable_pixel(fine_x))
+    }
+
     fn write_ppuctrl(&mut self, value: u8) {
         Private::write_ppuctrl(self, value);
     }
 
+    fn on_cpu_cycle(&mut self) {
+        let remaining = self.state().warmup_cycles_remaining;
+        self.state_mut().warmup_cycles_remaining = remaining.saturating_sub(1);
+    }
+
+    fn end_warmup(&mut self) {
+        self.state_mut().warmup_cycles_remaining = 0;
+    }
+
+    // the reset button: on the NES it also resets the ppu, clearing $2000, $2001, the scroll,
+    // the write toggle and the read buffer, and the warm-up starts over. Power on is State::new
+    fn reset(&mut self) {
+        self.state_mut().pctrl.0 = 0;
+        self.state_mut().pmask.0 = 0;
+        self.state_mut().pmask_update_delay = 0;
+        self.state_mut().temporary_addr.0 = 0;
+        self.state_mut().fine_x = 0;
+        self.state_mut().write_toggle = false;
+        self.state_mut().ppudata_latch = 0;
+        self.state_mut().warmup_cycles_remaining = WARMUP_CYCLES;
+    }
+
+    fn get_model(&self) -> PpuModel {
+        self.state().model
+    }
+
+    fn set_model(&mut self, model: PpuModel) {
+        self.state_mut().model = model;
+    }
+
     fn write_ppumask(&mut self, value: u8) {
         Private::write_ppumask(self, value);
     }
 
+    // reads of the write-only registers
+    fn read_io_latch(&mut self) -> u8 {
+        Private::read_io_latch(self)
+    }
+
+    // every register write drives the whole byte
+    fn write_io_latch(&mut self, value: u8) {
+        self.refresh_io_latch(value, 0xFF);
+    }
+
     fn read_ppustatus(&mut self) -> u8 {
-        Private::read_ppustatus(self)
+        // only the top 3 bits are driven, the rest is open bus
+        let value = (Private::read_ppustatus(self) & 0xE0) | (Private::read_io_latch(self) & 0x1F);
+        self.refresh_io_latch(value, 0xE0);
+        value
     }
 
     fn write_oamaddr(&mut self, value: u8) {
@@ -541,13 +768,20 @@ pub trait Interface: Sized + Context {
     }
 
     fn read_oamdata(&mut self) -> u8 {
-        Private::read_oamdata(self)
+        let value = Private::read_oamdata(self);
+        self.refresh_io_latch(value, 0xFF);
+        value
     }
 
     fn write_oamdata(&mut self, value: u8) {
         Private::write_oamdata(self, value);
     }
 
+    fn write_oamdata_from_dma(&mut self, offset: usize, value: u8) {
+        let index = (offset + self.state().oamaddr) & 0xFF;
+        Private::store_oam(self, index, value);
+    }
+
     fn write_ppuscroll(&mut self, value: u8) {
         Private::write_ppuscroll(self, value);
     }
@@ -571,6 +805,26 @@ trait Private: Sized + Context {
     fn tick(&mut self) {
         self.try_to_trigger_nmi();
 
+        if self.state().current_addr_update_delay > 0 {
+            self.state_mut().current_addr_update_delay -= 1;
+            if self.state().current_addr_update_delay == 0 {
+                self.state_mut().current_addr.0 = self.state().temporary_addr.0;
+            }
+        }
+
+        if self.state().pmask_update_delay > 0 {
+            self.state_mut().pmask_update_delay -= 1;
+            if self.state().pmask_update_delay == 0 {
+                self.state_mut().pmask.0 = self.state().pending_pmask;
+            }
+        }
+
+        if self.state().n_dot == 0 {
+            self.on_scanline_start(self.state().n_scanline);
+        }
+
+        let vblank_scanline = self.vblank_scanline();
+        let pre_render_scanline = self.pre_render_scanline();
         match (self.state().n_scanline, self.state().n_dot) {
             (0, 0) => {
                 self.state_mut().sprite_0_on_current_scanline = self.state().sprite_0_on_next_scanline;
@@ -592,49 +846,39 @@ trait Private: Sized + Context {
                 self.draw_pixel();
                 self.prepare_render_data();
             }
-            (0..=239, 260) => {
-                if self.is_rendering() {
-                    self.irq_scanline();
-                }
-                self.prepare_render_data();
-            }
             (0..=239, _) => {
                 self.prepare_render_data();
             }
-            (241, 1) => {
+            (n, 1) if n == vblank_scanline => {
                 self.state_mut().frame_buffer_cursor = 0;
                 if !self.state_mut().vblank_suppress_flag {
                     self.state_mut().pstatus.set_vblank_occured(true);
                 }
                 self.generate_frame();
             }
-            (260, 340) => {
+            (n, 340) if n == pre_render_scanline - 1 => {
                 self.state_mut().is_odd_frame = !self.state().is_odd_frame;
+                self.state_mut().frame_count += 1;
             }
-            (261, 1) => {
+            (n, 1) if n == pre_render_scanline => {
                 self.state_mut().pstatus.set_vblank_occured(false);
                 self.state_mut().pstatus.set_sprite_overflow(false);
                 self.state_mut().pstatus.set_sprite_0_hit(false);
-                self.state_mut().nmi_already_triggered = false;
+                self.corrupt_oam_on_render_start();
                 self.prepare_render_data();
             }
-            (261, 260) => {
-                if self.is_rendering() {
-                    self.irq_scanline();
-                }
-                self.prepare_render_data();
-            }
-            (261, _) => {
+            (n, _) if n == pre_render_scanline => {
                 self.prepare_render_data();
             }
             (_, _) => {}
         }
 
         match (self.state().n_scanline, self.state().n_dot) {
-            (261, 340) => {
+            (n, 340) if n == pre_render_scanline => {
                 self.state_mut().n_scanline = 0;
                 self.state_mut().n_dot = 0;
-                if self.state().is_odd_frame && self.state().pmask.show_background() {
+                let skip_odd_frame = self.region() == Region::Ntsc;
+                if skip_odd_frame && self.state().is_odd_frame && self.state().pmask.show_background() {
                     self.state_mut().skip_one_tick = true;
                 }
             }
@@ -655,9 +899,11 @@ trait Private: Sized + Context {
 
         // shift registers and sprite evaluation
         match n_dot {
+            // this scanline's slots were fetched on the previous one, so the count can start over
             1 => {
                 self.shift_sprite_registers();
                 self.shift_background_registers();
+                self.state_mut().sprite_nums_on_next_scanline = 0;
                 self.tick_clear_secondary_oam()
             }
             2..=64 => {
@@ -665,27 +911,35 @@ trait Private: Sized + Context {
                 self.shift_background_registers();
                 self.tick_clear_secondary_oam() 
             }
-            65 => {
+            // the pre-render scanline clears secondary oam but evaluates nothing, so the slots
+            // fetched for scanline 0 are all empty and no sprite shows there
+            65 if n_scanline != self.pre_render_scanline() => {
                 self.shift_sprite_registers();
                 self.shift_background_registers();
                 self.state_mut().sprite_evaluation_state = SpriteEvaluationState::Search;
                 self.state_mut().secondary_oam_cursor = 0;
                 self.state_mut().primary_oam_cursor = self.state().oamaddr;
-                self.state_mut().sprite_nums_on_next_scanline = 0;
                 self.tick_sprite_evaluation()
             }
-            66..=256 => {
+            66..=256 if n_scanline != self.pre_render_scanline() => {
                 self.shift_sprite_registers();
                 self.shift_background_registers();
                 self.tick_sprite_evaluation() 
             }
+            65..=256 => {
+                self.shift_sprite_registers();
+                self.shift_background_registers();
+            }
             321..=336 => {
                 self.shift_background_registers();
             }
             _ => {}
         }
 
-        // fetch tiles and set registers
+        // fetch tiles and set registers, the bus stays idle while rendering is off
+        if !self.is_rendering() {
+            return;
+        }
         match n_dot {
             1 | 321 => {
                 self.bg_latch_tile_index_addr();
@@ -714,19 +968,24 @@ trait Private: Sized + Context {
                 self.v_scroll();
             }
             257 => {
-                // self.state_mut().oamaddr = 0;
+                self.state_mut().oamaddr = 0;
                 self.h_update();
                 self.state_mut().secondary_oam_cursor = 0;
                 self.state_mut().sprite_list_cursor = 0;
                 self.sp_latch_y();
             }
+            // all 8 slots fetch, empty ones use tile $FF from secondary oam, so the A12 pattern is
+            // the same for any number of sprites. The garbage nametable reads keep A12 low for a
+            // couple of dots between slots, too short for the MMC3 filter, which sees one rise per
+            // scanline, at dot 262 when sprites use $1000 and the background $0000. The other way
+            // round it rises at dot 6 of every background tile fetch instead
             258..=320 => {
-                // self.state_mut().oamaddr = 0;
+                self.state_mut().oamaddr = 0;
                 match n_dot & 0b111 {
                     1 => { self.sp_latch_y() }
-                    2 => { self.sp_latch_tile_addr() }
+                    2 => { self.sp_fetch_garbage_nametable(); self.sp_latch_tile_addr() }
                     3 => { self.sp_latch_attribute() }
-                    4 => { self.sp_set_position() }
+                    4 => { self.sp_fetch_garbage_nametable(); self.sp_set_position() }
                     5 => { self.sp_fetch_tile_lo_addr() }
                     6 => { self.sp_set_lo_shift() }
                     7 => { self.sp_fetch_tile_hi_addr() }
@@ -740,31 +999,68 @@ trait Private: Sized + Context {
             340 => { self.bg_latch_tile_index(); }
             _ => {}
         }
-        if n_scanline == 261 && (280..=304).contains(&n_dot) {
+        if n_scanline == self.pre_render_scanline() && (280..=304).contains(&n_dot) {
             self.v_update()
         }
     }
 
-    fn try_to_trigger_nmi(&mut self) {
-        if self.state().pstatus.vblank_occured() && self.state().pctrl.nmi_output() {
-            if !self.state().nmi_already_triggered {
-                if self.state().nmi_ready_to_trigger {
-                    self.trigger_nmi();
-                    self.state_mut().nmi_ready_to_trigger = false;
-                    self.state_mut().nmi_already_triggered = true;
-                }
-                else {
-                    self.state_mut().nmi_ready_to_trigger = true;
-                }
+    fn vblank_scanline(&self) -> usize {
+        match self.region() {
+            Region::Ntsc => NTSC_VBLANK_SCANLINE,
+            Region::Pal => PAL_VBLANK_SCANLINE,
+            Region::Dendy => DENDY_VBLANK_SCANLINE,
+        }
+    }
+
+    // 2C02 only: if OAMADDR is 8 or more when rendering starts, the 8 bytes at OAMADDR & 0xF8
+    // get copied over the first 8 bytes of OAM
+    fn corrupt_oam_on_render_start(&mut self) {
+        let oamaddr = self.state().oamaddr;
+        if self.is_oam_corruption_enabled() && self.is_rendering() && oamaddr >= 8 && self.region() != Region::Pal {
+            let base = oamaddr & 0xF8;
+            for i in 0..8 {
+                self.state_mut().oamdata[i] = self.state().oamdata[base + i];
             }
         }
-        else {
-            self.state_mut().nmi_already_triggered = false;
+    }
+
+    fn pre_render_scanline(&self) -> usize {
+        match self.region() {
+            Region::Ntsc => NTSC_PRE_RENDER_SCANLINE,
+            Region::Pal => PAL_PRE_RENDER_SCANLINE,
+            Region::Dendy => DENDY_PRE_RENDER_SCANLINE,
         }
     }
 
+    fn try_to_trigger_nmi(&mut self) {
+        let output = self.state().pstatus.vblank_occured() && self.state().pctrl.nmi_output();
+        let nmi_state = match (self.state().nmi_state, output) {
+            (_, false) => NmiState::Inactive,
+            (NmiState::Inactive, true) => NmiState::Rising,
+            (NmiState::Rising, true) => {
+                self.trigger_nmi();
+                NmiState::Triggered
+            }
+            (NmiState::Triggered, true) => NmiState::Triggered,
+        };
+        self.state_mut().nmi_state = nmi_state;
+    }
+
+    // pixel x is drawn on dot x + 1
+    fn pixel_x(&self) -> usize {
+        self.state().n_dot - 1
+    }
+
+    // the left mask covers exactly x = 0..=7, i.e. dots 1..=8
+    fn is_in_leftmost_8_pixels(&self) -> bool {
+        self.pixel_x() < 8
+    }
+
+    // only the first opaque sprite takes part in priority, even when it's behind the background.
+    // A behind-background sprite over a later front sprite shows the background through both,
+    // which games use to hide sprites behind a masking sprite (items rising out of blocks in SMB3)
     fn pixel_sprite(&self) -> (u8, u8, bool, bool) {
-        if self.state().pmask.show_sprites() && (self.state().pmask.show_sprite_in_leftmost_8_pixels() || self.state().n_dot > 8) {
+        if self.state().pmask.show_sprites() && (self.state().pmask.show_sprite_in_leftmost_8_pixels() || !self.is_in_leftmost_8_pixels()) {
             for (nth, sprite) in self.state().sprite_list.iter().enumerate() {
                 if sprite.countdown != 0 { continue; }
 
@@ -783,7 +1079,7 @@ trait Private: Sized + Context {
     }
 
     fn pixel_background(&self) -> (u8, u8) {
-        if self.state().pmask.show_background() && (self.state().pmask.show_background_in_leftmost_8_pixels() || self.state().n_dot > 8) {
+        if self.state().pmask.show_background() && (self.state().pmask.show_background_in_leftmost_8_pixels() || !self.is_in_leftmost_8_pixels()) {
             let shift = (7 - self.state().fine_x) + 8;
             let pattern_lo = (self.state().background_shift_lo >> shift) & 1;
             let pattern_hi = (self.state().background_shift_hi >> shift) & 1;
@@ -804,8 +1100,10 @@ trait Private: Sized + Context {
 
         let (sp_color_set_index, sp_color_index, sp_behind_background, is_sprite_0) = self.pixel_sprite();
         let (bg_color_set_index, bg_color_index) = self.pixel_background();
-        
-        if self.state().sprite_0_on_current_scanline && sp_color_index != 0 && bg_color_index != 0 && is_sprite_0 && self.state().n_dot != 256 {
+
+        // both pixels are already clipped by the leftmost 8 pixels masks,
+        // and sprite 0 hit never happens at x = 255
+        if self.state().sprite_0_on_current_scanline && sp_color_index != 0 && bg_color_index != 0 && is_sprite_0 && self.pixel_x() != 255 {
             self.state_mut().pstatus.set_sprite_0_hit(true);
         }
 
@@ -816,37 +1114,33 @@ trait Private: Sized + Context {
             (_, _, false) => (sp_color_set_index << 2) | sp_color_index,
             (_, _, true) => (bg_color_set_index << 2) | bg_color_index,
         } as u16;
+        // with rendering off the backdrop comes from wherever v points when it's inside palette ram,
+        // so forced blank mid-frame shows the palette entries being written
+        let v = self.state().current_addr.0 & 0x3FFF;
+        let palette_ram_index = if !self.is_rendering() && v >= 0x3F00 { v & 0x1F } else { palette_ram_index };
+
+        let mut palette_index = self.load(0x3F00 | palette_ram_index) as usize;
+        // greyscale keeps only the brightness column of the palette
+        if self.state().pmask.greyscale_mode() {
+            palette_index &= 0x30;
+        }
 
-        let palette_index = self.load(0x3F00 | palette_ram_index) as usize;
-
-        // let emphasized_palette_index = (palette_index | (self.state().pmask.emphasize_bits() << 6)) as usize;
-        let mut rgb = self.state().palette.get_rgb(palette_index);
+        let emphasis = self.state().pmask.emphasize_bits();
+        let emphasized_palette_index = palette_index | ((emphasis as usize) << 6);
+        let rgb = match self.state().model.rgb_palette_index(palette_index) {
+            Some(rgb_index) => rgb_ppu_color(rgb_index, emphasis),
+            None => self.palette().get_rgb(emphasized_palette_index),
+        };
 
-        if self.state().pmask.emphasize_red() {
-            rgb.r = (rgb.r as f32 *1.1) as u8;
-            rgb.g = (rgb.g as f32 *0.9) as u8;
-            rgb.b = (rgb.b as f32 *0.9) as u8;
-        }
-        if self.state().pmask.emphasize_green() {
-            rgb.r = (rgb.r as f32 *0.9) as u8;
-            rgb.g = (rgb.g as f32 *1.1) as u8;
-            rgb.b = (rgb.b as f32 *0.9) as u8;
-        }
-        if self.state().pmask.emphasize_blue() {
-            rgb.r = (rgb.r as f32 *0.9) as u8;
-            rgb.g = (rgb.g as f32 *0.9) as u8;
-            rgb.b = (rgb.b as f32 *1.1) as u8;
-        }
-        
         let index = self.state().frame_buffer_cursor;
         self.state_mut().frame_buffer[index] = rgb;
+        if self.is_indexed_output_enabled() {
+            self.state_mut().frame_buffer_indexed[index] = emphasized_palette_index as u16;
+        }
         self.state_mut().frame_buffer_cursor += 1;
     }
 
     fn tick_clear_secondary_oam(&mut self) {
-        if self.state().n_scanline == 261 {
-            return;
-        }
         let index = self.state().secondary_oam_cursor;
         self.state_mut().secondary_oam[index] = 0xff;
         self.state_mut().secondary_oam_cursor = (index + 1) % 32;
@@ -864,25 +1158,12 @@ trait Private: Sized + Context {
         let index = self.state().secondary_oam_cursor;
         match self.state().sprite_evaluation_state {
             SpriteEvaluationState::Search => {
-                let sprite_top = value as usize;
-                let sprite_bottom = sprite_top + self.state().pctrl.sprite_length();
-                let scanline_y = self.state().n_scanline;
-                let scanline_hit_sprite = (sprite_top <= scanline_y) && (scanline_y < sprite_bottom) && sprite_top != 255;
-                
-                if scanline_hit_sprite {
+                if self.is_sprite_on_scanline(value) {
                     if self.state().primary_oam_cursor == 1 {
                         self.state_mut().sprite_0_on_next_scanline = self.state().secondary_oam_cursor == 0;
                     }
-                    if self.state().sprite_nums_on_next_scanline >= 8 {
-                        if self.state().pmask.show_background() || self.state().pmask.show_sprites() {
-                            self.state_mut().pstatus.set_sprite_overflow(true);
-                        }
-                        self.state_mut().sprite_evaluation_state = SpriteEvaluationState::Idle;
-                    }
-                    else {
-                        self.state_mut().secondary_oam_cursor = index + 1;
-                        self.state_mut().sprite_evaluation_state = SpriteEvaluationState::Copy;
-                    } 
+                    self.state_mut().secondary_oam_cursor = index + 1;
+                    self.state_mut().sprite_evaluation_state = SpriteEvaluationState::Copy;
                 }
                 else {
                     self.state_mut().primary_oam_cursor += 3;
@@ -890,23 +1171,53 @@ trait Private: Sized + Context {
                         self.state_mut().sprite_evaluation_state = SpriteEvaluationState::Idle;
                     }
                 }
-
-                if self.state().sprite_nums_on_next_scanline < 8 {
-                    self.state_mut().secondary_oam[index] = value;
-                }
+                self.state_mut().secondary_oam[index] = value;
             },
             SpriteEvaluationState::Copy => {
                 self.state_mut().secondary_oam[index] = value;
                 self.state_mut().secondary_oam_cursor = index + 1;
                 if (index + 1) & 0b11 == 0 {
                     self.state_mut().sprite_nums_on_next_scanline += 1;
-                    self.state_mut().sprite_evaluation_state = SpriteEvaluationState::Search;
+                    self.state_mut().sprite_evaluation_state = if self.state().primary_oam_cursor >= 256 {
+                        SpriteEvaluationState::Idle
+                    } else if self.state().sprite_nums_on_next_scanline >= 8 {
+                        SpriteEvaluationState::Overflow
+                    } else {
+                        SpriteEvaluationState::Search
+                    };
+                }
+            }
+            SpriteEvaluationState::Overflow => {
+                if self.is_sprite_on_scanline(value) {
+                    if self.is_rendering() {
+                        self.state_mut().pstatus.set_sprite_overflow(true);
+                    }
+                    self.state_mut().sprite_evaluation_state = SpriteEvaluationState::Idle;
+                }
+                else {
+                    // hardware bug: once 8 sprites are found, a miss increments both
+                    // the sprite index n and the byte index m (without carry), so the
+                    // overflow search walks diagonally through OAM
+                    let m = (self.state().primary_oam_cursor - 1) & 0b11;
+                    if m != 0b11 {
+                        self.state_mut().primary_oam_cursor += 4;
+                    }
+                    if self.state().primary_oam_cursor >= 256 {
+                        self.state_mut().sprite_evaluation_state = SpriteEvaluationState::Idle;
+                    }
                 }
             }
             SpriteEvaluationState::Idle => {}
         }
     }
 
+    fn is_sprite_on_scanline(&self, sprite_y: u8) -> bool {
+        let sprite_top = sprite_y as usize;
+        let sprite_bottom = sprite_top + self.state().pctrl.sprite_length();
+        let scanline_y = self.state().n_scanline;
+        (sprite_top <= scanline_y) && (scanline_y < sprite_bottom) && sprite_top != 255
+    }
+
     #[inline]
     fn sp_latch_y(&mut self) {
         let value = self.state().secondary_oam[self.state().secondary_oam_cursor];
@@ -939,6 +1250,13 @@ trait Private: Sized + Context {
         self.state_mut().sprite_list[sprite_index].set_pos(x, y)
     }
 
+    // the sprite slots reuse the background's nametable fetches and throw the bytes away
+    #[inline]
+    fn sp_fetch_garbage_nametable(&mut self) {
+        let addr = self.state().current_addr.get_tile_address();
+        self.load(addr);
+    }
+
     #[inline]
     fn sp_fetch_tile_lo_addr(&mut self) {
         let lo = self.sprite_tile_lo_addr().fetch_lo();
@@ -949,9 +1267,8 @@ trait Private: Sized + Context {
     fn sp_set_lo_shift(&mut self) {
         let hi = self.sprite_tile_lo_addr().fetch_hi();
         self.state_mut().address_latch.set_hi(hi);
-        let mut value = self.load(self.state().address_latch);
-        let flip_horizontally = self.state().sprite_attribute_latch.is_b6_set();
-        if flip_horizontally { value = value.reverse_bits() }
+        let value = self.load(self.state().address_latch);
+        let value = self.sprite_pattern(value);
         let sprite_index = self.state().sprite_list_cursor;
         self.state_mut().sprite_list[sprite_index].set_lo_tile_shift(value);
     }
@@ -966,9 +1283,8 @@ trait Private: Sized + Context {
     fn sp_set_hi_shift(&mut self) {
         let hi = self.sprite_tile_hi_addr().fetch_hi();
         self.state_mut().address_latch.set_hi(hi);
-        let mut value = self.load(self.state().address_latch);
-        let flip_horizontally = self.state().sprite_attribute_latch.is_b6_set();
-        if flip_horizontally { value = value.reverse_bits() }
+        let value = self.load(self.state().address_latch);
+        let value = self.sprite_pattern(value);
         let sprite_index = self.state().sprite_list_cursor;
         self.state_mut().sprite_list[sprite_index].set_hi_tile_shift(value);
 
@@ -976,6 +1292,16 @@ trait Private: Sized + Context {
         self.state_mut().secondary_oam_cursor += 1;
     }
 
+    // empty slots still fetch (tile $FF) but their pattern is forced transparent, so a slot
+    // needs no x sentinel and a sprite at x = 255 still shows its first pixel
+    fn sprite_pattern(&self, value: u8) -> u8 {
+        if self.state().sprite_list_cursor >= self.state().sprite_nums_on_next_scanline {
+            return 0;
+        }
+        let flip_horizontally = self.state().sprite_attribute_latch.is_b6_set();
+        if flip_horizontally { value.reverse_bits() } else { value }
+    }
+
     fn sprite_tile_lo_addr(&self) -> u16 {
         let state = self.state();
         let filp_vertically = state.sprite_attribute_latch.is_b7_set();
@@ -1025,7 +1351,7 @@ trait Private: Sized + Context {
                 sprite.hi_tile_shift <<= 1;
                 sprite.lo_tile_shift <<= 1;
             }
-            else if sprite.countdown != 255 {
+            else {
                 sprite.countdown -= 1;
             }
         }
@@ -1114,13 +1440,11 @@ trait Private: Sized + Context {
         self.state_mut().attribute_shift_hi <<= 1;
     }
 
-    fn load(&mut self, mut address: u16) -> u8 {
+    fn load(&mut self, address: u16) -> u8 {
         if address < 0x3f00 {
             self.peek_vram(address)
         } else {
-            address &= 32 - 1;
-            debug_assert!((address as usize) < self.state_mut().palette_ram.len());
-            self.state().palette_ram[address as usize]
+            self.state().palette_ram[palette_ram_index(address)]
         }
     }
 
@@ -1150,25 +1474,29 @@ trait Private: Sized + Context {
     }
 
     fn increase_current_address(&mut self) {
+        let n_scanline = self.state().n_scanline;
+        if self.is_rendering() && (n_scanline < 240 || n_scanline == self.pre_render_scanline()) {
+            // $2007 accesses while rendering bump v with both of the rendering increments at once
+            self.state_mut().current_addr.increase_corase_x();
+            self.state_mut().current_addr.increase_fine_y();
+            return;
+        }
         let inc = self.state().pctrl.vram_addr_increment();
         let value = (self.state().current_addr.0 as usize + inc) & 0x7FFF;
-        // if self.state().current_addr.0 & 0x1000 == 0 && value & 0x1000 != 0 {
-        //     self.irq_scanline();
-        // }
         self.state_mut().current_addr.0 = value as u16;
     }
 
     fn write_ppuaddr(&mut self, value: u8) {
+        if self.is_warming_up() {
+            return;
+        }
         if self.state().write_toggle == false {
             self.state_mut().temporary_addr.set_high_byte(value);
             self.state_mut().write_toggle = true;
         }
         else {
             self.state_mut().temporary_addr.set_low_byte(value);
-            // if self.state().current_addr.0 & 0x1000 == 0 && self.state().temporary_addr.0 & 0x1000 != 0 {
-            //     self.irq_scanline();
-            // }
-            self.state_mut().current_addr.0 = self.state().temporary_addr.0;
+            self.state_mut().current_addr_update_delay = CURRENT_ADDR_UPDATE_DELAY;
             self.state_mut().write_toggle = false;
         }
     }
@@ -1181,17 +1509,44 @@ trait Private: Sized + Context {
         if addr < 0x3f00 {
             let old = self.state().ppudata_latch;
             self.state_mut().ppudata_latch = value;
+            self.refresh_io_latch(old, 0xFF);
             old
         }
         else {
+            // the read buffer gets the nametable byte underneath the palette
             self.state_mut().ppudata_latch = self.load(addr & 0x2fff);
+            // greyscale keeps only the brightness column, the same as for rendered pixels
             if self.state().pmask.greyscale_mode() {
-                value &= 0b110000;
+                value &= 0x30;
             }
+            // palette entries are 6 bits, the top 2 come from open bus
+            let value = (value & 0x3F) | (self.read_io_latch() & 0xC0);
+            self.refresh_io_latch(value, 0x3F);
             value
         }
     }
 
+    fn refresh_io_latch(&mut self, value: u8, mask: u8) {
+        let frame_count = self.state().frame_count;
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.state_mut().io_latch_refresh_frame[bit] = frame_count;
+            }
+        }
+        let latch = self.state().io_latch;
+        self.state_mut().io_latch = (latch & !mask) | (value & mask);
+    }
+
+    fn read_io_latch(&mut self) -> u8 {
+        let frame_count = self.state().frame_count;
+        for bit in 0..8 {
+            if frame_count - self.state().io_latch_refresh_frame[bit] >= IO_LATCH_DECAY_FRAMES {
+                self.state_mut().io_latch &= !(1 << bit);
+            }
+        }
+        self.state().io_latch
+    }
+
     fn write_ppudata(&mut self, value: u8) {
         let addr = self.state().current_addr.0 & 0x3FFF;
         self.store(addr, value);
@@ -1202,39 +1557,92 @@ trait Private: Sized + Context {
         self.state_mut().vblank_suppress_flag = true;
         let value = self.state().pstatus.0;
         self.state_mut().pstatus.set_vblank_occured(false);
-        self.state_mut().nmi_ready_to_trigger = false;
         self.state_mut().write_toggle = false;
         value
     }
 
+    fn is_warming_up(&self) -> bool {
+        self.state().warmup_cycles_remaining > 0
+    }
+
     fn write_ppuctrl(&mut self, value: u8) {
+        if self.is_warming_up() {
+            return;
+        }
         self.state_mut().pctrl.0 = value;
         let nn = self.state().pctrl.get_nn();
         self.state_mut().temporary_addr.set_nn(nn);
     }
 
     fn write_ppumask(&mut self, value: u8) {
-        self.state_mut().pmask.0 = value;
+        if self.is_warming_up() {
+            return;
+        }
+        // a write still waiting lands first
+        if self.state().pmask_update_delay > 0 {
+            self.state_mut().pmask.0 = self.state().pending_pmask;
+        }
+        self.state_mut().pending_pmask = value;
+        self.state_mut().pmask_update_delay = PMASK_UPDATE_DELAY;
     }
 
     fn read_oamdata(&mut self) -> u8 {
-        let index = self.state().oamaddr;
-        self.state().oamdata[index]
+        let n_scanline = self.state().n_scanline;
+        if self.is_rendering() && (n_scanline < 240 || n_scanline == self.pre_render_scanline()) {
+            // during rendering the PPU returns whatever the sprite logic is reading
+            match self.state().n_dot {
+                1..=64 => 0xFF,
+                65..=256 => self.state().primary_oam_latch,
+                257..=320 => {
+                    let index = self.state().secondary_oam_cursor.min(4 * 8 - 1);
+                    self.state().secondary_oam[index]
+                }
+                _ => self.state().secondary_oam[0],
+            }
+        }
+        else {
+            self.state().oamdata[self.state().oamaddr]
+        }
+    }
+
+    fn store_oam(&mut self, index: usize, value: u8) {
+        // bits 2-4 of the attribute byte are unimplemented and always read back as 0,
+        // both from $2004 and from sprite evaluation
+        let value = if index & 0b11 == 2 { value & 0xE3 } else { value };
+        self.state_mut().oamdata[index] = value;
     }
 
     fn write_oamdata(&mut self, value: u8) {
         let index = self.state().oamaddr;
-        if !(self.state().n_scanline < 240 && self.state().n_scanline == 261) {
-            self.state_mut().oamdata[index] = value;
+        let n_scanline = self.state().n_scanline;
+        if self.is_rendering() && (n_scanline < 240 || n_scanline == self.pre_render_scanline()) {
+            // the write is dropped, but the glitchy increment bumps the sprite index (the high 6 bits)
+            self.state_mut().oamaddr = (index + 4) & 0xFF;
+        }
+        else {
+            self.store_oam(index, value);
+            self.state_mut().oamaddr = (index + 1) & 0xFF;
         }
-        self.state_mut().oamaddr = (index + 1) & 0xFF;
     }
 
     fn write_oamaddr(&mut self, value: u8) {
+        // 2C02 only: a write during rendering copies the 8 byte row holding the new address
+        // over the row holding the old one
+        let old_row = self.state().oamaddr & 0xF8;
+        let new_row = value as usize & 0xF8;
+        let corrupts = self.is_oam_corruption_enabled() && self.is_rendering() && self.state().n_scanline < 240 && self.region() != Region::Pal;
+        if corrupts && old_row != new_row {
+            for i in 0..8 {
+                self.state_mut().oamdata[old_row + i] = self.state().oamdata[new_row + i];
+            }
+        }
         self.state_mut().oamaddr = value as usize;
     }
 
     fn write_ppuscroll(&mut self, value: u8) {
+        if self.is_warming_up() {
+            return;
+        }
         if self.state().write_toggle == false {
             self.state_mut().fine_x = value & 0b111;
             self.state_mut().temporary_addr.0 = (self.state().temporary_addr.0 & 0b0_111_11_11111_00000) | ((value >> 3) as u16);
@@ -1249,4 +1657,516 @@ trait Private: Sized + Context {
             self.state_mut().write_toggle = false;
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu;
+    use crate::emulator::Emulator;
+    use crate::test_rom::TestRom;
+
+    // an nrom past the warm-up, the ppu is ticked directly and the cpu never runs
+    fn emulator() -> Emulator {
+        let mut emulator = TestRom::nrom().emulator();
+        Interface::end_warmup(&mut emulator);
+        emulator
+    }
+
+    fn tick_to(emulator: &mut Emulator, scanline: usize, dot: usize) {
+        while Interface::get_beam_position(emulator) != (scanline, dot) {
+            Interface::tick(emulator);
+        }
+    }
+
+    #[test]
+    fn sprite_0_hit_is_clipped_by_the_left_masks() {
+        assert!(!sprite_0_hit_with(0, 0x18));
+        assert!(sprite_0_hit_with(0, 0x1E));
+        // only the background mask off is enough
+        assert!(!sprite_0_hit_with(0, 0x1C));
+        // x = 1..=8 reaches x = 8, the first unmasked pixel
+        assert!(sprite_0_hit_with(1, 0x18));
+    }
+
+    #[test]
+    fn sprite_0_hit_never_happens_at_x_255() {
+        assert!(!sprite_0_hit_with(255, 0x1E));
+        assert!(sprite_0_hit_with(254, 0x1E));
+    }
+
+    #[test]
+    fn secondary_oam_is_empty_at_the_start_of_scanline_0() {
+        let mut emulator = emulator();
+        emulator.state_mut().pmask = PMask::new(0x1E);
+        // a sprite on scanlines 16-23 and one the pre-render scanline would match
+        for (i, byte) in emulator.state_mut().oamdata.iter_mut().enumerate() {
+            *byte = if i % 4 == 0 { 0x10 } else { 0x00 };
+        }
+        emulator.state_mut().oamdata[4] = 0xFE;
+
+        tick_to(&mut emulator, 20, 0);
+        assert_eq!(emulator.state().secondary_oam[0], 0x10);
+
+        tick_to(&mut emulator, 0, 0);
+        assert_eq!(emulator.state().secondary_oam, [0xFF; 32]);
+    }
+
+    #[test]
+    fn oamdata_reads_during_rendering_see_the_sprite_logic() {
+        let mut emulator = emulator();
+        emulator.state_mut().oamdata[0..4].copy_from_slice(&[0x20, 0x05, 0x01, 0x40]);
+        emulator.state_mut().oamdata[4..8].copy_from_slice(&[0x90, 0x06, 0x02, 0x50]);
+
+        // vblank reads OAM at OAMADDR
+        tick_to(&mut emulator, 250, 0);
+        assert_eq!(Interface::read_oamdata(&mut emulator), 0x20);
+
+        emulator.state_mut().pmask = PMask::new(0x18);
+        // secondary OAM clear
+        tick_to(&mut emulator, 0x24, 10);
+        assert_eq!(Interface::read_oamdata(&mut emulator), 0xFF);
+        // evaluation, the y of sprite 0 was just read, it's in range so its other 3 bytes
+        // follow and sprite 1's y is read on dot 73
+        tick_to(&mut emulator, 0x24, 66);
+        assert_eq!(Interface::read_oamdata(&mut emulator), 0x20);
+        tick_to(&mut emulator, 0x24, 70);
+        assert_eq!(Interface::read_oamdata(&mut emulator), 0x01);
+        tick_to(&mut emulator, 0x24, 74);
+        assert_eq!(Interface::read_oamdata(&mut emulator), 0x90);
+        // sprite fetches walk secondary OAM, sprite 0's y was latched at dot 257
+        tick_to(&mut emulator, 0x24, 258);
+        assert_eq!(Interface::read_oamdata(&mut emulator), 0x05);
+        tick_to(&mut emulator, 0x24, 259);
+        assert_eq!(Interface::read_oamdata(&mut emulator), 0x01);
+        // an empty slot
+        tick_to(&mut emulator, 0x24, 266);
+        assert_eq!(Interface::read_oamdata(&mut emulator), 0xFF);
+
+        // with rendering off the same dot reads OAM again
+        emulator.state_mut().pmask = PMask::new(0x00);
+        tick_to(&mut emulator, 0x25, 70);
+        assert_eq!(Interface::read_oamdata(&mut emulator), 0x20);
+    }
+
+    #[test]
+    fn attribute_latch_holds_only_the_quadrant_bits() {
+        let mut emulator = emulator();
+        // top left 0, top right 1, bottom left 2, bottom right 3
+        emulator.poke_vram(0x23C0, 0b11_10_01_00);
+        for (coarse_x, coarse_y, expected) in [(0, 0, 0), (2, 0, 1), (0, 2, 2), (3, 3, 3), (1, 3, 2), (3, 1, 1)] {
+            emulator.state_mut().current_addr = PpuAddr(0x2000 | coarse_y << 5 | coarse_x);
+            Private::bg_latch_attribute_addr(&mut emulator);
+            Private::bg_latch_attribute(&mut emulator);
+            assert_eq!(emulator.state().attribute_latch, expected, "tile ({}, {})", coarse_x, coarse_y);
+        }
+    }
+
+    // an 8x16 sprite at y = 100 with tile byte $25, tiles $24/$25 of the $1000 table
+    fn tall_sprite_row_addr(row: usize, attribute: u8) -> u16 {
+        let mut emulator = emulator();
+        emulator.state_mut().pctrl = PCtrl::new(0x20);
+        emulator.state_mut().sprite_y_latch = 100;
+        emulator.state_mut().sprite_tile_addr_latch = 0x25;
+        emulator.state_mut().sprite_attribute_latch = attribute;
+        emulator.state_mut().n_scanline = 100 + row;
+        assert_eq!(Private::sprite_tile_hi_addr(&emulator), Private::sprite_tile_lo_addr(&emulator) + 8);
+        Private::sprite_tile_lo_addr(&emulator)
+    }
+
+    #[test]
+    fn tall_sprite_vertical_flip_swaps_the_tiles() {
+        assert_eq!(tall_sprite_row_addr(3, 0x00), 0x1243);
+        assert_eq!(tall_sprite_row_addr(10, 0x00), 0x1252);
+        // row r shows row 15 - r of the pair
+        assert_eq!(tall_sprite_row_addr(3, 0x80), 0x1254);
+        assert_eq!(tall_sprite_row_addr(10, 0x80), 0x1245);
+        assert_eq!(tall_sprite_row_addr(0, 0x80), 0x1257);
+        assert_eq!(tall_sprite_row_addr(15, 0x80), 0x1240);
+        // horizontal flip doesn't move rows
+        assert_eq!(tall_sprite_row_addr(7, 0x40), 0x1247);
+    }
+
+    // both $2006 writes, then the dots it takes for v to pick up the new address
+    fn set_ppuaddr(emulator: &mut Emulator, addr: u16) {
+        Interface::write_ppuaddr(emulator, (addr >> 8) as u8);
+        Interface::write_ppuaddr(emulator, addr as u8);
+        for _ in 0..3 {
+            Interface::tick(emulator);
+        }
+    }
+
+    fn read_palette(emulator: &mut Emulator, addr: u16) -> u8 {
+        set_ppuaddr(emulator, addr);
+        Interface::read_ppudata(emulator) & 0x3F
+    }
+
+    #[test]
+    fn sprite_backdrop_entries_mirror_the_background_ones() {
+        let mut emulator = emulator();
+        set_ppuaddr(&mut emulator, 0x3F00);
+        for value in 0..0x20 {
+            Interface::write_ppudata(&mut emulator, value);
+        }
+        set_ppuaddr(&mut emulator, 0x3F10);
+        Interface::write_ppudata(&mut emulator, 0x2A);
+        Interface::write_ppudata(&mut emulator, 0x15);
+        assert_eq!(read_palette(&mut emulator, 0x3F00), 0x2A);
+        assert_eq!(read_palette(&mut emulator, 0x3F10), 0x2A);
+        assert_eq!(read_palette(&mut emulator, 0x3F11), 0x15);
+        assert_eq!(read_palette(&mut emulator, 0x3F01), 0x01);
+        // the other three mirror too, in both directions. $3F18 and $3F1C were written after
+        // $3F08 and $3F0C
+        set_ppuaddr(&mut emulator, 0x3F04);
+        Interface::write_ppudata(&mut emulator, 0x33);
+        assert_eq!(read_palette(&mut emulator, 0x3F14), 0x33);
+        assert_eq!(read_palette(&mut emulator, 0x3F08), 0x18);
+        assert_eq!(read_palette(&mut emulator, 0x3F0C), 0x1C);
+        // and the whole $3F20-$3FFF range repeats $3F00-$3F1F
+        assert_eq!(read_palette(&mut emulator, 0x3FF0), 0x2A);
+    }
+
+    // reads $2002 with the beam at (241, dot), the vblank flag is set while dot 1 is drawn.
+    // Gives the flag the read saw, the flag a few dots later and whether the nmi fired
+    fn ppustatus_read_at(dot: usize) -> (bool, bool, bool) {
+        let mut emulator = emulator();
+        emulator.state_mut().pctrl = PCtrl::new(0x80);
+        tick_to(&mut emulator, 241, dot);
+        let read = Interface::read_ppustatus(&mut emulator) & 0x80 != 0;
+        tick_to(&mut emulator, 241, dot + 10);
+        (read, emulator.state().pstatus.vblank_occured(), cpu::Context::state(&emulator).nmi)
+    }
+
+    #[test]
+    fn ppustatus_read_around_vblank_start() {
+        // two dots early, nothing special
+        assert_eq!(ppustatus_read_at(0), (false, true, true));
+        // one dot early reads it clear, and the flag and the nmi never happen
+        assert_eq!(ppustatus_read_at(1), (false, false, false));
+        // right after it is set the read sees it but still cancels the nmi
+        assert_eq!(ppustatus_read_at(2), (true, false, false));
+    }
+
+    // tile 1 is solid colour 1 and fills nametable 0, sprite 0 uses it too
+    fn solid_background(emulator: &mut Emulator) {
+        for addr in 0x0010..0x0018 {
+            emulator.poke_vram(addr, 0xFF);
+        }
+        for addr in 0x2000..0x23C0 {
+            emulator.poke_vram(addr, 0x01);
+        }
+    }
+
+    // sprite 0 covers scanlines 31-38
+    fn sprite_0_hit_with(sprite_x: u8, pmask: u8) -> bool {
+        let mut emulator = emulator();
+        solid_background(&mut emulator);
+        emulator.state_mut().oamdata[0..4].copy_from_slice(&[30, 1, 0, sprite_x]);
+        emulator.state_mut().pmask = PMask::new(pmask);
+        tick_to(&mut emulator, 40, 0);
+        emulator.state().pstatus.sprite_0_hit()
+    }
+
+    #[test]
+    fn sprite_0_hit_needs_both_layers_shown() {
+        assert!(sprite_0_hit_with(100, 0x18));
+        assert!(!sprite_0_hit_with(100, 0x08));
+        assert!(!sprite_0_hit_with(100, 0x10));
+        assert!(!sprite_0_hit_with(100, 0x00));
+    }
+
+    // tile 1 is solid and tile 2 blank
+    fn sprite_0_hit_between(sprite_tile: u8, background_tile: u8) -> bool {
+        let mut emulator = emulator();
+        solid_background(&mut emulator);
+        for addr in 0x2000..0x23C0 {
+            emulator.poke_vram(addr, background_tile);
+        }
+        emulator.state_mut().oamdata[0..4].copy_from_slice(&[30, sprite_tile, 0, 100]);
+        emulator.state_mut().pmask = PMask::new(0x18);
+        tick_to(&mut emulator, 40, 0);
+        emulator.state().pstatus.sprite_0_hit()
+    }
+
+    #[test]
+    fn sprite_0_hit_needs_opaque_pixels_on_both_layers() {
+        assert!(sprite_0_hit_between(1, 1));
+        assert!(!sprite_0_hit_between(2, 1));
+        assert!(!sprite_0_hit_between(1, 2));
+    }
+
+    #[test]
+    fn sprite_0_hit_is_set_on_the_first_overlapping_pixel() {
+        let mut emulator = emulator();
+        solid_background(&mut emulator);
+        emulator.state_mut().oamdata[0..4].copy_from_slice(&[30, 1, 0, 100]);
+        emulator.state_mut().pmask = PMask::new(0x18);
+        // the sprite starts on scanline 31 and pixel 100 is drawn on dot 101
+        tick_to(&mut emulator, 31, 101);
+        assert!(!emulator.state().pstatus.sprite_0_hit());
+        tick_to(&mut emulator, 31, 102);
+        assert!(emulator.state().pstatus.sprite_0_hit());
+    }
+
+    #[test]
+    fn ppustatus_read_cancels_the_nmi_until_it_has_fired() {
+        // the nmi output needs the flag for two dots before it fires
+        assert_eq!(ppustatus_read_at(3), (true, false, false));
+        assert_eq!(ppustatus_read_at(4), (true, false, true));
+        assert_eq!(ppustatus_read_at(100), (true, false, true));
+    }
+
+    // sets OAMADDR to $40 in vblank, lets a frame go by and writes $2004 once
+    fn oamdata_write_after_a_frame(pmask: u8) -> usize {
+        let mut emulator = emulator();
+        emulator.state_mut().pmask = PMask::new(pmask);
+        tick_to(&mut emulator, 241, 10);
+        Interface::write_oamaddr(&mut emulator, 0x40);
+        tick_to(&mut emulator, 0, 0);
+        tick_to(&mut emulator, 241, 10);
+        Interface::write_oamdata(&mut emulator, 0xAB);
+        emulator.state().oamdata.iter().position(|&byte| byte == 0xAB).unwrap()
+    }
+
+    #[test]
+    fn rendering_resets_oamaddr() {
+        // the sprite tile fetches leave OAMADDR at 0 on every rendered scanline
+        assert_eq!(oamdata_write_after_a_frame(0x18), 0x00);
+        assert_eq!(oamdata_write_after_a_frame(0x00), 0x40);
+    }
+
+    // sprites 0-7 sit on scanline 64 and every other OAM byte is $F0, which no scanline
+    // matches. Each (offset, value) is poked over that, then the overflow flag is read
+    // once scanline 64 has been evaluated
+    fn sprite_overflow_with(bytes: &[(usize, u8)]) -> bool {
+        let mut emulator = emulator();
+        emulator.state_mut().pmask = PMask::new(0x18);
+        let oam = &mut emulator.state_mut().oamdata;
+        oam.fill(0xF0);
+        for sprite in 0..8 {
+            oam[sprite * 4] = 0x40;
+        }
+        for &(offset, value) in bytes {
+            oam[offset] = value;
+        }
+        tick_to(&mut emulator, 0x40, 257);
+        emulator.state().pstatus.sprite_overflow()
+    }
+
+    #[test]
+    fn sprite_overflow_search_walks_oam_diagonally() {
+        assert!(!sprite_overflow_with(&[]));
+        assert!(sprite_overflow_with(&[(8 * 4, 0x40)]));
+        // after a miss on sprite 8's y, the search reads sprite 9's tile, sprite 10's
+        // attribute and sprite 11's x as if they were y coordinates
+        assert!(sprite_overflow_with(&[(9 * 4 + 1, 0x40)]));
+        assert!(sprite_overflow_with(&[(10 * 4 + 2, 0x3C)]));
+        assert!(sprite_overflow_with(&[(11 * 4 + 3, 0x3A)]));
+        // so a ninth sprite there is missed
+        assert!(!sprite_overflow_with(&[(9 * 4, 0x40)]));
+        assert!(!sprite_overflow_with(&[(9 * 4, 0x40), (10 * 4, 0x40), (11 * 4, 0x40)]));
+        // m wraps to 0 without carrying into n, so sprite 12's y is read as a y again
+        assert!(sprite_overflow_with(&[(12 * 4, 0x40)]));
+        assert!(!sprite_overflow_with(&[(12 * 4 + 1, 0x40)]));
+    }
+
+    #[test]
+    fn enabling_nmi_during_vblank_fires_it() {
+        let mut emulator = emulator();
+        tick_to(&mut emulator, 241, 20);
+        assert!(!cpu::Context::state(&emulator).nmi);
+        Interface::write_ppuctrl(&mut emulator, 0x80);
+        tick_to(&mut emulator, 241, 23);
+        assert!(cpu::Context::state(&emulator).nmi);
+
+        // turning it off and on again fires another one
+        cpu::Context::state_mut(&mut emulator).nmi = false;
+        Interface::write_ppuctrl(&mut emulator, 0x00);
+        tick_to(&mut emulator, 241, 26);
+        Interface::write_ppuctrl(&mut emulator, 0x80);
+        tick_to(&mut emulator, 241, 29);
+        assert!(cpu::Context::state(&emulator).nmi);
+
+        // not once $2002 has cleared the flag
+        cpu::Context::state_mut(&mut emulator).nmi = false;
+        Interface::write_ppuctrl(&mut emulator, 0x00);
+        Interface::read_ppustatus(&mut emulator);
+        Interface::write_ppuctrl(&mut emulator, 0x80);
+        tick_to(&mut emulator, 250, 0);
+        assert!(!cpu::Context::state(&emulator).nmi);
+    }
+
+    // palette entry i holds colour i, so the indexed frame buffer says which entry each pixel
+    // came from. Sprites are [y, tile, attribute, x] from OAM index 0, tile 1 is solid and
+    // tile 2 blank. Gives pixel x of scanline 34
+    fn pixel_over(sprites: &[[u8; 4]], background_tile: u8, x: usize) -> u16 {
+        let mut emulator = emulator();
+        emulator.set_indexed_output(true);
+        solid_background(&mut emulator);
+        for addr in 0x2000..0x23C0 {
+            emulator.poke_vram(addr, background_tile);
+        }
+        for (i, entry) in emulator.state_mut().palette_ram.iter_mut().enumerate() {
+            *entry = i as u8;
+        }
+        emulator.state_mut().oamdata.fill(0xF0);
+        for (i, sprite) in sprites.iter().enumerate() {
+            emulator.state_mut().oamdata[i * 4..i * 4 + 4].copy_from_slice(sprite);
+        }
+        emulator.state_mut().pmask = PMask::new(0x18);
+        tick_to(&mut emulator, 35, 0);
+        emulator.state().frame_buffer_indexed[34 * 256 + x]
+    }
+
+    #[test]
+    fn sprite_priority_truth_table() {
+        let front = [30, 1, 0x00, 100];
+        let behind = [30, 1, 0x21, 100];
+        assert_eq!(pixel_over(&[front], 2, 100), 0x11);
+        assert_eq!(pixel_over(&[behind], 2, 100), 0x15);
+        assert_eq!(pixel_over(&[front], 1, 100), 0x11);
+        assert_eq!(pixel_over(&[behind], 1, 100), 0x01);
+        assert_eq!(pixel_over(&[], 2, 100), 0x00);
+        // the first opaque sprite wins before the background is looked at, so a behind
+        // sprite with a lower index hides a front one and the background shows through
+        assert_eq!(pixel_over(&[behind, front], 1, 100), 0x01);
+        assert_eq!(pixel_over(&[behind, front], 2, 100), 0x15);
+        assert_eq!(pixel_over(&[front, behind], 1, 100), 0x11);
+    }
+
+    #[test]
+    fn lowest_oam_index_wins_between_sprites() {
+        // sprite 1 starts further left, sprite 0 still covers it where they overlap
+        let sprites = [[30, 1, 0x00, 104], [30, 1, 0x01, 100]];
+        assert_eq!(pixel_over(&sprites, 2, 100), 0x15);
+        assert_eq!(pixel_over(&sprites, 2, 104), 0x11);
+        let sprites = [[30, 1, 0x01, 100], [30, 1, 0x00, 104]];
+        assert_eq!(pixel_over(&sprites, 2, 104), 0x15);
+        // a transparent pixel of the lower index lets the next sprite through
+        assert_eq!(pixel_over(&[[30, 2, 0x00, 100], [30, 1, 0x01, 100]], 2, 100), 0x15);
+        // the behind bit doesn't order sprites, only sprites against the background
+        assert_eq!(pixel_over(&[[30, 1, 0x20, 100], [30, 1, 0x01, 100]], 2, 100), 0x11);
+        assert_eq!(pixel_over(&[[30, 1, 0x00, 100], [30, 1, 0x21, 100]], 2, 100), 0x11);
+    }
+
+    #[test]
+    fn palette_reads_fill_the_buffer_from_the_nametable_underneath() {
+        let mut emulator = emulator();
+        emulator.poke_vram(0x2000, 0x55);
+        emulator.poke_vram(0x2F05, 0x77);
+        emulator.poke_vram(0x2F25, 0x66);
+        emulator.state_mut().palette_ram[5] = 0x21;
+
+        // below the palette a read gives the old buffer, the byte itself comes a read later
+        set_ppuaddr(&mut emulator, 0x2000);
+        assert_eq!(Interface::read_ppudata(&mut emulator), 0x00);
+        assert_eq!(Interface::read_ppudata(&mut emulator), 0x55);
+
+        // palette reads are immediate and leave $2F05 in the buffer for the next read
+        assert_eq!(read_palette(&mut emulator, 0x3F05), 0x21);
+        set_ppuaddr(&mut emulator, 0x2000);
+        assert_eq!(Interface::read_ppudata(&mut emulator), 0x77);
+        // the palette mirrors up to $3FFF fill it from as far as $2FFF
+        assert_eq!(read_palette(&mut emulator, 0x3F25), 0x21);
+        set_ppuaddr(&mut emulator, 0x2000);
+        assert_eq!(Interface::read_ppudata(&mut emulator), 0x66);
+    }
+
+    #[test]
+    fn ppudata_access_while_rendering_steps_coarse_x_and_y() {
+        let mut emulator = emulator();
+        Interface::write_ppumask(&mut emulator, 0x08);
+        // past dot 257 nothing else touches v until the prefetch at dot 328
+        tick_to(&mut emulator, 10, 260);
+
+        // coarse x wraps into the next horizontal nametable, fine y steps within the tile
+        emulator.state_mut().current_addr = PpuAddr(3 << 12 | 5 << 5 | 31);
+        Interface::read_ppudata(&mut emulator);
+        assert_eq!(emulator.state().current_addr.0, 4 << 12 | 0x0400 | 5 << 5);
+
+        // fine y 7 on row 29 wraps to the top of the next vertical nametable
+        emulator.state_mut().current_addr = PpuAddr(7 << 12 | 29 << 5 | 4);
+        Interface::write_ppudata(&mut emulator, 0);
+        assert_eq!(emulator.state().current_addr.0, 0x0800 | 5);
+
+        // outside rendering the increment from $2000 applies
+        tick_to(&mut emulator, 241, 0);
+        emulator.state_mut().current_addr = PpuAddr(0x2000 | 31);
+        Interface::read_ppudata(&mut emulator);
+        assert_eq!(emulator.state().current_addr.0, 0x2000 | 32);
+    }
+
+    #[test]
+    fn ppuaddr_written_twice_in_hblank_moves_the_next_scanline() {
+        let mut emulator = emulator();
+        Interface::write_ppumask(&mut emulator, 0x08);
+        tick_to(&mut emulator, 100, 260);
+        // fine y 2, nametable 1, coarse y 10, coarse x 4
+        Interface::write_ppuaddr(&mut emulator, 0x25);
+        Interface::write_ppuaddr(&mut emulator, 0x44);
+        tick_to(&mut emulator, 100, 270);
+        assert_eq!(emulator.state().current_addr.0, 0x2544);
+
+        // the prefetch of the next scanline's first two tiles steps coarse x twice
+        tick_to(&mut emulator, 101, 0);
+        assert_eq!(emulator.state().current_addr.0, 0x2546);
+
+        // the end of scanline 101 steps fine y and takes coarse x back from t
+        tick_to(&mut emulator, 101, 258);
+        assert_eq!(emulator.state().current_addr.0, 0x3544);
+    }
+
+    #[test]
+    fn oamaddr_past_the_first_row_at_render_start_copies_its_row_over_it() {
+        let mut emulator = emulator();
+        emulator.state_mut().pmask = PMask::new(0x18);
+        tick_to(&mut emulator, 250, 0);
+        for (i, byte) in emulator.state_mut().oamdata.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        emulator.state_mut().oamaddr = 0x2B;
+
+        tick_to(&mut emulator, 261, 2);
+        assert_eq!(emulator.state().oamdata[0..8], [0x28, 0x29, 0x2A, 0x2B, 0x2C, 0x2D, 0x2E, 0x2F]);
+        assert!(emulator.state().oamdata[8..].iter().enumerate().all(|(i, &byte)| byte == i as u8 + 8));
+
+        // the first row itself copies nothing
+        tick_to(&mut emulator, 250, 0);
+        emulator.state_mut().oamdata[0] = 0xAA;
+        emulator.state_mut().oamaddr = 0x04;
+        tick_to(&mut emulator, 261, 2);
+        assert_eq!(emulator.state().oamdata[0..8], [0xAA, 0x29, 0x2A, 0x2B, 0x2C, 0x2D, 0x2E, 0x2F]);
+    }
+
+    #[test]
+    fn ppumask_writes_take_effect_3_dots_later() {
+        let mut emulator = emulator();
+        Interface::write_ppumask(&mut emulator, 0x1E);
+        for _ in 0..2 {
+            Interface::tick(&mut emulator);
+            assert_eq!(emulator.state().pmask.0, 0x00);
+        }
+        Interface::tick(&mut emulator);
+        assert_eq!(emulator.state().pmask.0, 0x1E);
+
+        // a second write lands the first one straight away and waits itself
+        Interface::write_ppumask(&mut emulator, 0x01);
+        Interface::write_ppumask(&mut emulator, 0x18);
+        assert_eq!(emulator.state().pmask.0, 0x01);
+        for _ in 0..3 {
+            Interface::tick(&mut emulator);
+        }
+        assert_eq!(emulator.state().pmask.0, 0x18);
+    }
+
+    #[test]
+    fn palette_reads_take_the_top_2_bits_from_open_bus_and_honour_greyscale() {
+        let mut emulator = emulator();
+        emulator.state_mut().palette_ram[5] = 0x2D;
+        for (greyscale, latch, expected) in [(false, 0x00, 0x2D), (false, 0xFF, 0xED), (true, 0x00, 0x20), (true, 0xFF, 0xE0)] {
+            emulator.state_mut().pmask = PMask::new(if greyscale { 0x01 } else { 0x00 });
+            set_ppuaddr(&mut emulator, 0x3F05);
+            emulator.state_mut().io_latch = latch;
+            assert_eq!(Interface::read_ppudata(&mut emulator), expected);
+        }
+    }
+}
@@ -0,0 +1,11 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    // 312 scanlines, the PPU runs 3.2 dots per CPU cycle
+    Pal,
+    // famiclone with 312 scanlines but NTSC's 3:1 PPU/CPU clock ratio
+    Dendy,
+}
@@ -0,0 +1,61 @@
+// tiny iNES images for the unit tests: two identical 16K PRG banks and 8K of CHR RAM.
+// Code lives from $E000, which is in the fixed bank of every mapper create_mapper knows,
+// so the same image runs on NROM, MMC1 and MMC3 boards
+use crate::emulator::Emulator;
+
+pub const CODE_ADDR: u16 = 0xE000;
+// NMI and IRQ/BRK both land on an RTI unless a test points them elsewhere
+pub const RTI_ADDR: u16 = 0xFF00;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+pub struct TestRom {
+    mapper_id: u8,
+    prg: Vec<u8>,
+}
+
+impl TestRom {
+    // the default program spins on JMP $E000
+    pub fn new(mapper_id: u8) -> Self {
+        let rom = TestRom {
+            mapper_id,
+            prg: vec![0xEA; PRG_BANK_SIZE],
+        };
+        rom.code(CODE_ADDR, &[0x4C, 0x00, 0xE0])
+            .code(RTI_ADDR, &[0x40])
+            .vector(0xFFFA, RTI_ADDR)
+            .vector(0xFFFC, CODE_ADDR)
+            .vector(0xFFFE, RTI_ADDR)
+    }
+
+    pub fn nrom() -> Self {
+        TestRom::new(0)
+    }
+
+    pub fn code(mut self, addr: u16, bytes: &[u8]) -> Self {
+        let offset = addr as usize & (PRG_BANK_SIZE - 1);
+        self.prg[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self
+    }
+
+    pub fn vector(self, addr: u16, target: u16) -> Self {
+        self.code(addr, &target.to_le_bytes())
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let flags_6 = self.mapper_id << 4;
+        let flags_7 = self.mapper_id & 0xF0;
+        let mut image = vec![b'N', b'E', b'S', 0x1A, 2, 0, flags_6, flags_7, 0, 0, 0, 0, 0, 0, 0, 0];
+        image.extend_from_slice(&self.prg);
+        image.extend_from_slice(&self.prg);
+        image
+    }
+
+    // loaded and reset, so the cpu starts at the reset vector
+    pub fn emulator(&self) -> Emulator {
+        let mut emulator = Emulator::new();
+        emulator.load_rom_from_bytes(&self.build()).unwrap();
+        emulator.reset();
+        emulator
+    }
+}
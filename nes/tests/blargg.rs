@@ -0,0 +1,67 @@
+// blargg's test roms, run with `cargo test --features slow-tests --test blargg` after
+// test-roms/fetch-blargg.sh has downloaded them
+#![cfg(feature = "slow-tests")]
+
+use nes::{Emulator, TestStatus};
+use std::path::PathBuf;
+
+const STATUS_ADDR: u16 = 0x6000;
+const RUNNING: u8 = 0x80;
+const PASSED: u8 = 0x00;
+// cpu_instrs is the longest, about a minute of emulated time
+const MAX_CYCLES: usize = 1_789_773 * 120;
+
+fn rom_path(rom: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../test-roms/blargg").join(rom)
+}
+
+fn run(rom: &str) {
+    let path = rom_path(rom);
+    assert!(path.exists(), "{} is missing, run test-roms/fetch-blargg.sh", path.display());
+
+    let mut emulator = Emulator::new();
+    emulator.load_rom_from_file(&path).unwrap();
+    emulator.reset();
+    // $6000 reads 0 until the rom starts, so wait for it to report running first
+    assert_eq!(emulator.run_until_memory_equals(STATUS_ADDR, RUNNING, MAX_CYCLES), Ok(true), "{} never started", rom);
+    let finished = emulator.run_until_memory_equals(STATUS_ADDR, PASSED, MAX_CYCLES);
+    match emulator.test_status() {
+        TestStatus::Passed(_) => assert_eq!(finished, Ok(true)),
+        status => panic!("{}: {:?}", rom, status),
+    }
+}
+
+#[test]
+fn cpu_instrs() {
+    run("cpu_instrs/cpu_instrs.nes");
+}
+
+#[test]
+fn instr_timing() {
+    run("instr_timing/instr_timing.nes");
+}
+
+#[test]
+fn ppu_vbl_nmi() {
+    run("ppu_vbl_nmi/ppu_vbl_nmi.nes");
+}
+
+#[test]
+fn apu_mixer_square() {
+    run("apu_mixer/square.nes");
+}
+
+#[test]
+fn apu_mixer_triangle() {
+    run("apu_mixer/triangle.nes");
+}
+
+#[test]
+fn apu_mixer_noise() {
+    run("apu_mixer/noise.nes");
+}
+
+#[test]
+fn apu_mixer_dmc() {
+    run("apu_mixer/dmc.nes");
+}
@@ -0,0 +1,71 @@
+// a tiny NROM that reports through $6000 the way blargg's test roms do: status $80 and the
+// DE B0 61 signature first, then the message, a few frames of busy waiting and the final status
+use nes::{Emulator, TestStatus};
+
+const MESSAGE_ADDR: usize = 0x40;
+
+fn reporting_rom(status: u8, message: &str) -> Vec<u8> {
+    let code: [u8; 0x33] = [
+        0xA9, 0x80, 0x8D, 0x00, 0x60,       // LDA #$80, STA $6000
+        0xA9, 0xDE, 0x8D, 0x01, 0x60,       // signature
+        0xA9, 0xB0, 0x8D, 0x02, 0x60,
+        0xA9, 0x61, 0x8D, 0x03, 0x60,
+        0xA2, 0x00,                         // LDX #0
+        0xBD, MESSAGE_ADDR as u8, 0xE0,     // LDA message,X
+        0x9D, 0x04, 0x60,                   // STA $6004,X
+        0xF0, 0x03,                         // BEQ wait
+        0xE8,                               // INX
+        0xD0, 0xF5,                         // BNE copy
+        0xA0, 0x00, 0xA2, 0x00,             // wait: LDY #0, LDX #0
+        0xCA, 0xD0, 0xFD,                   // DEX, BNE
+        0x88, 0xD0, 0xFA,                   // DEY, BNE
+        0xA9, status, 0x8D, 0x00, 0x60,     // LDA #status, STA $6000
+        0x4C, 0x30, 0xE0,                   // JMP *
+    ];
+    let mut prg = vec![0xEA; 0x4000];
+    prg[0x2000..0x2000 + code.len()].copy_from_slice(&code);
+    let message_start = 0x2000 + MESSAGE_ADDR;
+    prg[message_start..message_start + message.len()].copy_from_slice(message.as_bytes());
+    prg[message_start + message.len()] = 0;
+    // every vector points at $E000
+    prg[0x3FFA..].copy_from_slice(&[0x00, 0xE0, 0x00, 0xE0, 0x00, 0xE0]);
+
+    let mut image = vec![b'N', b'E', b'S', 0x1A, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    image.extend_from_slice(&prg);
+    image
+}
+
+fn run(status: u8, message: &str) -> Emulator {
+    let mut emulator = Emulator::new();
+    emulator.load_rom_from_bytes(&reporting_rom(status, message)).unwrap();
+    emulator.reset();
+    emulator
+}
+
+#[test]
+fn no_rom_has_no_status() {
+    let mut emulator = Emulator::new();
+    assert_eq!(emulator.test_status(), TestStatus::NoRom);
+    assert_eq!(emulator.run_until_test_done(10), TestStatus::NoRom);
+}
+
+#[test]
+fn passing_rom_reports_passed() {
+    let mut emulator = run(0x00, "\nPassed\n");
+    emulator.run_for_one_frame();
+    emulator.run_for_one_frame();
+    assert_eq!(emulator.test_status(), TestStatus::Running);
+    assert_eq!(emulator.run_until_test_done(60), TestStatus::Passed("\nPassed\n".to_string()));
+}
+
+#[test]
+fn failing_rom_reports_its_code_and_message() {
+    let mut emulator = run(0x03, "\nFailed #3\n");
+    assert_eq!(emulator.run_until_test_done(60), TestStatus::Failed(3, "\nFailed #3\n".to_string()));
+}
+
+#[test]
+fn too_few_frames_is_still_running() {
+    let mut emulator = run(0x00, "\nPassed\n");
+    assert_eq!(emulator.run_until_test_done(3), TestStatus::Running);
+}
@@ -0,0 +1,76 @@
+use std::collections::VecDeque;
+
+use nes::Region;
+
+pub const SAMPLE_RATE: u32 = 44100;
+// the queue is kept between these many frames of samples
+const LOW_WATER_FRAMES: u32 = 2;
+const HIGH_WATER_FRAMES: u32 = 6;
+const HISTORY_LEN: usize = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioSyncAction {
+    Normal,
+    // run two emulator frames before presenting
+    CatchUp,
+    // hold the next frame back by one frame
+    Wait,
+}
+
+// watches how full the sdl audio queue is before each frame
+pub struct AudioSyncState {
+    // queued samples, oldest first
+    history: VecDeque<u32>,
+    last_action: AudioSyncAction,
+    // PAL and Dendy run 50 frames a second, so each frame carries more samples
+    samples_per_frame: u32,
+}
+
+impl AudioSyncState {
+    pub fn new(region: Region) -> Self {
+        let samples_per_frame = match region {
+            Region::Ntsc => SAMPLE_RATE / 60,
+            Region::Pal | Region::Dendy => SAMPLE_RATE / 50,
+        };
+        AudioSyncState {
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            last_action: AudioSyncAction::Normal,
+            samples_per_frame,
+        }
+    }
+
+    // queued_bytes is AudioQueue::size() per channel, the queue holds f32 samples
+    pub fn update(&mut self, queued_bytes: u32) -> AudioSyncAction {
+        let queued_samples = queued_bytes / 4;
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(queued_samples);
+
+        self.last_action = if queued_samples < LOW_WATER_FRAMES * self.samples_per_frame {
+            AudioSyncAction::CatchUp
+        } else if queued_samples > HIGH_WATER_FRAMES * self.samples_per_frame {
+            AudioSyncAction::Wait
+        } else {
+            AudioSyncAction::Normal
+        };
+        self.last_action
+    }
+
+    pub fn last_action(&self) -> AudioSyncAction {
+        self.last_action
+    }
+
+    // latest queue fill, in frames of samples
+    pub fn queued_frames(&self) -> f32 {
+        self.history.back().map_or(0.0, |&samples| samples as f32 / self.samples_per_frame as f32)
+    }
+
+    pub fn average_queued_frames(&self) -> f32 {
+        if self.history.is_empty() {
+            return 0.0;
+        }
+        let total: u32 = self.history.iter().sum();
+        total as f32 / self.history.len() as f32 / self.samples_per_frame as f32
+    }
+}
@@ -0,0 +1,65 @@
+use std::fs;
+use std::path::Path;
+
+// read from `rottenes.cfg` in the working directory, one `key = value` per line:
+//
+//     scale = 3
+//     integer_scaling = true
+//     vsync = false
+#[derive(Clone, Copy, Debug)]
+pub struct GuiConfig {
+    pub scale: u32,
+    pub integer_scaling: bool,
+    // present on vsync instead of sleeping out the rest of the frame
+    pub vsync: bool,
+}
+
+impl Default for GuiConfig {
+    fn default() -> Self {
+        GuiConfig {
+            scale: 3,
+            integer_scaling: true,
+            vsync: false,
+        }
+    }
+}
+
+impl GuiConfig {
+    // a missing file gives the defaults, unknown keys and bad values are skipped
+    pub fn load(path: &Path) -> Self {
+        let mut config = GuiConfig::default();
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(_) => return config,
+        };
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            match key {
+                "scale" => {
+                    if let Ok(scale) = value.parse::<u32>() {
+                        config.scale = scale.max(1);
+                    }
+                }
+                "integer_scaling" => {
+                    if let Ok(integer_scaling) = value.parse() {
+                        config.integer_scaling = integer_scaling;
+                    }
+                }
+                "vsync" => {
+                    if let Ok(vsync) = value.parse() {
+                        config.vsync = vsync;
+                    }
+                }
+                _ => println!("unknown config key: {}", key),
+            }
+        }
+        config
+    }
+}
@@ -1,55 +1,90 @@
 use std::path::Path;
 
-use nes::{LoadError, Emulator, StandardInput};
+use nes::{LoadError, PaletteError, Emulator, StandardInput, Overscan, FrameBlend};
 
-use sdl2::pixels::Color;
-use sdl2::event::Event;
+use crate::config::GuiConfig;
+use crate::pacer::FramePacer;
+use crate::audio_sync::{AudioSyncAction, AudioSyncState, SAMPLE_RATE};
+
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::audio::{AudioQueue, AudioSpecDesired};
-use std::time::Duration; 
 
 pub struct GuiObject {
     emulator: Emulator,
     save_slot: Option<Vec<u8>>,
+    config: GuiConfig,
 }
 
 impl GuiObject {
-    pub fn new() -> Self {
+    pub fn new(config: GuiConfig) -> Self {
         GuiObject {
             emulator: Emulator::new(),
             save_slot: None,
+            config,
         }
     }
 
+    // a .zip has to hold exactly one .nes, otherwise it fails with AmbiguousZipEntry
     pub fn load_rom_from_file(&mut self, path: &Path) -> Result<(), LoadError> {
+        #[cfg(feature = "zip-roms")]
+        if path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("zip")) {
+            return self.emulator.load_rom_from_zip(path, None);
+        }
         self.emulator.load_rom_from_file(path)
     }
 
+    pub fn set_palette_from_pal(&mut self, data: &[u8]) -> Result<(), PaletteError> {
+        self.emulator.set_palette_from_pal(data)
+    }
+
+    pub fn set_overscan(&mut self, overscan: Overscan) {
+        self.emulator.set_overscan(overscan)
+    }
+
     pub fn run(&mut self) {
-        let mut frame_counter = 0usize;
-        let mut frame_skipped = 0usize;
-        use std::time::Instant;
+        let mut show_frame_time_stats = false;
+        let mut show_audio_sync = false;
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
         let audio_subsystem = sdl_context.audio().unwrap();
         
-        let magnifaction = 3u32;
-        let window = video_subsystem.window("rust-sdl2 demo", 256 * magnifaction, 240 * magnifaction)
+        let magnifaction = self.config.scale;
+        let width = self.emulator.visible_width();
+        let height = self.emulator.visible_height();
+        let window = video_subsystem.window("rust-sdl2 demo", width as u32 * magnifaction, height as u32 * magnifaction)
             .position_centered()
             .build()
             .unwrap();
         
-        let mut canvas = window.into_canvas().build().unwrap();
+        let mut canvas = if self.config.vsync {
+            window.into_canvas().present_vsync().build().unwrap()
+        } else {
+            window.into_canvas().build().unwrap()
+        };
+        if self.config.integer_scaling {
+            canvas.set_logical_size(width as u32, height as u32).unwrap();
+            canvas.set_integer_scale(true).unwrap();
+        }
         canvas.set_draw_color(Color::RGB(0, 0, 0));
         canvas.clear();
         canvas.present();
 
+        let texture_creator = canvas.texture_creator();
+        let mut texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGBA32, width as u32, height as u32).unwrap();
+        let mut pixels = vec![0u8; width * height * 4];
+
         self.emulator.reset();
 
+        // stereo samples come interleaved left/right
+        let channels: u8 = if self.emulator.get_config().stereo { 2 } else { 1 };
         let desired_spec = AudioSpecDesired {
-            freq: Some(44100),
-            channels: Some(1),
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(channels),
             samples: None,
         };
 
@@ -58,32 +93,53 @@ impl GuiObject {
 
 
         let mut event_pump = sdl_context.event_pump().unwrap();
+        let region = self.emulator.region();
+        let mut pacer = FramePacer::new(region);
+        let mut audio_sync = AudioSyncState::new(region);
         
         'running: loop {
-            let start = Instant::now();
-            // let start2 = Instant::now();
-            self.emulator.run_for_one_frame();
-            frame_counter += 1;
-            // println!("time cost: {:?} ms", start2.elapsed().as_millis());
-            let frame_buffer = self.emulator.get_framebuffer();
-            for (i, rgb) in frame_buffer.iter().enumerate() {
-                let i = i as i32;
-                let x = i % 256;
-                let y = i / 256;
-                canvas.set_draw_color(Color::RGB(rgb.r, rgb.g, rgb.b));
-                canvas.fill_rect(Rect::new(x * magnifaction as i32, y * magnifaction as i32, magnifaction, magnifaction)).unwrap();
+            // F1 prints the mean and max time the last 60 frames took, sleep excluded
+            if let Some((mean, max)) = pacer.take_frame_time_stats() {
+                if show_frame_time_stats {
+                    println!("frame time mean {:.2} ms, max {:.2} ms, audio queue {:.1} frames",
+                        mean.as_secs_f64() * 1000.0, max.as_secs_f64() * 1000.0, audio_sync.average_queued_frames());
+                }
             }
+            pacer.frame_start();
 
             for event in event_pump.poll_iter() {
                 match event {
                     Event::DropFile { timestamp, window_id, filename } => {
                         let path = Path::new(&filename);
-                        self.emulator.load_rom_from_file(&path).unwrap();
+                        // a file that doesn't load leaves the running game alone
+                        if let Err(e) = self.load_rom_from_file(&path) {
+                            println!("can't load {}: {:?}", path.display(), e);
+                            continue;
+                        }
                         self.emulator.reset();
+                        // the header can switch the region, which changes the frame rate
+                        let region = self.emulator.region();
+                        pacer = FramePacer::new(region);
+                        audio_sync = AudioSyncState::new(region);
                     }
                     Event::KeyDown { keycode: Some(Keycode::E), repeat: false, .. } => {
                         self.save_slot = Option::Some(self.emulator.save_state());
                     },
+                    Event::KeyDown { keycode: Some(Keycode::F1), repeat: false, .. } => {
+                        show_frame_time_stats = !show_frame_time_stats;
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::F2), repeat: false, .. } => {
+                        show_audio_sync = !show_audio_sync;
+                    },
+                    // F3 cycles frame blending off, average and phosphor
+                    Event::KeyDown { keycode: Some(Keycode::F3), repeat: false, .. } => {
+                        let blend = match self.emulator.get_config().frame_blend {
+                            FrameBlend::Off => FrameBlend::Average,
+                            FrameBlend::Average => FrameBlend::Phosphor,
+                            FrameBlend::Phosphor => FrameBlend::Off,
+                        };
+                        self.emulator.set_frame_blend(blend);
+                    },
                     Event::KeyDown { keycode: Some(Keycode::Q), repeat: false, .. } => {
                         if let Some(v) = &self.save_slot {
                             self.emulator.load_state(&v)
@@ -96,54 +152,79 @@ impl GuiObject {
                 }
             }
 
-            let keyboard_state = sdl2::keyboard::KeyboardState::new(&event_pump);
-            if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::Return) {
-                self.emulator.set_input_1(StandardInput::START, true)
-            }
-            if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::Space) {
-                self.emulator.set_input_1(StandardInput::SELECT, true)
-            }
-            if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::W) {
-                self.emulator.set_input_1(StandardInput::UP, true)
-            }
-            if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::S) {
-                self.emulator.set_input_1(StandardInput::DOWN, true)
-            }
-            if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::A) {
-                self.emulator.set_input_1(StandardInput::LEFT, true)
-            }
-            if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::D) {
-                self.emulator.set_input_1(StandardInput::RIGHT, true)
-            }
-            if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::J) {
-                self.emulator.set_input_1(StandardInput::B, true)
-            }
-            if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::K) {
-                self.emulator.set_input_1(StandardInput::A, true)
-            }
+            let input = keyboard_input(&event_pump);
 
-            audio_device.queue_audio(self.emulator.get_sample().as_slice()).unwrap();
-            self.emulator.clear_sample();
-            
-            // if frame_counter % 60 == 0 {
-            //     println!("{}", frame_skipped);
-            //     frame_skipped = 0;
-            // }
+            // an almost empty audio queue runs an extra frame instead of dropping a present
+            let frames = match audio_sync.update(audio_device.size() / channels as u32) {
+                AudioSyncAction::CatchUp => 2,
+                _ => 1,
+            };
+            for _ in 0..frames {
+                // the input mask is cleared after every frame
+                self.emulator.set_input_1(input, true);
+                self.emulator.run_for_one_frame();
+                audio_device.queue_audio(self.emulator.get_sample().as_slice()).unwrap();
+                self.emulator.clear_sample();
+            }
 
-            if audio_device.size() < 44100 / 2 && frame_counter & 1 == 0 {
-                frame_skipped += 1;
-                continue;
+            self.emulator.copy_framebuffer_rgba8888(&mut pixels).unwrap();
+            texture.update(None, &pixels, width * 4).unwrap();
+            canvas.copy(&texture, None, None).unwrap();
+            // F2 draws the audio queue fill as a bar, 8 pixels per frame of samples
+            if show_audio_sync {
+                draw_audio_sync_bar(&mut canvas, &audio_sync);
             }
             canvas.present();
 
-            let t = start.elapsed().as_nanos();
-            let wait = if (1_000_000_000u128 / 60) > t {
-                ((1_000_000_000u128 / 60) - t) as u32
+            if self.config.vsync {
+                pacer.skip_wait();
+                continue;
             }
-            else {
-                0
-            };
-            ::std::thread::sleep(Duration::new(0, wait));
+            if audio_sync.last_action() == AudioSyncAction::Wait {
+                pacer.delay_one_frame();
+            }
+            pacer.wait_for_next_frame();
         }
     }
-}
\ No newline at end of file
+}
+
+fn keyboard_input(event_pump: &sdl2::EventPump) -> StandardInput {
+    let keyboard_state = sdl2::keyboard::KeyboardState::new(event_pump);
+    let mut input = StandardInput::empty();
+    if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::Return) {
+        input |= StandardInput::START;
+    }
+    if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::Space) {
+        input |= StandardInput::SELECT;
+    }
+    if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::W) {
+        input |= StandardInput::UP;
+    }
+    if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::S) {
+        input |= StandardInput::DOWN;
+    }
+    if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::A) {
+        input |= StandardInput::LEFT;
+    }
+    if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::D) {
+        input |= StandardInput::RIGHT;
+    }
+    if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::J) {
+        input |= StandardInput::B;
+    }
+    if keyboard_state.is_scancode_pressed(sdl2::keyboard::Scancode::K) {
+        input |= StandardInput::A;
+    }
+    input
+}
+
+fn draw_audio_sync_bar(canvas: &mut Canvas<Window>, audio_sync: &AudioSyncState) {
+    let color = match audio_sync.last_action() {
+        AudioSyncAction::CatchUp => Color::RGB(255, 0, 0),
+        AudioSyncAction::Wait => Color::RGB(255, 255, 0),
+        AudioSyncAction::Normal => Color::RGB(0, 255, 0),
+    };
+    let bar_width = ((audio_sync.queued_frames() * 8.0) as u32).max(1);
+    canvas.set_draw_color(color);
+    canvas.fill_rect(Rect::new(0, 0, bar_width, 4)).unwrap();
+}
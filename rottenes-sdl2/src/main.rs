@@ -8,13 +8,30 @@ extern crate sdl2;
 extern crate nes;
 
 mod gui;
+mod config;
+mod pacer;
+mod audio_sync;
 
 fn main() {
     let path_str = String::from("../test-roms/spritecans.nes");
     println!("{}", path_str);
 
-    let mut gui = gui::GuiObject::new();
+    let config = config::GuiConfig::load(Path::new("rottenes.cfg"));
+    let mut gui = gui::GuiObject::new(config);
     gui.load_rom_from_file(Path::new(&path_str)).unwrap();
+
+    // --palette file.pal
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(index) = args.iter().position(|arg| arg == "--palette") {
+        let palette_path = args.get(index + 1).expect("--palette needs a .pal file");
+        let mut palette = Vec::new();
+        File::open(palette_path).unwrap().read_to_end(&mut palette).unwrap();
+        gui.set_palette_from_pal(&palette).unwrap();
+    }
+    // --crop-overscan hides the top and bottom 8 lines like most NTSC TVs
+    if args.iter().any(|arg| arg == "--crop-overscan") {
+        gui.set_overscan(nes::Overscan { top: 8, bottom: 8, left: 0, right: 0 });
+    }
     gui.run();
     println!("Hello, rottenes!");
 }
@@ -0,0 +1,74 @@
+use std::time::{Duration, Instant};
+
+use nes::Region;
+
+// sleeps until a fixed schedule of frame deadlines instead of sleeping for what is left of
+// each frame, so sleep's coarse granularity doesn't add up over time
+pub struct FramePacer {
+    frame_duration: Duration,
+    target_time: Instant,
+    frame_start: Instant,
+    // frame times since the stats were last printed
+    frame_times: Vec<Duration>,
+}
+
+// frames of stats collected before they are printed
+const STATS_FRAMES: usize = 60;
+
+impl FramePacer {
+    pub fn new(region: Region) -> Self {
+        // 60.0988 Hz for NTSC, 50.007 Hz for PAL and Dendy
+        let frame_duration = match region {
+            Region::Ntsc => Duration::from_nanos(16_639_267),
+            Region::Pal | Region::Dendy => Duration::from_nanos(19_997_200),
+        };
+        let now = Instant::now();
+        FramePacer {
+            frame_duration,
+            target_time: now,
+            frame_start: now,
+            frame_times: Vec::with_capacity(STATS_FRAMES),
+        }
+    }
+
+    pub fn frame_start(&mut self) {
+        self.frame_start = Instant::now();
+    }
+
+    // a late frame skips the sleep and the next one catches up, but after falling more than
+    // a frame behind the schedule starts over rather than running frames back to back
+    pub fn wait_for_next_frame(&mut self) {
+        self.frame_times.push(self.frame_start.elapsed());
+        self.target_time += self.frame_duration;
+        let now = Instant::now();
+        if now < self.target_time {
+            std::thread::sleep(self.target_time - now);
+        }
+        else if now - self.target_time > self.frame_duration {
+            self.target_time = now;
+        }
+    }
+
+    // pushes the next deadline back a frame, when too much audio is queued
+    pub fn delay_one_frame(&mut self) {
+        self.target_time += self.frame_duration;
+    }
+
+    // skips the sleep and restarts the schedule, for frames paced by vsync
+    pub fn skip_wait(&mut self) {
+        self.frame_times.push(self.frame_start.elapsed());
+        self.target_time = Instant::now();
+    }
+
+    // mean and max frame time over the last STATS_FRAMES frames, once that many are collected
+    pub fn take_frame_time_stats(&mut self) -> Option<(Duration, Duration)> {
+        if self.frame_times.len() < STATS_FRAMES {
+            return None;
+        }
+        let total: Duration = self.frame_times.iter().sum();
+        let max = self.frame_times.iter().max().copied().unwrap_or_default();
+        let mean = total / self.frame_times.len() as u32;
+        self.frame_times.clear();
+        Some((mean, max))
+    }
+}
@@ -0,0 +1,21 @@
+#!/bin/sh
+# downloads the blargg roms tests/blargg.rs runs into test-roms/blargg, then
+#   cargo test --features slow-tests --test blargg
+set -e
+
+BASE_URL=https://raw.githubusercontent.com/christopherpow/nes-test-roms/master
+DEST="$(dirname "$0")/blargg"
+
+mkdir -p "$DEST"
+for rom in \
+    cpu_instrs/cpu_instrs.nes \
+    instr_timing/instr_timing.nes \
+    ppu_vbl_nmi/ppu_vbl_nmi.nes \
+    apu_mixer/square.nes \
+    apu_mixer/triangle.nes \
+    apu_mixer/noise.nes \
+    apu_mixer/dmc.nes
+do
+    mkdir -p "$DEST/$(dirname "$rom")"
+    curl -fsSL -o "$DEST/$rom" "$BASE_URL/$rom"
+done